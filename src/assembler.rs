@@ -0,0 +1,1181 @@
+use crate::bytecode::{self, BOOL, F32, F64, I16, I32, I64, I8, U16, U32, U64, U8};
+use crate::expression::{self, Expr};
+use crate::host::HostValue;
+use crate::instructions::Operand;
+use crate::{create_mapping, Token};
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+/// Bytecode together with the memory it expects to start from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Program {
+    pub code: Vec<u8>,
+    /// Initial contents of `BufferArray` from address 0, covering every
+    /// variable the directives allocate.
+    pub data: Vec<u8>,
+    /// The lines that produced code, in order of their code.
+    pub lines: Vec<SourceLine>,
+    /// Labels and the code offsets they stand for, by offset.
+    pub labels: Vec<(String, usize)>,
+    /// Variables and their addresses in `data`, by address.
+    pub variables: Vec<(String, usize)>,
+    pub line_table: LineTable,
+    /// Every directive that allocates memory, with where `data` ends after it.
+    declarations: Vec<(usize, Line)>,
+}
+
+/// Where in the source an instruction was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SourcePosition {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    /// 1-based, in characters. Instructions from a macro have the position
+    /// of the macro's name where it is used.
+    pub column: usize,
+}
+
+impl fmt::Display for SourcePosition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}:{}", file, self.line, self.column),
+            None => write!(f, "line {}, column {}", self.line, self.column),
+        }
+    }
+}
+
+/// Maps the offset of every instruction of a program to its source position.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub(crate) struct LineTable {
+    /// By offset; an instruction without an entry of its own belongs to the
+    /// one before it.
+    entries: Vec<(usize, SourcePosition)>,
+}
+
+impl LineTable {
+    fn push(&mut self, offset: usize, position: SourcePosition) {
+        if self.entries.last().map(|(_, last)| last) != Some(&position) {
+            self.entries.push((offset, position));
+        }
+    }
+
+    /// The position of the instruction at `offset`, or of the one `offset`
+    /// is inside of.
+    pub(crate) fn lookup(&self, offset: usize) -> Option<&SourcePosition> {
+        let index = self.entries.partition_point(|(start, _)| *start <= offset);
+        index.checked_sub(1).map(|index| &self.entries[index].1)
+    }
+
+    /// The table for the program an optimization made of this one, given
+    /// the offset of every instruction in it together with the offset in
+    /// this one it came from, see `bytecode::origins`.
+    pub(crate) fn remap(&self, origins: &[(usize, usize)]) -> LineTable {
+        let mut table = LineTable::default();
+        for (offset, origin) in origins {
+            if let Some(position) = self.lookup(*origin) {
+                table.push(*offset, position.clone());
+            }
+        }
+        table
+    }
+}
+
+/// A line of source as assembled, after constants and macros are expanded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct SourceLine {
+    pub file: Option<Rc<str>>,
+    pub line: usize,
+    pub text: String,
+    /// The bytes of `Program::code` it assembled to.
+    pub code: Range<usize>,
+}
+
+impl fmt::Display for SourceLine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "{}", self.line),
+        }
+    }
+}
+
+impl Program {
+    /// Checks that `data` fits in a memory of `max_memory_size` bytes, and
+    /// otherwise points at the first directive that does not.
+    pub(crate) fn check_memory(&self, max_memory_size: usize) -> Result<(), AsmError> {
+        match self
+            .declarations
+            .iter()
+            .find(|(end, _)| *end > max_memory_size)
+        {
+            Some((end, line)) => Err(line.error(format!(
+                "Variables need {} bytes of memory, more than the maximum of {}",
+                end, max_memory_size
+            ))),
+            None => Ok(()),
+        }
+    }
+    /// Every instruction with its offset, its bytes in hex and the line it
+    /// comes from, then the labels and variables with their addresses.
+    pub(crate) fn listing(&self) -> String {
+        let mut listing = String::new();
+        let mut row = |offset: usize, bytes: &[u8], line: String, text: &str| {
+            let hex: Vec<String> = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+            let row = format!(
+                "{:>6}  {:<41}  {:<16} {}",
+                offset,
+                hex.join(" "),
+                line,
+                text
+            );
+            listing.push_str(row.trim_end());
+            listing.push('\n');
+        };
+        for line in self.lines.iter() {
+            let mut offset = line.code.start;
+            let mut first = true;
+            loop {
+                let size = match bytecode::try_decode(&self.code, offset) {
+                    Ok(decoded) => decoded.size,
+                    Err(_) => line.code.end - offset,
+                };
+                let end = (offset + size).min(line.code.end);
+                let (position, text) = if first {
+                    (line.to_string(), line.text.trim())
+                } else {
+                    (String::new(), "")
+                };
+                row(offset, &self.code[offset..end], position, text);
+                first = false;
+                offset = end;
+                if offset >= line.code.end {
+                    break;
+                }
+            }
+        }
+
+        listing.push_str("\nlabels\n");
+        for (name, offset) in self.labels.iter() {
+            listing.push_str(&format!("{:>6}  {}\n", offset, name));
+        }
+        listing.push_str("\nvariables\n");
+        for (name, address) in self.variables.iter() {
+            listing.push_str(&format!("{:>6}  {}\n", address, name));
+        }
+        listing
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct AsmError {
+    /// The file the error is in, `None` for source given to `assemble`.
+    pub file: Option<String>,
+    /// 1-based line of that file.
+    pub line: usize,
+    pub message: String,
+    /// Where else to look, such as the macro definition a line comes from.
+    pub notes: Vec<String>,
+}
+
+impl fmt::Display for AsmError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let location = Location {
+            file: self.file.as_deref().map(Rc::from),
+            line: self.line,
+        };
+        write!(f, "{}: {}", location, self.message)?;
+        for note in self.notes.iter() {
+            write!(f, "\n  {}", note)?;
+        }
+        Ok(())
+    }
+}
+
+/// A line of the source being assembled or of a file it includes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Location {
+    file: Option<Rc<str>>,
+    line: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.file {
+            Some(file) => write!(f, "{}:{}", file, self.line),
+            None => write!(f, "line {}", self.line),
+        }
+    }
+}
+
+impl Location {
+    fn error(&self, message: String) -> AsmError {
+        AsmError {
+            file: self.file.as_deref().map(str::to_owned),
+            line: self.line,
+            message,
+            notes: Vec::new(),
+        }
+    }
+}
+
+/// How deeply macros may use other macros, which catches recursive ones.
+const MAX_MACRO_DEPTH: usize = 64;
+
+/// A `.macro name params...` to `.endm` definition.
+struct Macro {
+    params: Vec<String>,
+    /// The lines between `.macro` and `.endm`.
+    body: Vec<Line>,
+    location: Location,
+}
+
+/// One line to assemble.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Line {
+    /// Where it is; for a line from a macro, where the macro is used.
+    location: Location,
+    text: String,
+    /// The 1-based column of every word of `text`; for a line from a macro,
+    /// the column of the macro's name where it is used.
+    columns: Vec<usize>,
+    /// The macros it comes from, outermost first, each with where in its
+    /// body the text comes from.
+    macros: Vec<(String, Location)>,
+    /// A number for each of those macro expansions, unique among all of
+    /// them, so that labels in a macro body are defined once per expansion.
+    expansions: Vec<usize>,
+    /// The `.include`s that led to the file it is in, innermost first.
+    includes: Rc<[Location]>,
+    /// Labels defined in an included file are prefixed with `namespace.`.
+    namespace: Option<Rc<str>>,
+}
+
+impl Line {
+    fn error(&self, message: String) -> AsmError {
+        let mut error = self.location.error(message);
+        // A recursive macro repeats the same frames; each is noted once.
+        let mut frames: Vec<(&(String, Location), usize)> = Vec::new();
+        for frame in self.macros.iter().rev() {
+            match frames.iter_mut().find(|(seen, _)| *seen == frame) {
+                Some((_, count)) => *count += 1,
+                None => frames.push((frame, 1)),
+            }
+        }
+        for ((name, location), count) in frames {
+            let note = format!("in macro {} at {}", name, location);
+            error.notes.push(match count {
+                1 => note,
+                count => format!("{} ({} times)", note, count),
+            });
+        }
+        for location in self.includes.iter() {
+            error.notes.push(format!("included from {}", location));
+        }
+        error
+    }
+    /// What `name` defined on this line is called everywhere else: `name@N`
+    /// in the `N`th macro expansion.
+    fn qualified(&self, name: &str) -> String {
+        match (self.expansions.last(), &self.namespace) {
+            (Some(expansion), _) => format!("{}@{}", name, expansion),
+            (None, Some(namespace)) => format!("{}.{}", namespace, name),
+            (None, None) => name.to_owned(),
+        }
+    }
+    /// What `name` used on this line may be called where it is defined, the
+    /// nearest scope first: the macro expansions it is in, innermost first,
+    /// then its file and then the top level.
+    fn candidates(&self, name: &str) -> Vec<String> {
+        let mut candidates: Vec<String> = self
+            .expansions
+            .iter()
+            .rev()
+            .map(|expansion| format!("{}@{}", name, expansion))
+            .collect();
+        if let Some(namespace) = &self.namespace {
+            candidates.push(format!("{}.{}", namespace, name));
+        }
+        candidates.push(name.to_owned());
+        candidates
+    }
+}
+
+/// The 1-based column, in characters, of every word of `text`.
+fn columns(text: &str) -> Vec<usize> {
+    let mut columns = Vec::new();
+    let mut previous = ' ';
+    for (column, char) in text.chars().enumerate() {
+        if previous.is_whitespace() && !char.is_whitespace() {
+            columns.push(column + 1);
+        }
+        previous = char;
+    }
+    columns
+}
+
+/// Names of constants, macros, labels and namespaces, which must not be
+/// mistaken for anything else.
+fn check_name(name: &str) -> Result<(), String> {
+    if name.is_empty()
+        || parse_integer(name).is_some()
+        || create_mapping().contains_key(name)
+        || name.starts_with('.')
+        || name.contains('@')
+    {
+        return Err(format!("{} cannot be a name", name));
+    }
+    Ok(())
+}
+
+/// Reads the source to assemble and every file it includes into one list of
+/// lines. `.include "path"` reads `path` relative to the including file, or
+/// else relative to the first search path that has it, in place of the
+/// directive. Labels in the included file get the file's name without
+/// extension as namespace, or the one given by `.include "path" as name`.
+/// A file included a second time is skipped, a file including itself is an
+/// error.
+struct Reader<'a> {
+    search_paths: &'a [PathBuf],
+    /// Canonical paths of the files being read, outermost first.
+    reading: Vec<PathBuf>,
+    read: HashSet<PathBuf>,
+    lines: Vec<Line>,
+}
+
+impl Reader<'_> {
+    fn read(
+        &mut self,
+        source: &str,
+        file: Option<Rc<str>>,
+        directory: &Path,
+        namespace: Option<Rc<str>>,
+        includes: Rc<[Location]>,
+    ) -> Result<(), AsmError> {
+        for (index, text) in source.lines().enumerate() {
+            let line = Line {
+                location: Location {
+                    file: file.clone(),
+                    line: index + 1,
+                },
+                text: text.to_owned(),
+                columns: columns(text),
+                macros: Vec::new(),
+                expansions: Vec::new(),
+                includes: includes.clone(),
+                namespace: namespace.clone(),
+            };
+            if text.split_whitespace().next() == Some(".include") {
+                self.include(&line, directory)?;
+            } else {
+                self.lines.push(line);
+            }
+        }
+        Ok(())
+    }
+
+    fn include(&mut self, line: &Line, directory: &Path) -> Result<(), AsmError> {
+        let error = |message: &str| line.error(message.to_owned());
+        let literal = line.text.trim_start()[".include".len()..].trim_start();
+        let (name, rest) = parse_string(literal)
+            .ok_or_else(|| error(".include needs a file name in double quotes"))?;
+        let namespace = match rest.split_whitespace().collect::<Vec<_>>()[..] {
+            [] => Path::new(&name)
+                .file_stem()
+                .map(|stem| stem.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            ["as", namespace] => namespace.to_owned(),
+            _ => return Err(error("Expected `as name` after the file name")),
+        };
+        check_name(&namespace).map_err(|message| line.error(message))?;
+
+        let directories =
+            std::iter::once(directory).chain(self.search_paths.iter().map(PathBuf::as_path));
+        let path = directories
+            .map(|directory| directory.join(&name))
+            .find(|path| path.is_file())
+            .ok_or_else(|| error(&format!("Cannot find {}", name)))?;
+        let canonical = path
+            .canonicalize()
+            .map_err(|cause| error(&format!("Cannot read {}: {}", path.display(), cause)))?;
+        if self.reading.contains(&canonical) {
+            return Err(error(&format!("{} includes itself", name)));
+        }
+        if !self.read.insert(canonical.clone()) {
+            return Ok(());
+        }
+        let source = std::fs::read_to_string(&path)
+            .map_err(|cause| error(&format!("Cannot read {}: {}", path.display(), cause)))?;
+
+        let includes: Vec<Location> = std::iter::once(line.location.clone())
+            .chain(line.includes.iter().cloned())
+            .collect();
+        self.reading.push(canonical);
+        self.read(
+            &source,
+            Some(path.display().to_string().into()),
+            path.parent().unwrap_or(Path::new("")),
+            Some(namespace.into()),
+            includes.into(),
+        )?;
+        self.reading.pop();
+        Ok(())
+    }
+}
+
+/// Lines of `.string` and `.lstring` keep their text as written.
+fn is_string_directive(text: &str) -> bool {
+    matches!(text.split_whitespace().next(), Some(".string" | ".lstring"))
+}
+
+/// Replaces every word of `text` that `replacements` has a value for.
+fn substitute(text: &str, replacements: &HashMap<String, String>) -> String {
+    if is_string_directive(text) {
+        return text.to_owned();
+    }
+    let words: Vec<&str> = text
+        .split_whitespace()
+        .map(|word| replacements.get(word).map_or(word, String::as_str))
+        .collect();
+    words.join(" ")
+}
+
+/// Collects `.const name value...` and `.macro` definitions from `lines` and
+/// expands them: constants are replaced wherever they appear as a word, and
+/// a line starting with a macro's name, after any labels, is replaced by its
+/// body with the parameters replaced by the words after the name. Constants
+/// and macros can be used above their definition. Also returns the
+/// constants, for expressions that use them inside a word.
+fn expand(lines: Vec<Line>) -> Result<(Vec<Line>, HashMap<String, String>), AsmError> {
+    let mut constants: HashMap<String, String> = HashMap::new();
+    let mut constant_locations: HashMap<String, Location> = HashMap::new();
+    let mut macros: HashMap<String, Macro> = HashMap::new();
+    let mut defining: Option<(String, Macro)> = None;
+    let mut rest = Vec::new();
+    for line in lines {
+        let error = |message| line.error(message);
+        let words: Vec<&str> = line.text.split_whitespace().collect();
+        match (words.first().copied(), &mut defining) {
+            (Some(".endm"), Some(_)) => {
+                let (name, definition) = defining.take().unwrap();
+                macros.insert(name, definition);
+            }
+            (Some(".macro" | ".const"), Some(_)) => {
+                return Err(error(format!("{} inside a macro", words[0])));
+            }
+            (_, Some((_, definition))) => definition.body.push(line),
+            (Some(".endm"), None) => return Err(error(".endm without .macro".to_owned())),
+            (Some(".macro"), None) => {
+                let name = *words
+                    .get(1)
+                    .ok_or_else(|| error(".macro needs a name".to_owned()))?;
+                check_name(name).map_err(error)?;
+                if let Some(previous) = macros.get(name) {
+                    let mut error = error(format!("Macro {} is defined twice", name));
+                    error
+                        .notes
+                        .push(format!("first defined at {}", previous.location));
+                    return Err(error);
+                }
+                let params: Vec<String> =
+                    words[2..].iter().map(|param| param.to_string()).collect();
+                defining = Some((
+                    name.to_owned(),
+                    Macro {
+                        params,
+                        body: Vec::new(),
+                        location: line.location.clone(),
+                    },
+                ));
+            }
+            (Some(".const"), None) => {
+                let [_, name, value @ ..] = &words[..] else {
+                    return Err(error(".const needs a name and a value".to_owned()));
+                };
+                if value.is_empty() {
+                    return Err(error(".const needs a name and a value".to_owned()));
+                }
+                let name = *name;
+                check_name(name).map_err(error)?;
+                if let Some(previous) = constant_locations.get(name) {
+                    let mut error = error(format!("Constant {} is defined twice", name));
+                    error.notes.push(format!("first defined at {}", previous));
+                    return Err(error);
+                }
+                // An expression is kept to one word in parentheses, so that
+                // it can replace a word and keeps its precedence there.
+                let value = match value {
+                    [value] => constants.get(*value).cloned().unwrap_or(value.to_string()),
+                    _ => {
+                        expression::parse(&value.join(" ")).map_err(error)?;
+                        value.concat()
+                    }
+                };
+                let value = match expression::parse(&value) {
+                    Ok(Expr::Binary(..)) if !value.starts_with('(') => format!("({})", value),
+                    _ => value,
+                };
+                constants.insert(name.to_owned(), value);
+                constant_locations.insert(name.to_owned(), line.location.clone());
+            }
+            _ => rest.push(line),
+        }
+    }
+    if let Some((name, definition)) = defining {
+        return Err(definition
+            .location
+            .error(format!("Macro {} has no .endm", name)));
+    }
+    if let Some(name) = macros.keys().find(|name| constants.contains_key(*name)) {
+        return Err(macros[name]
+            .location
+            .error(format!("{} is both a macro and a constant", name)));
+    }
+
+    let mut expanded = Vec::new();
+    let mut expansion = 0;
+    let mut pending: Vec<Line> = rest.into_iter().rev().collect();
+    while let Some(line) = pending.pop() {
+        let text = substitute(&line.text, &constants);
+        let mut words: Vec<&str> = text.split_whitespace().collect();
+        let labels = words.iter().take_while(|word| word.ends_with(':')).count();
+        let mut column = line.columns.first().copied().unwrap_or(1);
+        if labels > 0 && labels < words.len() && macros.contains_key(words[labels]) {
+            expanded.push(Line {
+                text: words[..labels].join(" "),
+                columns: line.columns[..labels].to_vec(),
+                ..line.clone()
+            });
+            words.drain(..labels);
+            column = line.columns[labels];
+        }
+        let Some(definition) = words.first().and_then(|word| macros.get(*word)) else {
+            expanded.push(Line { text, ..line });
+            continue;
+        };
+        let name = words[0];
+        let definition_note = format!("macro {} is defined at {}", name, definition.location);
+        if words.len() - 1 != definition.params.len() {
+            let mut error = line.error(format!(
+                "Macro {} takes {} arguments, got {}",
+                name,
+                definition.params.len(),
+                words.len() - 1
+            ));
+            error.notes.insert(0, definition_note);
+            return Err(error);
+        }
+        if line.macros.len() == MAX_MACRO_DEPTH {
+            let mut error = line.error(format!(
+                "Macros are nested more than {} deep, is {} recursive?",
+                MAX_MACRO_DEPTH, name
+            ));
+            error.notes.insert(0, definition_note);
+            return Err(error);
+        }
+        let arguments: HashMap<String, String> = definition
+            .params
+            .iter()
+            .cloned()
+            .zip(words[1..].iter().map(|word| word.to_string()))
+            .collect();
+        expansion += 1;
+        let mut expansions = line.expansions.clone();
+        expansions.push(expansion);
+        for body in definition.body.iter().rev() {
+            let mut macros = line.macros.clone();
+            macros.push((name.to_owned(), body.location.clone()));
+            let text = substitute(&body.text, &arguments);
+            pending.push(Line {
+                location: line.location.clone(),
+                columns: vec![column; text.split_whitespace().count()],
+                text,
+                macros,
+                expansions: expansions.clone(),
+                includes: line.includes.clone(),
+                namespace: body.namespace.clone(),
+            });
+        }
+    }
+    Ok((expanded, constants))
+}
+
+/// Reads an escape sequence after its `\`. Escapes are those of Rust:
+/// `\n \r \t \0 \\ \' \"`, `\x41` and `\u{1F600}`.
+fn escape(chars: &mut std::str::Chars) -> Option<char> {
+    Some(match chars.next()? {
+        'n' => '\n',
+        'r' => '\r',
+        't' => '\t',
+        '0' => '\0',
+        '\\' => '\\',
+        '\'' => '\'',
+        '"' => '"',
+        'x' => {
+            let hex: String = chars.by_ref().take(2).collect();
+            let code = u8::from_str_radix(&hex, 16).ok()?;
+            (hex.len() == 2 && code < 0x80).then_some(code as char)?
+        }
+        'u' => {
+            let hex = chars.as_str().strip_prefix('{')?;
+            let (hex, rest) = hex.split_once('}')?;
+            let character = char::from_u32(u32::from_str_radix(hex, 16).ok()?)?;
+            *chars = rest.chars();
+            character
+        }
+        _ => return None,
+    })
+}
+
+/// The character in a literal like `'A'` or `'\n'`. Literals are single
+/// words, so a space has to be written `'\x20'`.
+fn parse_character(word: &str) -> Option<char> {
+    let mut chars = word.strip_prefix('\'')?.strip_suffix('\'')?.chars();
+    let character = match chars.next()? {
+        '\\' => escape(&mut chars)?,
+        character => character,
+    };
+    chars.next().is_none().then_some(character)
+}
+
+/// The text of the string literal `literal` starts with, with the same
+/// escapes as characters, and what follows its closing quote.
+fn parse_string(literal: &str) -> Option<(String, &str)> {
+    let mut chars = literal.strip_prefix('"')?.chars();
+    let mut text = String::new();
+    loop {
+        match chars.next()? {
+            '"' => return Some((text, chars.as_str())),
+            '\\' => text.push(escape(&mut chars)?),
+            character => text.push(character),
+        }
+    }
+}
+
+/// Removes the `_`s between digits, refusing them anywhere else.
+fn without_separators(digits: &str) -> Option<String> {
+    let valid = !digits.starts_with('_')
+        && !digits.ends_with('_')
+        && !digits.contains("__")
+        && !digits.contains("_.")
+        && !digits.contains("._");
+    valid.then(|| digits.replace('_', ""))
+}
+
+/// An integer literal: decimal or with a `0x`, `0o` or `0b` prefix, optionally
+/// signed, with `_` between digits, or a character literal.
+pub(crate) fn parse_integer(word: &str) -> Option<i128> {
+    if let Some(character) = parse_character(word) {
+        return Some(character as i128);
+    }
+    let (negative, unsigned) = match word.strip_prefix('-') {
+        Some(rest) => (true, rest),
+        None => (false, word.strip_prefix('+').unwrap_or(word)),
+    };
+    let (radix, digits) = match unsigned.get(..2) {
+        Some("0x" | "0X") => (16, &unsigned[2..]),
+        Some("0o" | "0O") => (8, &unsigned[2..]),
+        Some("0b" | "0B") => (2, &unsigned[2..]),
+        _ => (10, unsigned),
+    };
+    let digits = without_separators(digits)?;
+    // `from_str_radix` would take another sign after the prefix.
+    if digits.starts_with(['+', '-']) {
+        return None;
+    }
+    let magnitude = i128::from_str_radix(&digits, radix).ok()?;
+    Some(if negative { -magnitude } else { magnitude })
+}
+
+/// How deeply constants may use other constants in an expression, which
+/// catches ones defined in terms of themselves.
+const MAX_CONSTANT_DEPTH: usize = 64;
+
+/// Evaluates the operand expression `text`. A name is looked up in
+/// `constants`, whose values are expressions too, and then with `lookup`.
+fn evaluate(
+    text: &str,
+    constants: &HashMap<String, String>,
+    lookup: &dyn Fn(&str) -> Option<i128>,
+) -> Result<i128, String> {
+    fn nested(
+        text: &str,
+        constants: &HashMap<String, String>,
+        lookup: &dyn Fn(&str) -> Option<i128>,
+        depth: usize,
+    ) -> Result<i128, String> {
+        expression::parse(text)?.evaluate(&mut |name| match constants.get(name) {
+            Some(_) if depth == MAX_CONSTANT_DEPTH => {
+                Err(format!("Constant {} is defined in terms of itself", name))
+            }
+            Some(value) => nested(value, constants, lookup, depth + 1),
+            None => lookup(name).ok_or_else(|| format!("Unknown name {}", name)),
+        })
+    }
+    nested(text, constants, lookup, 0)
+}
+
+fn is_integer(type_tag: u8) -> bool {
+    bytecode::is_type_tag(type_tag) && !matches!(type_tag, BOOL | F32 | F64)
+}
+
+/// `value` as a value of the integer type `type_tag`, which it must fit.
+/// `text` is how it was written, for the error.
+fn integer_value(type_tag: u8, value: i128, text: &str) -> Result<HostValue, String> {
+    macro_rules! integer {
+        ($type:ty, $variant:ident) => {
+            <$type>::try_from(value)
+                .map(HostValue::$variant)
+                .map_err(|_| {
+                    format!(
+                        "{} is out of range for {} ({} to {})",
+                        text,
+                        stringify!($type),
+                        <$type>::MIN,
+                        <$type>::MAX
+                    )
+                })
+        };
+    }
+    match type_tag {
+        I8 => integer!(i8, I8),
+        I16 => integer!(i16, I16),
+        I32 => integer!(i32, I32),
+        I64 => integer!(i64, I64),
+        U8 => integer!(u8, U8),
+        U16 => integer!(u16, U16),
+        U32 => integer!(u32, U32),
+        U64 => integer!(u64, U64),
+        _ => Err(format!(
+            "{} is not an integer type",
+            bytecode::mnemonic(type_tag)
+        )),
+    }
+}
+
+/// A value of `type_tag` written as `text`: an expression, see `evaluate`,
+/// for an integer type and a literal for the others.
+fn parse_value(
+    type_tag: u8,
+    text: &str,
+    constants: &HashMap<String, String>,
+    lookup: &dyn Fn(&str) -> Option<i128>,
+) -> Result<HostValue, String> {
+    if is_integer(type_tag) {
+        integer_value(type_tag, evaluate(text, constants, lookup)?, text)
+    } else {
+        try_parse_value(type_tag, text)
+    }
+}
+
+/// Parses `word` as a value of `type_tag`. Integers take any literal
+/// `parse_integer` does but must fit the type; floats are what `str::parse`
+/// reads, such as `1.5e-3`, `inf`, `-inf` and `nan`, also with `_` between
+/// digits, but must not overflow to infinity.
+pub(crate) fn try_parse_value(type_tag: u8, word: &str) -> Result<HostValue, String> {
+    macro_rules! float {
+        ($type:ty, $variant:ident) => {{
+            let value = without_separators(word)
+                .and_then(|digits| digits.parse::<$type>().ok())
+                .ok_or_else(|| format!("{} is not a valid {} literal", word, stringify!($type)))?;
+            let infinite = word.trim_start_matches(['-', '+']).to_ascii_lowercase();
+            if value.is_infinite() && infinite != "inf" && infinite != "infinity" {
+                return Err(format!(
+                    "{} is out of range for {}",
+                    word,
+                    stringify!($type)
+                ));
+            }
+            Ok(HostValue::$variant(value))
+        }};
+    }
+    match type_tag {
+        BOOL => match word {
+            "true" => Ok(HostValue::Bool(true)),
+            "false" => Ok(HostValue::Bool(false)),
+            _ => Err(format!("{} is not a valid bool literal", word)),
+        },
+        _ if is_integer(type_tag) => {
+            let value = parse_integer(word).ok_or_else(|| {
+                let type_name = bytecode::mnemonic(type_tag);
+                format!("{} is not a valid {} literal", word, type_name)
+            })?;
+            integer_value(type_tag, value, word)
+        }
+        F32 => float!(f32, F32),
+        F64 => float!(f64, F64),
+        _ => Err(format!("{} is not a type", bytecode::mnemonic(type_tag))),
+    }
+}
+
+/// Memory laid out by the directives, in order of declaration from address 0.
+#[derive(Default)]
+struct Memory {
+    addresses: HashMap<String, usize>,
+    data: Vec<u8>,
+}
+
+impl Memory {
+    /// Declares one directive line.
+    fn declare(&mut self, line: &str, constants: &HashMap<String, String>) -> Result<(), String> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words[0] {
+            ".string" | ".lstring" => self.declare_string(words[0], line),
+            _ => self.declare_values(&words, constants),
+        }
+    }
+
+    /// Places `bytes` at the next address that is a multiple of `alignment`
+    /// and names that address `name`.
+    fn allocate(&mut self, name: &str, alignment: usize, bytes: &[u8]) -> Result<(), String> {
+        if parse_integer(name).is_some() || create_mapping().contains_key(name) {
+            return Err(format!("{} cannot be a variable name", name));
+        }
+        if self.addresses.contains_key(name) {
+            return Err(format!("{} is declared twice", name));
+        }
+        let address = self.data.len().next_multiple_of(alignment);
+        self.data.resize(address, 0);
+        self.data.extend_from_slice(bytes);
+        self.addresses.insert(name.to_owned(), address);
+        Ok(())
+    }
+
+    /// `.var name type [value]`, `.array name type length [value...]` or
+    /// `.data name type value...`, aligned to the size of the type. Elements
+    /// without a value start as zero. The length and integer values can be
+    /// expressions of one word each, of constants and variables above.
+    fn declare_values(
+        &mut self,
+        words: &[&str],
+        constants: &HashMap<String, String>,
+    ) -> Result<(), String> {
+        let lookup = |name: &str| self.addresses.get(name).map(|address| *address as i128);
+        let (name, type_name, rest) = match words {
+            [_, name, type_name, rest @ ..] => (*name, *type_name, rest),
+            _ => return Err(format!("{} needs a name and a type", words[0])),
+        };
+        let type_tag = create_mapping()
+            .get(type_name)
+            .copied()
+            .filter(|type_tag| bytecode::is_type_tag(*type_tag))
+            .ok_or_else(|| format!("Unknown type {}", type_name))?;
+        let (length, values) = match words[0] {
+            ".var" => (1, rest),
+            ".array" => match rest.split_first() {
+                Some((length, values)) => {
+                    let value = evaluate(length, constants, &lookup)?;
+                    let length = usize::try_from(value)
+                        .map_err(|_| format!("Bad array length {}", length))?;
+                    (length, values)
+                }
+                None => return Err(format!(".array {} needs a length", name)),
+            },
+            ".data" => (rest.len(), rest),
+            directive => return Err(format!("Unknown directive {}", directive)),
+        };
+        if values.len() > length {
+            return Err(format!(
+                "{} has {} values for {} elements",
+                name,
+                values.len(),
+                length
+            ));
+        }
+
+        let size = bytecode::type_size(type_tag);
+        let mut bytes = Vec::with_capacity(size * length);
+        for value in values {
+            bytes.extend(parse_value(type_tag, value, constants, &lookup)?.to_le_bytes());
+        }
+        bytes.resize(size * length, 0);
+        self.allocate(name, size, &bytes)
+    }
+
+    /// `.string name "text"`, the UTF-8 bytes of `text` followed by a NUL, or
+    /// `.lstring name "text"`, a `u64` byte count followed by the bytes.
+    fn declare_string(&mut self, directive: &str, line: &str) -> Result<(), String> {
+        let quote = line
+            .find('"')
+            .ok_or_else(|| format!("{} needs a string in double quotes", directive))?;
+        let name = match line[..quote].split_whitespace().collect::<Vec<_>>()[..] {
+            [_, name] => name,
+            _ => return Err(format!("{} needs a name before the string", directive)),
+        };
+        let (text, rest) = parse_string(&line[quote..])
+            .ok_or_else(|| format!("Bad string literal {}", line[quote..].trim_end()))?;
+        if !rest.trim().is_empty() {
+            return Err(format!("Unexpected {} after the string", rest.trim()));
+        }
+        if directive == ".string" {
+            let mut bytes = text.into_bytes();
+            bytes.push(0);
+            self.allocate(name, 1, &bytes)
+        } else {
+            let mut bytes = (text.len() as u64).to_le_bytes().to_vec();
+            bytes.extend(text.into_bytes());
+            self.allocate(name, 8, &bytes)
+        }
+    }
+}
+
+fn is_directive(line: &str) -> bool {
+    line.trim_start().starts_with('.')
+}
+
+/// Other names of opcodes, for the dotted spelling `cast.i32.f64`.
+const ALIASES: [(&str, Token); 1] = [("cast", Token::TypeCast)];
+
+fn parse_type(word: &str) -> Option<u8> {
+    create_mapping()
+        .get(word)
+        .copied()
+        .filter(|type_tag| bytecode::is_type_tag(*type_tag))
+}
+
+/// Reads an instruction's name, `add` or `add.i32` with its types after
+/// dots, into the opcode, its operands and the types given with dots.
+fn parse_mnemonic(word: &str) -> Option<(u8, &'static [Operand], Vec<u8>)> {
+    let mut parts = word.split('.');
+    let name = parts.next()?;
+    let opcode = match ALIASES.iter().find(|(alias, _)| *alias == name) {
+        Some((_, token)) => *token as u8,
+        None => create_mapping().get(name).copied()?,
+    };
+    let operands = bytecode::operands(opcode)?;
+    let types = parts.map(parse_type).collect::<Option<Vec<u8>>>()?;
+    Some((opcode, operands, types))
+}
+
+/// An integer operand, which is evaluated once every label is known.
+struct Fixup {
+    /// Where in the code it goes.
+    offset: usize,
+    operand: Operand,
+    /// The instruction's first type, which a `Value` has.
+    type_tag: u8,
+    text: String,
+    line: Line,
+}
+
+/// Assembles `source`: instructions, each a mnemonic followed by its
+/// operands as `bytecode::operands` lists them, separated by whitespace,
+/// where the types can also follow the mnemonic after dots as in
+/// `add.i32` or `cast.i32.f64`. Integer operands can be expressions, see
+/// `expression`, of constants, labels and variables. Besides those, one
+/// directive per line allocates a variable, defines a constant or macro,
+/// see `expand`, or includes a file relative to the current directory, see
+/// `Reader`. A variable's name can stand wherever a memory address can,
+/// also above its declaration.
+pub(crate) fn assemble(source: &str) -> Result<Program, AsmError> {
+    let mut reader = Reader {
+        search_paths: &[],
+        reading: Vec::new(),
+        read: HashSet::new(),
+        lines: Vec::new(),
+    };
+    reader.read(source, None, Path::new(""), None, Rc::from([]))?;
+    assemble_lines(reader.lines)
+}
+
+/// Assembles `source`, the contents of the file at `path`, like `assemble`,
+/// also looking for included files in `search_paths`.
+pub(crate) fn assemble_file(
+    path: &Path,
+    source: &str,
+    search_paths: &[PathBuf],
+) -> Result<Program, AsmError> {
+    let canonical = path.canonicalize().unwrap_or_else(|_| path.to_path_buf());
+    let mut reader = Reader {
+        search_paths,
+        reading: vec![canonical.clone()],
+        read: HashSet::from([canonical]),
+        lines: Vec::new(),
+    };
+    let file = Some(path.display().to_string().into());
+    let directory = path.parent().unwrap_or(Path::new(""));
+    reader.read(source, file, directory, None, Rc::from([]))?;
+    assemble_lines(reader.lines)
+}
+
+/// Assembles lines read by `Reader`. A word ending in `:` defines a label
+/// at the current offset in the code, which can stand wherever a jump
+/// target can, also above its definition. A label used in an included file
+/// means the one that file defines, if any, and one used in a macro body the
+/// one that expansion of the macro defines, so a macro with a loop can be
+/// used more than once.
+fn assemble_lines(lines: Vec<Line>) -> Result<Program, AsmError> {
+    let (lines, constants) = expand(lines)?;
+    let mut memory = Memory::default();
+    let mut declarations = Vec::new();
+    for line in lines.iter() {
+        if is_directive(&line.text) {
+            memory
+                .declare(&line.text, &constants)
+                .map_err(|message| line.error(message))?;
+            declarations.push((memory.data.len(), line.clone()));
+        }
+    }
+
+    let mut code = Vec::<u8>::new();
+    let mut labels: HashMap<String, (usize, Location)> = HashMap::new();
+    let mut fixups = Vec::new();
+    let mut source_lines = Vec::new();
+    let mut line_table = LineTable::default();
+    for line in lines.iter() {
+        if is_directive(&line.text) {
+            continue;
+        }
+        let start = code.len();
+        let error = |message| line.error(message);
+        let mut words = line
+            .text
+            .split_whitespace()
+            .zip(line.columns.iter().copied())
+            .peekable();
+        while let Some((word, column)) = words.next() {
+            if let Some(name) = word.strip_suffix(':') {
+                check_name(name).map_err(error)?;
+                let name = line.qualified(name);
+                if let Some((_, previous)) = labels.get(&name) {
+                    let mut error = error(format!("Label {} is defined twice", name));
+                    error.notes.push(format!("first defined at {}", previous));
+                    return Err(error);
+                }
+                labels.insert(name, (code.len(), line.location.clone()));
+                continue;
+            }
+            let (opcode, operands, dotted) =
+                parse_mnemonic(word).ok_or_else(|| error(format!("Unexpected token: {}", word)))?;
+            let name = bytecode::mnemonic(opcode);
+            let types = operands
+                .iter()
+                .filter(|operand| **operand == Operand::Type)
+                .count();
+            if !dotted.is_empty() && dotted.len() != types {
+                return Err(error(format!(
+                    "{} takes {} types, got {}",
+                    name,
+                    types,
+                    dotted.len()
+                )));
+            }
+            let position = SourcePosition {
+                file: line.location.file.clone(),
+                line: line.location.line,
+                column,
+            };
+            line_table.push(code.len(), position);
+            code.push(opcode);
+
+            let mut dotted = dotted.into_iter();
+            let mut type_tag = None;
+            for operand in operands.iter().copied() {
+                if let (Operand::Type, Some(tag)) = (operand, dotted.next()) {
+                    code.push(tag);
+                    type_tag.get_or_insert(tag);
+                    continue;
+                }
+                let (word, _) = words
+                    .next()
+                    .ok_or_else(|| error(format!("{} needs {}", name, operand.describe())))?;
+                if operand == Operand::Type {
+                    let tag = parse_type(word)
+                        .ok_or_else(|| error(format!("{} needs a type, found {}", name, word)))?;
+                    code.push(tag);
+                    type_tag.get_or_insert(tag);
+                    continue;
+                }
+                // Every instruction with a value has a type first.
+                let type_tag = type_tag.unwrap_or(BOOL);
+                if operand == Operand::Value && !is_integer(type_tag) {
+                    let value = try_parse_value(type_tag, word).map_err(error)?;
+                    code.extend(value.to_le_bytes());
+                    continue;
+                }
+                let mut text = word.to_owned();
+                while let Some((next, _)) =
+                    words.next_if(|(next, _)| expression::continues(&text, next))
+                {
+                    text.push(' ');
+                    text.push_str(next);
+                }
+                let size = match operand {
+                    Operand::Value => bytecode::type_size(type_tag),
+                    Operand::HostFunction => 4,
+                    _ => 8,
+                };
+                fixups.push(Fixup {
+                    offset: code.len(),
+                    operand,
+                    type_tag,
+                    text,
+                    line: line.clone(),
+                });
+                code.resize(code.len() + size, 0);
+            }
+        }
+        if line.text.trim().is_empty() {
+            continue;
+        }
+        source_lines.push(SourceLine {
+            file: line.location.file.clone(),
+            line: line.location.line,
+            text: line.text.clone(),
+            code: start..code.len(),
+        });
+    }
+
+    for fixup in fixups {
+        let line = &fixup.line;
+        let lookup = |name: &str| {
+            line.candidates(name)
+                .iter()
+                .find_map(|candidate| labels.get(candidate))
+                .map(|(offset, _)| *offset)
+                .or_else(|| memory.addresses.get(name).copied())
+                .map(|value| value as i128)
+        };
+        let value =
+            evaluate(&fixup.text, &constants, &lookup).map_err(|message| line.error(message))?;
+        let out_of_range = || {
+            let message = format!(
+                "{} is out of range for {}",
+                fixup.text,
+                fixup.operand.describe()
+            );
+            line.error(message)
+        };
+        let bytes = match fixup.operand {
+            Operand::Value => integer_value(fixup.type_tag, value, &fixup.text)
+                .map_err(|message| line.error(message))?
+                .to_le_bytes(),
+            Operand::HostFunction => u32::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+            _ => usize::try_from(value)
+                .map_err(|_| out_of_range())?
+                .to_le_bytes()
+                .to_vec(),
+        };
+        code[fixup.offset..fixup.offset + bytes.len()].copy_from_slice(&bytes);
+    }
+    let mut labels: Vec<(String, usize)> = labels
+        .into_iter()
+        .map(|(name, (offset, _))| (name, offset))
+        .collect();
+    labels.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    let mut variables: Vec<(String, usize)> = memory.addresses.into_iter().collect();
+    variables.sort_by(|a, b| (a.1, &a.0).cmp(&(b.1, &b.0)));
+    Ok(Program {
+        code,
+        data: memory.data,
+        lines: source_lines,
+        labels,
+        variables,
+        line_table,
+        declarations,
+    })
+}
@@ -0,0 +1,221 @@
+use crate::fuel::{ExecutionOutcome, Fuel};
+use crate::optimizer;
+use crate::peephole::{self, PeepholeOptions};
+use crate::{parse_source, Engine, StackUpperVector, VmConfig};
+use std::time::{Duration, Instant};
+
+/// Builds a program whose jump targets depend on the size of earlier parts:
+/// every `{}` in `parts[i]` is replaced by the byte offset where `parts[j]`
+/// starts, `j` taken from `targets[i]` in order.
+pub(crate) fn assemble_parts(parts: &[&str], targets: &[&[usize]]) -> Vec<u8> {
+    let mut starts = vec![0usize; parts.len() + 1];
+    // Jump operands are fixed size, so sizes do not depend on target values.
+    for (i, part) in parts.iter().enumerate() {
+        starts[i + 1] = starts[i] + parse_source(&part.replace("{}", "0")).len();
+    }
+    let mut source = String::new();
+    for (part, part_targets) in parts.iter().zip(targets) {
+        let mut text = part.to_string();
+        for target in part_targets.iter() {
+            text = text.replacen("{}", &starts[*target].to_string(), 1);
+        }
+        source.push_str(&text);
+        source.push('\n');
+    }
+    parse_source(&source)
+}
+
+pub(crate) fn integer_loop(iterations: u64) -> Vec<u8> {
+    let limit = format!(
+        "clone_push i64 push i64 {} compare_greater_equal i64 pop_goto_if_true {{}}",
+        iterations
+    );
+    assemble_parts(
+        &[
+            "push i64 0",
+            &limit,
+            "push i64 1 add i64 goto {}",
+            "store i64 0",
+        ],
+        &[&[], &[3], &[1], &[]],
+    )
+}
+
+pub(crate) fn float_loop(iterations: u64) -> Vec<u8> {
+    let limit = format!(
+        "clone_push i64 push i64 {} compare_greater_equal i64 pop_goto_if_true {{}}",
+        iterations
+    );
+    assemble_parts(
+        &[
+            "push f64 1 store f64 8 push i64 0",
+            &limit,
+            "clone_push i64 type_cast i64 f64 push f64 1000 divide f64 load f64 8 add f64 \
+             push f64 0.999 multiply f64 push f64 0.5 subtract f64 store f64 8 \
+             push i64 1 add i64 goto {}",
+            "store i64 0",
+        ],
+        &[&[], &[3], &[1], &[]],
+    )
+}
+
+/// Copies `MEMORY_COPY_WORDS` words to another part of memory on every
+/// iteration, after changing the first source word so each copy moves new data.
+pub(crate) fn memory_copy(iterations: u64) -> Vec<u8> {
+    const MEMORY_COPY_WORDS: usize = 32;
+    let limit = format!(
+        "load u64 0 push u64 {} compare_greater_equal u64 pop_goto_if_true {{}}",
+        iterations
+    );
+    let mut copy = String::from("load u64 0 store u64 8 ");
+    for word in 0..MEMORY_COPY_WORDS {
+        let source = 8 + 8 * word;
+        let destination = source + 8 * MEMORY_COPY_WORDS;
+        copy.push_str(&format!("load u64 {} store u64 {} ", source, destination));
+    }
+    copy.push_str("load u64 0 push u64 1 add u64 store u64 0 goto {}");
+    assemble_parts(
+        &["push u64 0 store u64 0", &limit, &copy, ""],
+        &[&[], &[3], &[1], &[]],
+    )
+}
+
+/// Walks a decision tree `BRANCHING_DEPTH` compares deep on a byte derived
+/// from the loop counter, counting in memory how often each leaf is reached.
+pub(crate) fn branching(iterations: u64) -> Vec<u8> {
+    const BRANCHING_DEPTH: u32 = 4;
+    // Parts of a subtree `depth` compares deep, laid out node first, then
+    // the subtree for values at or above the split, then the one below it.
+    fn subtree(
+        depth: u32,
+        low: u32,
+        high: u32,
+        parts: &mut Vec<String>,
+        targets: &mut Vec<Vec<usize>>,
+        next: usize,
+    ) {
+        if depth == 0 {
+            let counter = 16 + 8 * (low * (1 << BRANCHING_DEPTH) / 256);
+            parts.push(format!(
+                "load u64 {0} push u64 1 add u64 store u64 {0} goto {{}}",
+                counter
+            ));
+            targets.push(vec![next]);
+            return;
+        }
+        let split = (low + high) / 2;
+        let below = parts.len() + 1 + ((1 << depth) - 1);
+        parts.push(format!(
+            "load u8 8 push u8 {} compare_lesser u8 pop_goto_if_true {{}}",
+            split
+        ));
+        targets.push(vec![below]);
+        subtree(depth - 1, split, high, parts, targets, next);
+        subtree(depth - 1, low, split, parts, targets, next);
+    }
+
+    let mut parts = vec![
+        "push u64 0 store u64 0".to_owned(),
+        format!(
+            "load u64 0 push u64 {} compare_greater_equal u64 pop_goto_if_true {{}} \
+             load u64 0 push u64 37 multiply u64 type_cast u64 u8 store u8 8",
+            iterations
+        ),
+    ];
+    let mut targets = vec![vec![], vec![]];
+    // The tree takes 2^(depth + 1) - 1 parts, then come the increment and the end.
+    let increment = parts.len() + (1 << (BRANCHING_DEPTH + 1)) - 1;
+    subtree(BRANCHING_DEPTH, 0, 256, &mut parts, &mut targets, increment);
+    parts.push("load u64 0 push u64 1 add u64 store u64 0 goto {}".to_owned());
+    targets.push(vec![1]);
+    parts.push(String::new());
+    targets[1].push(parts.len() - 1);
+
+    let parts: Vec<&str> = parts.iter().map(String::as_str).collect();
+    let targets: Vec<&[usize]> = targets.iter().map(Vec::as_slice).collect();
+    assemble_parts(&parts, &targets)
+}
+
+fn fresh_vm(code: &[u8], engine: Engine) -> StackUpperVector {
+    let mut vm = StackUpperVector::with_config(VmConfig {
+        engine,
+        ..VmConfig::default()
+    });
+    vm.output = Some(Vec::new());
+    vm.token_byte_sequence = code.to_vec();
+    vm.init();
+    vm
+}
+
+fn count_instructions(code: &[u8]) -> u64 {
+    let mut vm = fresh_vm(code, Engine::Interpreter);
+    let mut fuel = Fuel::new(u64::MAX);
+    assert_eq!(vm.execute_with_fuel(&mut fuel), ExecutionOutcome::Finished);
+    u64::MAX - fuel.remaining
+}
+
+/// How often every program runs on every engine; the fastest run counts.
+const REPEATS: usize = 3;
+
+/// The benchmark programs, each also after `-O` optimization.
+fn programs() -> Vec<(String, Vec<u8>)> {
+    let optimizations = PeepholeOptions::default();
+    let programs = [
+        ("integer loop", integer_loop(2_000_000)),
+        ("float loop", float_loop(500_000)),
+        ("memory copy", memory_copy(50_000)),
+        ("branching", branching(300_000)),
+    ];
+    let mut all = Vec::new();
+    for (name, code) in programs {
+        let optimized = optimizer::optimize(&code, &optimizations);
+        let optimized = peephole::optimize(&optimized, &optimizations);
+        all.push((name.to_owned(), code));
+        all.push((format!("{} -O", name), optimized));
+    }
+    all
+}
+
+/// Runs every benchmark program on every engine and prints instructions per
+/// second. Engine times include translating the program. Every engine must
+/// end in the same state as the interpreter, so a broken engine cannot post
+/// a fast time.
+pub(crate) fn run_benchmarks() {
+    println!(
+        "{:<16} {:<12} {:>12} {:>12} {:>10} {:>8}",
+        "program", "engine", "instructions", "time", "M instr/s", "speedup"
+    );
+    for (name, code) in programs().iter() {
+        let instructions = count_instructions(code);
+        let mut baseline = None;
+        let mut expected = None;
+        for engine in Engine::ALL {
+            let mut fastest = None;
+            for _ in 0..REPEATS {
+                let mut vm = fresh_vm(code, engine);
+                let start = Instant::now();
+                vm.run();
+                let elapsed = start.elapsed();
+                fastest = Some(fastest.map_or(elapsed, |fastest: Duration| fastest.min(elapsed)));
+                let state = (vm.output.take(), vm.snapshot());
+                assert!(
+                    *expected.get_or_insert_with(|| state.clone()) == state,
+                    "{} ends differently on {}",
+                    name,
+                    engine.name()
+                );
+            }
+            let elapsed = fastest.unwrap();
+            let baseline = *baseline.get_or_insert(elapsed);
+            println!(
+                "{:<16} {:<12} {:>12} {:>12.3?} {:>10.1} {:>7.2}x",
+                name,
+                engine.name(),
+                instructions,
+                elapsed,
+                instructions as f64 / elapsed.as_secs_f64() / 1e6,
+                baseline.as_secs_f64() / elapsed.as_secs_f64()
+            );
+        }
+    }
+}
@@ -0,0 +1,330 @@
+use crate::instructions::{self, Effect, Operand, Slot};
+
+pub(crate) use crate::instructions::{
+    type_size, BOOL, F32, F64, I16, I32, I64, I8, U16, U32, U64, U8,
+};
+
+/// One instruction as it is laid out in `token_byte_sequence`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Decoded {
+    pub offset: usize,
+    pub opcode: u8,
+    pub type_tag: Option<u8>,
+    pub second_type_tag: Option<u8>,
+    /// Jump target for `goto`-like instructions, buffer address for memory
+    /// instructions, function id for `call_host`.
+    pub operand: Option<usize>,
+    pub size: usize,
+}
+
+impl Decoded {
+    pub(crate) fn is_jump(&self) -> bool {
+        instructions::instruction(self.opcode)
+            .is_some_and(|instruction| instruction.has(Operand::Target))
+    }
+    pub(crate) fn jump_target(&self) -> Option<usize> {
+        if self.is_jump() {
+            self.operand
+        } else {
+            None
+        }
+    }
+}
+
+/// The fused compare-and-branch instructions, the ones the table pairs with a compare.
+pub(crate) fn is_conditional_goto(opcode: u8) -> bool {
+    instructions::instruction(opcode).is_some_and(|instruction| instruction.condition.is_some())
+}
+
+/// The `compare_*` opcode a `goto_if_*` opcode branches on.
+pub(crate) fn goto_condition(opcode: u8) -> u8 {
+    instructions::instruction(opcode)
+        .and_then(|instruction| instruction.condition)
+        .unwrap_or_else(|| panic!("{} is not a conditional goto", opcode))
+}
+
+/// The `goto_if_*` opcode that branches on a `compare_*` opcode, `None` for
+/// any other opcode.
+pub(crate) fn fused_goto(compare: u8) -> Option<u8> {
+    instructions::INSTRUCTIONS
+        .iter()
+        .find(|instruction| instruction.condition == Some(compare))
+        .map(|instruction| instruction.opcode)
+}
+
+pub(crate) fn is_type_tag(type_tag: u8) -> bool {
+    instructions::type_tag(type_tag).is_some()
+}
+
+fn read_bytes<const N: usize>(code: &[u8], offset: usize) -> Result<[u8; N], String> {
+    let mut arr = [0u8; N];
+    arr.copy_from_slice(
+        code.get(offset..offset + N)
+            .ok_or_else(|| format!("Truncated instruction at offset {}", offset))?,
+    );
+    Ok(arr)
+}
+
+fn read_type_tag(code: &[u8], offset: usize) -> Result<u8, String> {
+    let [type_tag] = read_bytes::<1>(code, offset)?;
+    if !is_type_tag(type_tag) {
+        return Err(format!("Unknown type {} at offset {}", type_tag, offset));
+    }
+    Ok(type_tag)
+}
+
+/// Decodes the instruction starting at `offset` without executing it.
+pub(crate) fn decode(code: &[u8], offset: usize) -> Decoded {
+    try_decode(code, offset).unwrap_or_else(|message| panic!("{}", message))
+}
+
+/// The operands of every opcode, `None` for a byte that is not an opcode.
+pub(crate) fn operands(opcode: u8) -> Option<&'static [Operand]> {
+    instructions::instruction(opcode).map(|instruction| instruction.operands)
+}
+
+/// Like `decode`, but reports malformed bytecode instead of panicking.
+pub(crate) fn try_decode(code: &[u8], offset: usize) -> Result<Decoded, String> {
+    let [opcode] = read_bytes::<1>(code, offset)?;
+    let operands = operands(opcode)
+        .ok_or_else(|| format!("Unknown Token! {} at offset {}", opcode, offset))?;
+    let mut decoded = Decoded {
+        offset,
+        opcode,
+        type_tag: None,
+        second_type_tag: None,
+        operand: None,
+        size: 1,
+    };
+    let mut cursor = offset + 1;
+    for operand in operands {
+        match operand {
+            Operand::Type => {
+                let type_tag = read_type_tag(code, cursor)?;
+                match decoded.type_tag {
+                    None => decoded.type_tag = Some(type_tag),
+                    Some(_) => decoded.second_type_tag = Some(type_tag),
+                }
+            }
+            Operand::Value => {
+                let size = operand.size(decoded.type_tag);
+                if code.len() < cursor + size {
+                    return Err(format!("Truncated instruction at offset {}", offset));
+                }
+            }
+            Operand::Address | Operand::Target => {
+                decoded.operand = Some(usize::from_le_bytes(read_bytes(code, cursor)?));
+            }
+            Operand::HostFunction => {
+                decoded.operand = Some(u32::from_le_bytes(read_bytes(code, cursor)?) as usize);
+            }
+        }
+        cursor += operand.size(decoded.type_tag);
+    }
+    decoded.size = cursor - offset;
+    Ok(decoded)
+}
+
+/// How many bytes `instruction` pops off the stack and then pushes, `None` for
+/// `call_host`, whose effect depends on the host function.
+pub(crate) fn stack_effect(instruction: &Decoded) -> Option<(usize, usize)> {
+    let Effect::Stack(pops, pushes) = instructions::instruction(instruction.opcode)?.effect else {
+        return None;
+    };
+    let size = |slot: &Slot| match slot {
+        Slot::First => instruction.type_tag.map_or(0, type_size),
+        Slot::Second => instruction.second_type_tag.map_or(0, type_size),
+        Slot::Bool => 1,
+    };
+    Some((pops.iter().map(size).sum(), pushes.iter().map(size).sum()))
+}
+
+/// Decodes the whole sequence front to back.
+pub(crate) fn decode_all(code: &[u8]) -> Vec<Decoded> {
+    let mut instructions = Vec::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let decoded = decode(code, offset);
+        offset += decoded.size;
+        instructions.push(decoded);
+    }
+    instructions
+}
+
+/// Offsets at which a basic block starts: the entry, every jump target and
+/// every instruction following a jump.
+pub(crate) fn block_leaders(instructions: &[Decoded]) -> Vec<usize> {
+    let mut leaders = vec![0];
+    for instruction in instructions {
+        if let Some(target) = instruction.jump_target() {
+            leaders.push(target);
+            leaders.push(instruction.offset + instruction.size);
+        }
+    }
+    leaders.sort_unstable();
+    leaders.dedup();
+    leaders
+}
+
+/// The mnemonic of an opcode or type tag, used when printing instructions
+/// back out.
+pub(crate) fn mnemonic(token: u8) -> String {
+    instructions::instruction(token)
+        .map(|instruction| instruction.mnemonic)
+        .or_else(|| instructions::type_tag(token).map(|type_tag| type_tag.mnemonic))
+        .map_or_else(|| format!("<{}>", token), str::to_owned)
+}
+
+pub(crate) fn type_names(types: &[u8]) -> String {
+    let names: Vec<String> = types.iter().map(|tag| mnemonic(*tag)).collect();
+    format!("[{}]", names.join(", "))
+}
+
+pub(crate) fn describe(instruction: &Decoded) -> String {
+    let mut text = mnemonic(instruction.opcode);
+    for tag in [instruction.type_tag, instruction.second_type_tag]
+        .into_iter()
+        .flatten()
+    {
+        text.push(' ');
+        text.push_str(&mnemonic(tag));
+    }
+    text
+}
+
+/// An instruction detached from its position, so that optimization passes can
+/// drop, insert and rewrite instructions before `layout` encodes them again.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Op {
+    /// Offset of the instruction this one came from. Instructions a pass puts
+    /// in place of others take the offset of the first one they replace.
+    pub origin: usize,
+    pub opcode: u8,
+    pub type_tag: Option<u8>,
+    pub second_type_tag: Option<u8>,
+    /// As in `Decoded`; jump targets are offsets in the original program.
+    pub operand: Option<usize>,
+    /// Little-endian value of `push`, `add_immediate` and `increment`.
+    pub immediate: Vec<u8>,
+}
+
+impl Op {
+    pub(crate) fn new(origin: usize, opcode: u8, type_tag: Option<u8>) -> Op {
+        Op {
+            origin,
+            opcode,
+            type_tag,
+            second_type_tag: None,
+            operand: None,
+            immediate: Vec::new(),
+        }
+    }
+    pub(crate) fn push(origin: usize, type_tag: u8, immediate: Vec<u8>) -> Op {
+        Op {
+            immediate,
+            ..Op::new(origin, instructions::op::Push, Some(type_tag))
+        }
+    }
+    pub(crate) fn jump_target(&self) -> Option<usize> {
+        let decoded = Decoded {
+            offset: self.origin,
+            opcode: self.opcode,
+            type_tag: self.type_tag,
+            second_type_tag: self.second_type_tag,
+            operand: self.operand,
+            size: 0,
+        };
+        decoded.jump_target()
+    }
+    fn encode(&self, operand: Option<usize>, output: &mut Vec<u8>) {
+        output.push(self.opcode);
+        let mut types = [self.type_tag, self.second_type_tag].into_iter().flatten();
+        for kind in operands(self.opcode).unwrap_or_default() {
+            match kind {
+                Operand::Type => output.extend(types.next()),
+                Operand::Value => output.extend_from_slice(&self.immediate),
+                Operand::Address | Operand::Target => {
+                    output.extend(operand.into_iter().flat_map(usize::to_le_bytes))
+                }
+                Operand::HostFunction => {
+                    output.extend(operand.into_iter().flat_map(|id| (id as u32).to_le_bytes()))
+                }
+            }
+        }
+    }
+    fn size(&self) -> usize {
+        let mut output = Vec::new();
+        self.encode(self.operand, &mut output);
+        output.len()
+    }
+}
+
+/// Whether the instruction ends with a value of its type.
+fn has_immediate(opcode: u8) -> bool {
+    operands(opcode).is_some_and(|operands| operands.ends_with(&[Operand::Value]))
+}
+
+/// Decodes `code` into `Op`s whose origins are their offsets.
+pub(crate) fn lift(code: &[u8]) -> Vec<Op> {
+    decode_all(code)
+        .into_iter()
+        .map(|decoded| {
+            let end = decoded.offset + decoded.size;
+            let immediate = match decoded.type_tag {
+                Some(type_tag) if has_immediate(decoded.opcode) => {
+                    code[end - type_size(type_tag)..end].to_vec()
+                }
+                _ => Vec::new(),
+            };
+            Op {
+                origin: decoded.offset,
+                opcode: decoded.opcode,
+                type_tag: decoded.type_tag,
+                second_type_tag: decoded.second_type_tag,
+                operand: decoded.operand,
+                immediate,
+            }
+        })
+        .collect()
+}
+
+/// Where a jump to `target` in the original program lands after `lift`ed
+/// instructions were changed: the first instruction at or after `target`
+/// that survived, or the end of the program. Returns an index into `ops`.
+pub(crate) fn resolve(ops: &[Op], target: usize) -> usize {
+    ops.partition_point(|op| op.origin < target)
+}
+
+/// The offset every op gets from `layout`, together with its `origin`.
+pub(crate) fn origins(ops: &[Op]) -> Vec<(usize, usize)> {
+    let mut offset = 0;
+    ops.iter()
+        .map(|op| {
+            let origin = (offset, op.origin);
+            offset += op.size();
+            origin
+        })
+        .collect()
+}
+
+/// Encodes `ops` back to bytecode, pointing every jump at the new offset of
+/// the instruction it targeted in the original program.
+pub(crate) fn layout(ops: &[Op]) -> Vec<u8> {
+    let mut offsets = Vec::with_capacity(ops.len() + 1);
+    let mut offset = 0;
+    for op in ops {
+        offsets.push(offset);
+        offset += op.size();
+    }
+    offsets.push(offset);
+
+    let mut code = Vec::with_capacity(offset);
+    for op in ops {
+        let operand = match op.jump_target() {
+            Some(target) => Some(offsets[resolve(ops, target)]),
+            None => op.operand,
+        };
+        op.encode(operand, &mut code);
+    }
+    code
+}
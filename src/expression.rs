@@ -0,0 +1,220 @@
+//! Integer expressions the assembler takes wherever an integer operand
+//! goes, such as `buf + 4 * 3`, `end - start` or `sizeof(i64) * LENGTH`.
+//! They are evaluated in `i128`, and an overflow even there is an error.
+
+use crate::assembler::parse_integer;
+use crate::bytecode;
+use crate::create_mapping;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Expr {
+    Number(i128),
+    /// A constant, label or variable.
+    Name(String),
+    Negate(Box<Expr>),
+    /// `+`, `-`, `*`, `/` or `%` of two expressions.
+    Binary(char, Box<Expr>, Box<Expr>),
+}
+
+const OPERATORS: [char; 5] = ['+', '-', '*', '/', '%'];
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Lexeme {
+    Number(i128),
+    Name(String),
+    Operator(char),
+    Open,
+    Close,
+}
+
+fn is_name_char(char: char) -> bool {
+    char.is_alphanumeric() || char == '_' || char == '.'
+}
+
+fn lex(text: &str) -> Result<Vec<Lexeme>, String> {
+    let chars: Vec<char> = text.chars().collect();
+    let mut lexemes = Vec::new();
+    let mut index = 0;
+    while index < chars.len() {
+        let char = chars[index];
+        let start = index;
+        index += 1;
+        match char {
+            _ if char.is_whitespace() => {}
+            '(' => lexemes.push(Lexeme::Open),
+            ')' => lexemes.push(Lexeme::Close),
+            _ if OPERATORS.contains(&char) => lexemes.push(Lexeme::Operator(char)),
+            '\'' => {
+                while index < chars.len() && chars[index] != '\'' {
+                    index += if chars[index] == '\\' { 2 } else { 1 };
+                }
+                index += 1;
+                let literal: String = chars[start..index.min(chars.len())].iter().collect();
+                let value = parse_integer(&literal)
+                    .ok_or_else(|| format!("{} is not a valid character", literal))?;
+                lexemes.push(Lexeme::Number(value));
+            }
+            _ if is_name_char(char) => {
+                while index < chars.len() && is_name_char(chars[index]) {
+                    index += 1;
+                }
+                let word: String = chars[start..index].iter().collect();
+                if char.is_ascii_digit() {
+                    let value = parse_integer(&word)
+                        .ok_or_else(|| format!("{} is not a valid integer", word))?;
+                    lexemes.push(Lexeme::Number(value));
+                } else {
+                    lexemes.push(Lexeme::Name(word));
+                }
+            }
+            _ => return Err(format!("Unexpected {} in {}", char, text)),
+        }
+    }
+    Ok(lexemes)
+}
+
+/// Recursive descent over the lexemes, with the usual precedence: unary
+/// `-` binds tightest, then `*`, `/` and `%`, then `+` and `-`, all left to
+/// right.
+struct Parser<'a> {
+    text: &'a str,
+    lexemes: Vec<Lexeme>,
+    next: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&Lexeme> {
+        self.lexemes.get(self.next)
+    }
+
+    fn take(&mut self) -> Result<Lexeme, String> {
+        let lexeme = self
+            .peek()
+            .cloned()
+            .ok_or_else(|| format!("{} ends too early", self.text))?;
+        self.next += 1;
+        Ok(lexeme)
+    }
+
+    fn expect(&mut self, expected: Lexeme) -> Result<(), String> {
+        match self.take()? {
+            lexeme if lexeme == expected => Ok(()),
+            _ => Err(format!("Unbalanced parentheses in {}", self.text)),
+        }
+    }
+
+    fn binary(
+        &mut self,
+        operators: &[char],
+        operand: fn(&mut Self) -> Result<Expr, String>,
+    ) -> Result<Expr, String> {
+        let mut left = operand(self)?;
+        while let Some(Lexeme::Operator(operator)) = self.peek() {
+            let operator = *operator;
+            if !operators.contains(&operator) {
+                break;
+            }
+            self.next += 1;
+            left = Expr::Binary(operator, Box::new(left), Box::new(operand(self)?));
+        }
+        Ok(left)
+    }
+
+    fn sum(&mut self) -> Result<Expr, String> {
+        self.binary(&['+', '-'], Self::product)
+    }
+
+    fn product(&mut self) -> Result<Expr, String> {
+        self.binary(&['*', '/', '%'], Self::unary)
+    }
+
+    fn unary(&mut self) -> Result<Expr, String> {
+        match self.take()? {
+            Lexeme::Operator('-') => Ok(Expr::Negate(Box::new(self.unary()?))),
+            Lexeme::Operator('+') => self.unary(),
+            Lexeme::Number(value) => Ok(Expr::Number(value)),
+            Lexeme::Name(name) if name == "sizeof" => {
+                self.expect(Lexeme::Open)?;
+                let type_name = match self.take()? {
+                    Lexeme::Name(type_name) => type_name,
+                    _ => return Err(format!("sizeof needs a type in {}", self.text)),
+                };
+                let type_tag = create_mapping()
+                    .get(&type_name)
+                    .copied()
+                    .filter(|type_tag| bytecode::is_type_tag(*type_tag))
+                    .ok_or_else(|| format!("Unknown type {}", type_name))?;
+                self.expect(Lexeme::Close)?;
+                Ok(Expr::Number(bytecode::type_size(type_tag) as i128))
+            }
+            Lexeme::Name(name) => Ok(Expr::Name(name)),
+            Lexeme::Open => {
+                let inner = self.sum()?;
+                self.expect(Lexeme::Close)?;
+                Ok(inner)
+            }
+            Lexeme::Operator(_) | Lexeme::Close => {
+                Err(format!("Expected a value in {}", self.text))
+            }
+        }
+    }
+}
+
+pub(crate) fn parse(text: &str) -> Result<Expr, String> {
+    let mut parser = Parser {
+        text,
+        lexemes: lex(text)?,
+        next: 0,
+    };
+    let expr = parser.sum()?;
+    if parser.next < parser.lexemes.len() {
+        return Err(format!("Unexpected text after the value in {}", text));
+    }
+    Ok(expr)
+}
+
+impl Expr {
+    /// The value of the expression, with the value of every name from
+    /// `resolve`.
+    pub(crate) fn evaluate(
+        &self,
+        resolve: &mut dyn FnMut(&str) -> Result<i128, String>,
+    ) -> Result<i128, String> {
+        match self {
+            Expr::Number(value) => Ok(*value),
+            Expr::Name(name) => resolve(name),
+            Expr::Negate(inner) => inner
+                .evaluate(resolve)?
+                .checked_neg()
+                .ok_or_else(|| "Overflow in negation".to_owned()),
+            Expr::Binary(operator, left, right) => {
+                let (left, right) = (left.evaluate(resolve)?, right.evaluate(resolve)?);
+                if right == 0 && matches!(operator, '/' | '%') {
+                    return Err(format!(
+                        "Division by zero in {} {} {}",
+                        left, operator, right
+                    ));
+                }
+                let result = match operator {
+                    '+' => left.checked_add(right),
+                    '-' => left.checked_sub(right),
+                    '*' => left.checked_mul(right),
+                    '/' => left.checked_div(right),
+                    _ => left.checked_rem(right),
+                };
+                result.ok_or_else(|| format!("Overflow in {} {} {}", left, operator, right))
+            }
+        }
+    }
+}
+
+/// Whether an operand that is `text` so far goes on with the word `next`:
+/// when `next` is an operator on its own, or `text` ends in one or has an
+/// unclosed parenthesis. So `buf + 4` and `buf+4` are one operand, while
+/// `counter -1` is two.
+pub(crate) fn continues(text: &str, next: &str) -> bool {
+    let open = text.matches('(').count() > text.matches(')').count();
+    let ends_in_operator = text.ends_with(OPERATORS);
+    let is_operator = next.len() == 1 && next.starts_with(OPERATORS);
+    open || ends_in_operator || is_operator
+}
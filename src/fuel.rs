@@ -0,0 +1,50 @@
+use crate::StackUpperVector;
+
+/// Instruction budget for `StackUpperVector::execute_with_fuel`. Every opcode
+/// costs 1 unit unless configured otherwise with `set_cost`.
+pub(crate) struct Fuel {
+    pub remaining: u64,
+    costs: [u64; 256],
+}
+
+/// Why `execute_with_fuel` returned.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ExecutionOutcome {
+    Finished,
+    /// The next instruction costs more than what is left. Nothing of it has been
+    /// executed, so calling `execute_with_fuel` again once `remaining` has been
+    /// topped up resumes exactly there.
+    OutOfFuel,
+}
+
+impl Fuel {
+    pub(crate) fn new(amount: u64) -> Fuel {
+        Fuel {
+            remaining: amount,
+            costs: [1; 256],
+        }
+    }
+    pub(crate) fn set_cost(&mut self, opcode: u8, cost: u64) {
+        self.costs[opcode as usize] = cost;
+    }
+    pub(crate) fn cost(&self, opcode: u8) -> u64 {
+        self.costs[opcode as usize]
+    }
+}
+
+impl StackUpperVector {
+    /// Runs the program from the cursor until it ends or `fuel` runs out. This
+    /// always interprets, whatever engine the VM was configured with, since
+    /// only the interpreter stops between any two instructions.
+    pub(crate) fn execute_with_fuel(&mut self, fuel: &mut Fuel) -> ExecutionOutcome {
+        while self.cursor_offset() < self.token_byte_sequence.len() {
+            let cost = fuel.cost(self.token_byte_sequence[self.cursor_offset()]);
+            if cost > fuel.remaining {
+                return ExecutionOutcome::OutOfFuel;
+            }
+            fuel.remaining -= cost;
+            self.do_Token();
+        }
+        ExecutionOutcome::Finished
+    }
+}
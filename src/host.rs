@@ -0,0 +1,273 @@
+use crate::bytecode::{self, BOOL, F32, F64, I16, I32, I64, I8, U16, U32, U64, U8};
+use crate::{StackArray, StackMachine, StackUpperVector, Token};
+use std::collections::HashMap;
+
+/// A typed value crossing the boundary between bytecode and Rust.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum HostValue {
+    Bool(bool),
+    I8(i8),
+    I16(i16),
+    I32(i32),
+    I64(i64),
+    U8(u8),
+    U16(u16),
+    U32(u32),
+    U64(u64),
+    F32(f32),
+    F64(f64),
+}
+
+impl HostValue {
+    pub(crate) fn type_tag(&self) -> u8 {
+        match self {
+            HostValue::Bool(_) => BOOL,
+            HostValue::I8(_) => I8,
+            HostValue::I16(_) => I16,
+            HostValue::I32(_) => I32,
+            HostValue::I64(_) => I64,
+            HostValue::U8(_) => U8,
+            HostValue::U16(_) => U16,
+            HostValue::U32(_) => U32,
+            HostValue::U64(_) => U64,
+            HostValue::F32(_) => F32,
+            HostValue::F64(_) => F64,
+        }
+    }
+    /// Reads a value of `type_tag` from its little-endian bytes, as `push` stores it.
+    pub(crate) fn from_le_bytes(type_tag: u8, bytes: &[u8]) -> HostValue {
+        fn array<const N: usize>(bytes: &[u8]) -> [u8; N] {
+            bytes[..N].try_into().unwrap()
+        }
+        match type_tag {
+            BOOL => HostValue::Bool(bytes[0] != 0),
+            I8 => HostValue::I8(i8::from_le_bytes(array(bytes))),
+            I16 => HostValue::I16(i16::from_le_bytes(array(bytes))),
+            I32 => HostValue::I32(i32::from_le_bytes(array(bytes))),
+            I64 => HostValue::I64(i64::from_le_bytes(array(bytes))),
+            U8 => HostValue::U8(bytes[0]),
+            U16 => HostValue::U16(u16::from_le_bytes(array(bytes))),
+            U32 => HostValue::U32(u32::from_le_bytes(array(bytes))),
+            U64 => HostValue::U64(u64::from_le_bytes(array(bytes))),
+            F32 => HostValue::F32(f32::from_le_bytes(array(bytes))),
+            F64 => HostValue::F64(f64::from_le_bytes(array(bytes))),
+            _ => panic!("Unknown type"),
+        }
+    }
+    pub(crate) fn to_le_bytes(self) -> Vec<u8> {
+        match self {
+            HostValue::Bool(value) => vec![value as u8],
+            HostValue::I8(value) => value.to_le_bytes().to_vec(),
+            HostValue::I16(value) => value.to_le_bytes().to_vec(),
+            HostValue::I32(value) => value.to_le_bytes().to_vec(),
+            HostValue::I64(value) => value.to_le_bytes().to_vec(),
+            HostValue::U8(value) => value.to_le_bytes().to_vec(),
+            HostValue::U16(value) => value.to_le_bytes().to_vec(),
+            HostValue::U32(value) => value.to_le_bytes().to_vec(),
+            HostValue::U64(value) => value.to_le_bytes().to_vec(),
+            HostValue::F32(value) => value.to_le_bytes().to_vec(),
+            HostValue::F64(value) => value.to_le_bytes().to_vec(),
+        }
+    }
+    fn pop_from(stack: &mut StackArray, type_tag: u8) -> HostValue {
+        match type_tag {
+            BOOL => HostValue::Bool(stack.pop::<bool>()),
+            I8 => HostValue::I8(stack.pop::<i8>()),
+            I16 => HostValue::I16(stack.pop::<i16>()),
+            I32 => HostValue::I32(stack.pop::<i32>()),
+            I64 => HostValue::I64(stack.pop::<i64>()),
+            U8 => HostValue::U8(stack.pop::<u8>()),
+            U16 => HostValue::U16(stack.pop::<u16>()),
+            U32 => HostValue::U32(stack.pop::<u32>()),
+            U64 => HostValue::U64(stack.pop::<u64>()),
+            F32 => HostValue::F32(stack.pop::<f32>()),
+            F64 => HostValue::F64(stack.pop::<f64>()),
+            _ => panic!("Unknown type"),
+        }
+    }
+    fn push_to(self, stack: &mut StackArray) {
+        match self {
+            HostValue::Bool(value) => stack.push(value),
+            HostValue::I8(value) => stack.push(value),
+            HostValue::I16(value) => stack.push(value),
+            HostValue::I32(value) => stack.push(value),
+            HostValue::I64(value) => stack.push(value),
+            HostValue::U8(value) => stack.push(value),
+            HostValue::U16(value) => stack.push(value),
+            HostValue::U32(value) => stack.push(value),
+            HostValue::U64(value) => stack.push(value),
+            HostValue::F32(value) => stack.push(value),
+            HostValue::F64(value) => stack.push(value),
+        }
+    }
+}
+
+/// Argument and result type tags of a host function. Arguments are pushed in
+/// order, so the last argument is on top of the stack at the call.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct HostSignature {
+    pub params: Vec<u8>,
+    pub results: Vec<u8>,
+}
+
+/// What a host function can reach of the VM besides its arguments.
+pub(crate) struct HostContext<'a> {
+    pub memory: &'a [u8],
+    /// Where the program's output goes, captured or not.
+    pub output: &'a mut dyn std::io::Write,
+}
+
+type HostFunction = Box<dyn FnMut(&[HostValue], &mut HostContext) -> Vec<HostValue>>;
+
+/// Rust functions reachable from bytecode through `call_host <id>`.
+pub(crate) struct HostRegistry {
+    functions: HashMap<u32, (HostSignature, HostFunction)>,
+}
+
+fn type_tags(tokens: &[Token]) -> Vec<u8> {
+    tokens
+        .iter()
+        .map(|token| {
+            let type_tag = *token as u8;
+            if !bytecode::is_type_tag(type_tag) {
+                panic!("{:?} is not a type", token);
+            }
+            type_tag
+        })
+        .collect()
+}
+
+impl HostRegistry {
+    pub(crate) fn new() -> HostRegistry {
+        HostRegistry {
+            functions: HashMap::new(),
+        }
+    }
+    /// Makes `function` callable as `call_host id`. It gets the VM's memory
+    /// and output along with the arguments.
+    pub(crate) fn register(
+        &mut self,
+        id: u32,
+        params: &[Token],
+        results: &[Token],
+        function: impl FnMut(&[HostValue], &mut HostContext) -> Vec<HostValue> + 'static,
+    ) {
+        let signature = HostSignature {
+            params: type_tags(params),
+            results: type_tags(results),
+        };
+        self.functions.insert(id, (signature, Box::new(function)));
+    }
+    pub(crate) fn signature(&self, id: u32) -> Option<&HostSignature> {
+        self.functions.get(&id).map(|(signature, _)| signature)
+    }
+    /// Calls function `id` with `args`, which must match its signature, and
+    /// checks that it returns what the signature declares.
+    pub(crate) fn call(
+        &mut self,
+        id: u32,
+        args: &[HostValue],
+        context: &mut HostContext,
+    ) -> Vec<HostValue> {
+        let (signature, function) = self
+            .functions
+            .get_mut(&id)
+            .unwrap_or_else(|| panic!("Unknown host function {}", id));
+        let results = function(args, context);
+        let result_tags: Vec<u8> = results.iter().map(HostValue::type_tag).collect();
+        if result_tags != signature.results {
+            panic!(
+                "Host function {} returned {}, its signature declares {}",
+                id,
+                bytecode::type_names(&result_tags),
+                bytecode::type_names(&signature.results)
+            );
+        }
+        results
+    }
+}
+
+/// The `len` bytes of `memory` at `address`, or a panic naming what is out of
+/// bounds.
+fn memory_bytes(memory: &[u8], address: u64, len: u64) -> &[u8] {
+    usize::try_from(address)
+        .ok()
+        .zip(usize::try_from(len).ok())
+        .and_then(|(start, len)| memory.get(start..start.checked_add(len)?))
+        .unwrap_or_else(|| {
+            panic!(
+                "String at {} of {} bytes is out of bounds ({} bytes)",
+                address,
+                len,
+                memory.len()
+            )
+        })
+}
+
+/// Host functions every program run from the command line can use.
+pub(crate) fn register_builtins(registry: &mut HostRegistry) {
+    // 0: write_byte(u8), writes one raw byte.
+    registry.register(0, &[Token::U8], &[], |args, context| {
+        if let HostValue::U8(byte) = args[0] {
+            context.output.write_all(&[byte]).unwrap();
+        }
+        Vec::new()
+    });
+    // 1: write_string(u64), writes the NUL-terminated bytes at an address, as
+    // `.string` lays them out.
+    registry.register(1, &[Token::U64], &[], |args, context| {
+        if let HostValue::U64(address) = args[0] {
+            let memory = context.memory;
+            let rest = usize::try_from(address)
+                .ok()
+                .and_then(|start| memory.get(start..))
+                .unwrap_or_else(|| memory_bytes(memory, address, 0));
+            let len = rest
+                .iter()
+                .position(|byte| *byte == 0)
+                .unwrap_or_else(|| panic!("String at {} has no terminating NUL", address));
+            context.output.write_all(&rest[..len]).unwrap();
+        }
+        Vec::new()
+    });
+    // 2: write_lstring(u64), writes the bytes at an address counted by the
+    // `u64` before them, as `.lstring` lays them out.
+    registry.register(2, &[Token::U64], &[], |args, context| {
+        if let HostValue::U64(address) = args[0] {
+            let memory = context.memory;
+            let len = memory_bytes(memory, address, 8);
+            let len = u64::from_le_bytes(len.try_into().unwrap());
+            let text = memory_bytes(memory, address.saturating_add(8), len);
+            context.output.write_all(text).unwrap();
+        }
+        Vec::new()
+    });
+}
+
+impl StackUpperVector {
+    pub(crate) fn call_host(&mut self, id: u32) {
+        let params = self
+            .host
+            .signature(id)
+            .unwrap_or_else(|| panic!("Unknown host function {}", id))
+            .params
+            .clone();
+        let mut args: Vec<HostValue> = params
+            .iter()
+            .rev()
+            .map(|type_tag| HostValue::pop_from(&mut self.lower_stack, *type_tag))
+            .collect();
+        args.reverse();
+        let mut stdout = std::io::stdout();
+        let mut context = HostContext {
+            memory: &self.buffer.buffer,
+            output: match &mut self.output {
+                Some(captured) => captured,
+                None => &mut stdout,
+            },
+        };
+        for result in self.host.call(id, &args, &mut context) {
+            result.push_to(&mut self.lower_stack);
+        }
+    }
+}
@@ -0,0 +1,432 @@
+//! The instruction set in one table: every opcode with its mnemonic, its
+//! operands, the types it takes and what it does to the stack, and every
+//! type tag with its Rust type. `Token`, the assembler's mnemonics, decoding
+//! and encoding in `bytecode`, the verifier and `--opcodes` all come from it,
+//! and so does dispatch in `do_Token` and the predecoded and threaded engines,
+//! through `dispatch!`. Adding an instruction means adding a row here, a
+//! method and a handler named after its mnemonic, its specializations in
+//! `predecode`, and its behaviour in the register engine, the JIT and the
+//! reference interpreter. The `every_instruction_runs_on_every_engine` test
+//! runs each row with each of its types on every engine.
+
+use std::fmt::Write;
+
+/// What follows an opcode in an instruction, in the order written and encoded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Operand {
+    /// A type tag, one byte.
+    Type,
+    /// A value of the instruction's first type, in that type's size.
+    Value,
+    /// A memory address, eight bytes.
+    Address,
+    /// A code offset to jump to, eight bytes.
+    Target,
+    /// A host function id, four bytes.
+    HostFunction,
+}
+
+impl Operand {
+    pub(crate) fn describe(self) -> &'static str {
+        match self {
+            Operand::Type => "a type",
+            Operand::Value => "a value",
+            Operand::Address => "an address",
+            Operand::Target => "a jump target",
+            Operand::HostFunction => "a host function id",
+        }
+    }
+
+    /// How many bytes it takes after an instruction whose first type is `type_tag`.
+    pub(crate) fn size(self, type_tag: Option<u8>) -> usize {
+        match self {
+            Operand::Type => 1,
+            Operand::Value => type_tag.map_or(0, type_size),
+            Operand::Address | Operand::Target => 8,
+            Operand::HostFunction => 4,
+        }
+    }
+}
+
+/// Which type tags the `Type` operands of an instruction may be.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Types {
+    /// It has no `Type` operand.
+    None,
+    Any,
+    /// Any but `bool`.
+    Numeric,
+}
+
+/// A value on the stack, by where its type comes from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Slot {
+    /// The instruction's first type.
+    First,
+    /// The instruction's second type.
+    Second,
+    Bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Effect {
+    /// Pops the first values and then pushes the second, both bottom first.
+    Stack(&'static [Slot], &'static [Slot]),
+    /// Pops the parameters of the host function and pushes its results.
+    Host,
+}
+
+pub(crate) struct Instruction {
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub operands: &'static [Operand],
+    pub types: Types,
+    pub effect: Effect,
+    pub summary: &'static str,
+    /// For a fused compare-and-branch, the `compare_*` opcode it branches on.
+    pub condition: Option<u8>,
+}
+
+impl Instruction {
+    pub(crate) fn has(&self, operand: Operand) -> bool {
+        self.operands.contains(&operand)
+    }
+}
+
+pub(crate) struct TypeTag {
+    pub tag: u8,
+    pub mnemonic: &'static str,
+    pub size: usize,
+}
+
+/// Generates `Token`, the `op` constants, the `row` types, the type tag
+/// constants, `INSTRUCTIONS` and `TYPES` from the rows below, and the `with_type!`,
+/// `with_numeric_type!` and `dispatch!` macros the engines run them with.
+macro_rules! instruction_set {
+    (@condition) => { None };
+    (@condition $condition:ident) => { Some(op::$condition) };
+    (
+        $d:tt
+        instructions {
+            $($variant:ident = $opcode:literal, $mnemonic:ident, [$($operand:ident),*],
+                $types:ident, $effect:expr, $summary:literal $(branches on $condition:ident)?;)*
+        }
+        types {
+            $($any_variant:ident = $any_tag:literal, $any_type:ident, $any_constant:ident;)*
+        }
+        numeric types {
+            $($type_variant:ident = $tag:literal, $type:ident, $constant:ident;)*
+        }
+    ) => {
+        /// Most variants are only ever matched as their `op` byte.
+        #[allow(dead_code)]
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub(crate) enum Token {
+            $($variant = $opcode,)*
+            $($any_variant = $any_tag,)*
+            $($type_variant = $tag,)*
+        }
+
+        /// Every opcode and type tag as a `u8` named like its `Token`, to
+        /// match bytes against.
+        #[allow(non_upper_case_globals, dead_code)]
+        pub(crate) mod op {
+            $(pub(crate) const $variant: u8 = $opcode;)*
+            $(pub(crate) const $any_variant: u8 = $any_tag;)*
+            $(pub(crate) const $type_variant: u8 = $tag;)*
+        }
+
+        /// A type per instruction, named by its mnemonic, to key what an
+        /// engine keeps per instruction on at compile time.
+        #[allow(non_camel_case_types, dead_code)]
+        pub(crate) mod row {
+            $(pub(crate) struct $mnemonic;)*
+        }
+
+        $(pub(crate) const $any_constant: u8 = $any_tag;)*
+        $(pub(crate) const $constant: u8 = $tag;)*
+
+        pub(crate) const INSTRUCTIONS: &[Instruction] = {
+            use Effect::Stack;
+            use Slot::*;
+            &[$(Instruction {
+                opcode: $opcode,
+                mnemonic: stringify!($mnemonic),
+                operands: &[$(Operand::$operand),*],
+                types: Types::$types,
+                effect: $effect,
+                summary: $summary,
+                condition: instruction_set!(@condition $($condition)?),
+            },)*]
+        };
+
+        pub(crate) const TYPES: &[TypeTag] = &[
+            $(TypeTag {
+                tag: $any_tag,
+                mnemonic: stringify!($any_type),
+                size: std::mem::size_of::<$any_type>(),
+            },)*
+            $(TypeTag {
+                tag: $tag,
+                mnemonic: stringify!($type),
+                size: std::mem::size_of::<$type>(),
+            },)*
+        ];
+
+        /// `$body` with `$T` the Rust type of the type tag `$type_tag`.
+        macro_rules! with_type {
+            ($d type_tag:expr, $d T:ident => $d body:expr) => {
+                match $d type_tag {
+                    $($crate::instructions::op::$any_variant => {
+                        type $d T = $any_type;
+                        $d body
+                    })*
+                    $($crate::instructions::op::$type_variant => {
+                        type $d T = $type;
+                        $d body
+                    })*
+                    type_tag => panic!("Unknown type {}", type_tag),
+                }
+            };
+        }
+        pub(crate) use with_type;
+
+        /// `with_type!` for the types arithmetic works on, all but `bool`.
+        macro_rules! with_numeric_type {
+            ($d type_tag:expr, $d T:ident => $d body:expr) => {
+                match $d type_tag {
+                    $($crate::instructions::op::$type_variant => {
+                        type $d T = $type;
+                        $d body
+                    })*
+                    type_tag => panic!("{} is not a numeric type", type_tag),
+                }
+            };
+        }
+        pub(crate) use with_numeric_type;
+
+        /// `$callback!(mnemonic [T, U] [operands])` for the instruction
+        /// `$opcode`, with `T` and `U` the Rust types of its type tags
+        /// `$first` and `$second` and the operands that follow them, as
+        /// `Operand` names. `$first` and `$second` are only evaluated, in that
+        /// order, for an instruction with as many `Type` operands.
+        macro_rules! dispatch {
+            ($d opcode:expr, $d first:expr, $d second:expr, $d callback:ident) => {
+                match $d opcode {
+                    $($crate::instructions::op::$variant => $crate::instructions::dispatch!(
+                        @row $mnemonic $types [$($operand)*] $d first, $d second, $d callback
+                    ),)*
+                    opcode => panic!("Unknown Token! {}", opcode),
+                }
+            };
+            (@row $d mnemonic:ident $d types:ident [Type Type $d($d operand:ident)*]
+                $d first:expr, $d second:expr, $d callback:ident) => {
+                $crate::instructions::dispatch!(@type $d types, $d first, T =>
+                    $crate::instructions::dispatch!(@type $d types, $d second, U =>
+                        $d callback!($d mnemonic [T, U] [$d($d operand),*])))
+            };
+            (@row $d mnemonic:ident $d types:ident [Type $d($d operand:ident)*]
+                $d first:expr, $d second:expr, $d callback:ident) => {
+                $crate::instructions::dispatch!(@type $d types, $d first, T =>
+                    $d callback!($d mnemonic [T] [$d($d operand),*]))
+            };
+            (@row $d mnemonic:ident $d types:ident [$d($d operand:ident)*]
+                $d first:expr, $d second:expr, $d callback:ident) => {
+                $d callback!($d mnemonic [] [$d($d operand),*])
+            };
+            (@type Any, $d type_tag:expr, $d T:ident => $d body:expr) => {
+                $crate::instructions::with_type!($d type_tag, $d T => $d body)
+            };
+            (@type Numeric, $d type_tag:expr, $d T:ident => $d body:expr) => {
+                $crate::instructions::with_numeric_type!($d type_tag, $d T => $d body)
+            };
+        }
+        pub(crate) use dispatch;
+    };
+}
+
+instruction_set! {
+    $
+    instructions {
+        Push = 0, push, [Type, Value], Any, Stack(&[], &[First]),
+            "Pushes the value.";
+        Pop = 1, pop, [Type], Any, Stack(&[First], &[]),
+            "Pops the top value and prints it.";
+        Peek = 2, peek, [Type], Any, Stack(&[First], &[First]),
+            "Prints the top value.";
+        ClonePush = 3, clone_push, [Type], Any, Stack(&[First], &[First, First]),
+            "Pushes a copy of the top value.";
+        Add = 4, add, [Type], Numeric, Stack(&[First, First], &[First]),
+            "Replaces the top two values with their sum.";
+        Subtract = 5, subtract, [Type], Numeric, Stack(&[First, First], &[First]),
+            "Replaces the top two values with the lower minus the top.";
+        Multiply = 6, multiply, [Type], Numeric, Stack(&[First, First], &[First]),
+            "Replaces the top two values with their product.";
+        Divide = 7, divide, [Type], Numeric, Stack(&[First, First], &[First]),
+            "Replaces the top two values with the lower divided by the top.";
+        Store = 8, store, [Type, Address], Any, Stack(&[First], &[]),
+            "Pops the top value into memory at the address.";
+        PeekStore = 9, peek_store, [Type, Address], Any, Stack(&[First], &[First]),
+            "Copies the top value into memory at the address.";
+        Load = 10, load, [Type, Address], Any, Stack(&[], &[First]),
+            "Pushes the value in memory at the address.";
+        Goto = 11, goto, [Target], None, Stack(&[], &[]),
+            "Jumps to the target.";
+        PopGotoIfTrue = 12, pop_goto_if_true, [Target], None, Stack(&[Bool], &[]),
+            "Pops the top value and jumps to the target if it is true.";
+        PeekGotoIfTrue = 13, peek_goto_if_true, [Target], None, Stack(&[Bool], &[Bool]),
+            "Jumps to the target if the top value is true.";
+        LogicAnd = 14, logic_and, [], None, Stack(&[Bool, Bool], &[Bool]),
+            "Replaces the top two values with whether both are true.";
+        LogicOr = 15, logic_or, [], None, Stack(&[Bool, Bool], &[Bool]),
+            "Replaces the top two values with whether either is true.";
+        LogicNot = 16, logic_not, [], None, Stack(&[Bool], &[Bool]),
+            "Negates the top value.";
+        CompareEqual = 17, compare_equal, [Type], Numeric, Stack(&[First, First], &[Bool]),
+            "Replaces the top two values with whether they are equal.";
+        CompareNotEqual = 18, compare_not_equal, [Type], Numeric,
+            Stack(&[First, First], &[Bool]),
+            "Replaces the top two values with whether they differ.";
+        CompareGreater = 19, compare_greater, [Type], Numeric,
+            Stack(&[First, First], &[Bool]),
+            "Replaces the top two values with whether the lower is greater.";
+        CompareGreaterEqual = 20, compare_greater_equal, [Type], Numeric,
+            Stack(&[First, First], &[Bool]),
+            "Replaces the top two values with whether the lower is greater or equal.";
+        CompareLesser = 21, compare_lesser, [Type], Numeric,
+            Stack(&[First, First], &[Bool]),
+            "Replaces the top two values with whether the lower is lesser.";
+        CompareLesserEqual = 22, compare_lesser_equal, [Type], Numeric,
+            Stack(&[First, First], &[Bool]),
+            "Replaces the top two values with whether the lower is lesser or equal.";
+        TypeCast = 23, type_cast, [Type, Type], Numeric, Stack(&[First], &[Second]),
+            "Converts the top value from the first type to the second.";
+        CallHost = 35, call_host, [HostFunction], None, Effect::Host,
+            "Calls the host function registered under the id.";
+        AddImmediate = 36, add_immediate, [Type, Value], Numeric, Stack(&[First], &[First]),
+            "Adds the value to the top value.";
+        GotoIfEqual = 37, goto_if_equal, [Type, Target], Numeric, Stack(&[First, First], &[]),
+            "Pops the top two values and jumps to the target if they are equal."
+            branches on CompareEqual;
+        GotoIfNotEqual = 38, goto_if_not_equal, [Type, Target], Numeric,
+            Stack(&[First, First], &[]),
+            "Pops the top two values and jumps to the target if they differ."
+            branches on CompareNotEqual;
+        GotoIfGreater = 39, goto_if_greater, [Type, Target], Numeric,
+            Stack(&[First, First], &[]),
+            "Pops the top two values and jumps to the target if the lower is greater."
+            branches on CompareGreater;
+        GotoIfGreaterEqual = 40, goto_if_greater_equal, [Type, Target], Numeric,
+            Stack(&[First, First], &[]),
+            "Pops the top two values and jumps to the target if the lower is greater or equal."
+            branches on CompareGreaterEqual;
+        GotoIfLesser = 41, goto_if_lesser, [Type, Target], Numeric,
+            Stack(&[First, First], &[]),
+            "Pops the top two values and jumps to the target if the lower is lesser."
+            branches on CompareLesser;
+        GotoIfLesserEqual = 42, goto_if_lesser_equal, [Type, Target], Numeric,
+            Stack(&[First, First], &[]),
+            "Pops the top two values and jumps to the target if the lower is lesser or equal."
+            branches on CompareLesserEqual;
+        Increment = 43, increment, [Type, Address, Value], Numeric, Stack(&[], &[]),
+            "Adds the value to the one in memory at the address.";
+    }
+    types {
+        Bool = 24, bool, BOOL;
+    }
+    numeric types {
+        I8 = 25, i8, I8;
+        I16 = 26, i16, I16;
+        I32 = 27, i32, I32;
+        I64 = 28, i64, I64;
+        U8 = 29, u8, U8;
+        U16 = 30, u16, U16;
+        U32 = 31, u32, U32;
+        U64 = 32, u64, U64;
+        F32 = 33, f32, F32;
+        F64 = 34, f64, F64;
+    }
+}
+
+/// The row of `opcode`, `None` for a byte that is not an opcode.
+pub(crate) fn instruction(opcode: u8) -> Option<&'static Instruction> {
+    INSTRUCTIONS
+        .iter()
+        .find(|instruction| instruction.opcode == opcode)
+}
+
+pub(crate) fn type_tag(tag: u8) -> Option<&'static TypeTag> {
+    TYPES.iter().find(|type_tag| type_tag.tag == tag)
+}
+
+pub(crate) fn type_size(tag: u8) -> usize {
+    type_tag(tag)
+        .unwrap_or_else(|| panic!("Unknown type tag {}", tag))
+        .size
+}
+
+fn slot_name(slot: &Slot) -> &'static str {
+    match slot {
+        Slot::First => "T",
+        Slot::Second => "U",
+        Slot::Bool => "bool",
+    }
+}
+
+/// A description of every instruction and type, as `--opcodes` prints it.
+pub(crate) fn reference() -> String {
+    let mut text = String::new();
+    writeln!(
+        text,
+        "{:>6}  {:<30} {:<8} {:<22} summary",
+        "opcode", "syntax", "types", "stack"
+    )
+    .unwrap();
+    for instruction in INSTRUCTIONS {
+        let mut syntax = instruction.mnemonic.to_owned();
+        let mut types = ["T", "U"].into_iter();
+        for operand in instruction.operands {
+            syntax.push(' ');
+            syntax.push_str(match operand {
+                Operand::Type => types.next().unwrap(),
+                Operand::Value => "value",
+                Operand::Address => "address",
+                Operand::Target => "target",
+                Operand::HostFunction => "id",
+            });
+        }
+        let stack = match instruction.effect {
+            Effect::Stack(pops, pushes) => {
+                let pops: Vec<&str> = pops.iter().map(slot_name).collect();
+                let pushes: Vec<&str> = pushes.iter().map(slot_name).collect();
+                format!("{} -- {}", pops.join(" "), pushes.join(" "))
+            }
+            Effect::Host => "params -- results".to_owned(),
+        };
+        let types = match instruction.types {
+            Types::None => "",
+            Types::Any => "any",
+            Types::Numeric => "numeric",
+        };
+        writeln!(
+            text,
+            "{:>6}  {:<30} {:<8} {:<22} {}",
+            instruction.opcode,
+            syntax,
+            types,
+            stack.trim(),
+            instruction.summary
+        )
+        .unwrap();
+    }
+    writeln!(text, "\n{:>6}  {:<30} size", "tag", "type").unwrap();
+    for type_tag in TYPES {
+        writeln!(
+            text,
+            "{:>6}  {:<30} {}",
+            type_tag.tag, type_tag.mnemonic, type_tag.size
+        )
+        .unwrap();
+    }
+    text
+}
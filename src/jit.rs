@@ -0,0 +1,659 @@
+//! Template JIT for Linux x86-64. Every bytecode instruction is translated on
+//! its own into machine code that works on the same `StackArray` and
+//! `BufferArray` memory as the interpreter, so execution can move between
+//! native code and `do_Token` at any instruction boundary.
+//!
+//! Register use inside compiled code:
+//! `r12` stack end, `r13` memory base, `r15` memory length, `r14` `JitState`.
+//!
+//! Instructions that are not compiled (`pop`/`peek` output, integer division,
+//! float to integer and `u64` to float casts, float `increment`, `call_host`)
+//! exit to the interpreter, which runs them and enters native code again. Integer
+//! overflow, out-of-bounds memory accesses and stack overflow or underflow take
+//! the same exit, so the interpreter reports them exactly as it would have.
+
+use crate::bytecode::{self, Decoded, BOOL, F32, F64, I16, I32, I64, I8, U64};
+use crate::instructions::op;
+use crate::StackUpperVector;
+use std::collections::HashMap;
+use std::ffi::c_void;
+
+const PROT_READ: i32 = 1;
+const PROT_WRITE: i32 = 2;
+const PROT_EXEC: i32 = 4;
+const MAP_PRIVATE: i32 = 2;
+const MAP_ANONYMOUS: i32 = 0x20;
+
+extern "C" {
+    fn mmap(
+        addr: *mut c_void,
+        len: usize,
+        prot: i32,
+        flags: i32,
+        fd: i32,
+        offset: i64,
+    ) -> *mut c_void;
+    fn mprotect(addr: *mut c_void, len: usize, prot: i32) -> i32;
+    fn munmap(addr: *mut c_void, len: usize) -> i32;
+}
+
+/// Shared between Rust and compiled code; field offsets are baked into the prologue
+/// and the exit sequence.
+#[repr(C)]
+struct JitState {
+    stack_end: *mut u8,
+    memory: *mut u8,
+    memory_len: usize,
+    exit_offset: usize,
+    /// Non-zero when the instruction at `exit_offset` must be run by the interpreter.
+    interpret: usize,
+    stack_base: *const u8,
+    stack_limit: *const u8,
+}
+
+/// Machine code in an executable mapping.
+struct ExecutableMemory {
+    pointer: *mut c_void,
+    len: usize,
+}
+
+impl ExecutableMemory {
+    fn new(code: &[u8]) -> ExecutableMemory {
+        let len = code.len().max(1);
+        unsafe {
+            let pointer = mmap(
+                std::ptr::null_mut(),
+                len,
+                PROT_READ | PROT_WRITE,
+                MAP_PRIVATE | MAP_ANONYMOUS,
+                -1,
+                0,
+            );
+            if pointer as isize == -1 {
+                panic!("mmap of {} bytes for JIT code failed", len);
+            }
+            std::ptr::copy_nonoverlapping(code.as_ptr(), pointer as *mut u8, code.len());
+            if mprotect(pointer, len, PROT_READ | PROT_EXEC) != 0 {
+                panic!("mprotect of JIT code failed");
+            }
+            ExecutableMemory { pointer, len }
+        }
+    }
+}
+
+impl Drop for ExecutableMemory {
+    fn drop(&mut self) {
+        unsafe {
+            munmap(self.pointer, self.len);
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum Label {
+    /// Native code of the instruction at this bytecode offset.
+    Instruction(usize),
+    /// Leaves native code; execution continues at this offset.
+    Exit(usize),
+    /// Leaves native code; the interpreter runs the instruction at this offset.
+    Interpret(usize),
+}
+
+const RAX: u8 = 0;
+const RCX: u8 = 1;
+
+const JO: u8 = 0x80;
+const JB: u8 = 0x82;
+const JA: u8 = 0x87;
+const JNE: u8 = 0x85;
+
+fn is_signed(type_tag: u8) -> bool {
+    matches!(type_tag, I8 | I16 | I32 | I64)
+}
+
+fn is_float(type_tag: u8) -> bool {
+    matches!(type_tag, F32 | F64)
+}
+
+/// The `size` bytes at `start` in `code`, zero-extended.
+fn immediate(code: &[u8], start: usize, size: usize) -> u64 {
+    let mut value = [0u8; 8];
+    value[..size].copy_from_slice(&code[start..start + size]);
+    u64::from_le_bytes(value)
+}
+
+/// Emits x86-64 machine code and resolves jumps between labels.
+struct Emitter {
+    code: Vec<u8>,
+    labels: HashMap<Label, usize>,
+    fixups: Vec<(usize, Label)>,
+}
+
+impl Emitter {
+    fn bytes(&mut self, bytes: &[u8]) {
+        self.code.extend_from_slice(bytes);
+    }
+    fn bind(&mut self, label: Label) {
+        self.labels.insert(label, self.code.len());
+    }
+    fn rel32(&mut self, label: Label) {
+        self.fixups.push((self.code.len(), label));
+        self.bytes(&[0; 4]);
+    }
+    fn jmp(&mut self, label: Label) {
+        self.bytes(&[0xE9]);
+        self.rel32(label);
+    }
+    fn jcc(&mut self, condition: u8, label: Label) {
+        self.bytes(&[0x0F, condition]);
+        self.rel32(label);
+    }
+
+    /// `[r12 + displacement]` with `register` in the ModRM reg field.
+    fn stack_operand(&mut self, register: u8, displacement: i8) {
+        self.bytes(&[0x44 | (register << 3), 0x24, displacement as u8]);
+    }
+    /// `[r13 + rax]` with `register` in the ModRM reg field.
+    fn memory_operand(&mut self, register: u8) {
+        self.bytes(&[0x44 | (register << 3), 0x05, 0x00]);
+    }
+    fn mov_rax_imm64(&mut self, value: u64) {
+        self.bytes(&[0x48, 0xB8]);
+        self.bytes(&value.to_le_bytes());
+    }
+    fn mov_rcx_imm64(&mut self, value: u64) {
+        self.bytes(&[0x48, 0xB9]);
+        self.bytes(&value.to_le_bytes());
+    }
+    fn add_r12(&mut self, amount: usize) {
+        self.bytes(&[0x49, 0x83, 0xC4, amount as u8]);
+    }
+    fn sub_r12(&mut self, amount: usize) {
+        self.bytes(&[0x49, 0x83, 0xEC, amount as u8]);
+    }
+
+    /// Loads a value from the stack into `register`, sign- or zero-extended to 64 bits.
+    fn load_stack(&mut self, register: u8, type_tag: u8, displacement: i8) {
+        match (bytecode::type_size(type_tag), is_signed(type_tag)) {
+            (1, false) => self.bytes(&[0x41, 0x0F, 0xB6]),
+            (1, true) => self.bytes(&[0x49, 0x0F, 0xBE]),
+            (2, false) => self.bytes(&[0x41, 0x0F, 0xB7]),
+            (2, true) => self.bytes(&[0x49, 0x0F, 0xBF]),
+            (4, false) => self.bytes(&[0x41, 0x8B]),
+            (4, true) => self.bytes(&[0x49, 0x63]),
+            _ => self.bytes(&[0x49, 0x8B]),
+        }
+        self.stack_operand(register, displacement);
+    }
+    /// Stores the low bytes of `register` to the stack.
+    fn store_stack(&mut self, register: u8, size: usize, displacement: i8) {
+        match size {
+            1 => self.bytes(&[0x41, 0x88]),
+            2 => self.bytes(&[0x66, 0x41, 0x89]),
+            4 => self.bytes(&[0x41, 0x89]),
+            _ => self.bytes(&[0x49, 0x89]),
+        }
+        self.stack_operand(register, displacement);
+    }
+    fn load_memory_rcx(&mut self, size: usize) {
+        match size {
+            1 => self.bytes(&[0x41, 0x0F, 0xB6]),
+            2 => self.bytes(&[0x41, 0x0F, 0xB7]),
+            4 => self.bytes(&[0x41, 0x8B]),
+            _ => self.bytes(&[0x49, 0x8B]),
+        }
+        self.memory_operand(RCX);
+    }
+    fn store_memory_rcx(&mut self, size: usize) {
+        match size {
+            1 => self.bytes(&[0x41, 0x88]),
+            2 => self.bytes(&[0x66, 0x41, 0x89]),
+            4 => self.bytes(&[0x41, 0x89]),
+            _ => self.bytes(&[0x49, 0x89]),
+        }
+        self.memory_operand(RCX);
+    }
+    /// `movss`/`movsd` between `xmm<register>` and the stack.
+    fn float_stack(&mut self, type_tag: u8, store: bool, register: u8, displacement: i8) {
+        let prefix = if type_tag == F32 { 0xF3 } else { 0xF2 };
+        self.bytes(&[prefix, 0x41, 0x0F, if store { 0x11 } else { 0x10 }]);
+        self.stack_operand(register, displacement);
+    }
+    fn setcc_al(&mut self, condition: u8) {
+        self.bytes(&[0x0F, condition, 0xC0]);
+    }
+    fn setcc_cl(&mut self, condition: u8) {
+        self.bytes(&[0x0F, condition, 0xC1]);
+    }
+
+    /// `lea rax, [r12 + displacement]`
+    fn lea_rax_r12(&mut self, displacement: i32) {
+        self.bytes(&[0x49, 0x8D, 0x84, 0x24]);
+        self.bytes(&displacement.to_le_bytes());
+    }
+    /// Checks that the stack holds `popped` bytes and has room for `pushed`
+    /// more once they are popped.
+    fn stack_bounds(&mut self, popped: usize, pushed: usize, offset: usize) {
+        if popped > 0 {
+            self.lea_rax_r12(-(popped as i32));
+            self.bytes(&[0x49, 0x3B, 0x46, 0x28]); // cmp rax, [r14+40]
+            self.jcc(JB, Label::Interpret(offset));
+        }
+        if pushed > popped {
+            self.lea_rax_r12(pushed as i32 - popped as i32);
+            self.bytes(&[0x49, 0x3B, 0x46, 0x30]); // cmp rax, [r14+48]
+            self.jcc(JA, Label::Interpret(offset));
+        }
+    }
+
+    /// Checks that `size` bytes at `address` are inside the current memory.
+    fn memory_bounds(&mut self, address: usize, size: usize, offset: usize) -> bool {
+        let Some(end) = address.checked_add(size) else {
+            return false;
+        };
+        self.mov_rax_imm64(end as u64);
+        self.bytes(&[0x49, 0x39, 0xC7]); // cmp r15, rax
+        self.jcc(JB, Label::Interpret(offset));
+        self.mov_rax_imm64(address as u64);
+        true
+    }
+
+    /// Emits native code for `instruction`, or returns `false` if it has to run
+    /// in the interpreter.
+    fn instruction(&mut self, code: &[u8], instruction: &Decoded) -> bool {
+        let offset = instruction.offset;
+        let type_tag = instruction.type_tag.unwrap_or(BOOL);
+        let size = bytecode::type_size(type_tag);
+        let top = -(size as i8);
+        let second = -2 * size as i8;
+        match instruction.opcode {
+            op::Push => {
+                self.mov_rax_imm64(immediate(code, offset + 2, size));
+                self.store_stack(RAX, size, 0);
+                self.add_r12(size);
+            }
+            op::ClonePush => {
+                self.load_stack(RAX, type_tag, top);
+                self.store_stack(RAX, size, 0);
+                self.add_r12(size);
+            }
+            op::Add | op::Subtract | op::Multiply | op::Divide if is_float(type_tag) => {
+                let operation = match instruction.opcode {
+                    op::Add => 0x58,
+                    op::Subtract => 0x5C,
+                    op::Multiply => 0x59,
+                    _ => 0x5E,
+                };
+                let prefix = if type_tag == F32 { 0xF3 } else { 0xF2 };
+                self.float_stack(type_tag, false, 0, second);
+                self.float_stack(type_tag, false, 1, top);
+                self.bytes(&[prefix, 0x0F, operation, 0xC1]);
+                self.float_stack(type_tag, true, 0, second);
+                self.sub_r12(size);
+            }
+            op::Add | op::Subtract | op::Multiply => {
+                self.load_stack(RAX, type_tag, second);
+                self.load_stack(RCX, type_tag, top);
+                self.integer_operation(instruction.opcode, type_tag, offset);
+                self.store_stack(RAX, size, second);
+                self.sub_r12(size);
+            }
+            op::AddImmediate if is_float(type_tag) => {
+                let prefix = if type_tag == F32 { 0xF3 } else { 0xF2 };
+                self.float_stack(type_tag, false, 0, top);
+                self.mov_rax_imm64(immediate(code, offset + 2, size));
+                self.bytes(&[0x66, 0x48, 0x0F, 0x6E, 0xC8]); // movq xmm1, rax
+                self.bytes(&[prefix, 0x0F, 0x58, 0xC1]);
+                self.float_stack(type_tag, true, 0, top);
+            }
+            op::AddImmediate => {
+                self.load_stack(RAX, type_tag, top);
+                self.mov_rcx_imm64(immediate(code, offset + 2, size));
+                self.integer_operation(op::Add, type_tag, offset);
+                self.store_stack(RAX, size, top);
+            }
+            op::Increment if !is_float(type_tag) => {
+                let address = instruction.operand.unwrap_or_default();
+                if !self.memory_bounds(address, size, offset) {
+                    return false;
+                }
+                self.bytes(&[0x48, 0x89, 0xC2]); // mov rdx, rax
+                self.load_memory_rcx(size);
+                self.bytes(&[0x48, 0x89, 0xC8]); // mov rax, rcx
+                self.mov_rcx_imm64(immediate(code, offset + 10, size));
+                self.integer_operation(op::Add, type_tag, offset);
+                self.bytes(&[0x48, 0x89, 0xC1]); // mov rcx, rax
+                self.bytes(&[0x48, 0x89, 0xD0]); // mov rax, rdx
+                self.store_memory_rcx(size);
+            }
+            opcode if bytecode::is_conditional_goto(opcode) => {
+                self.compare_to_al(bytecode::goto_condition(instruction.opcode), type_tag);
+                self.sub_r12(2 * size);
+                self.bytes(&[0x84, 0xC0]); // test al, al
+                self.conditional_jump_to(code, instruction);
+            }
+            op::CompareEqual
+            | op::CompareNotEqual
+            | op::CompareGreater
+            | op::CompareGreaterEqual
+            | op::CompareLesser
+            | op::CompareLesserEqual => self.compare(instruction.opcode, type_tag),
+            op::Store | op::PeekStore | op::Load => {
+                let address = instruction.operand.unwrap_or_default();
+                if !self.memory_bounds(address, size, offset) {
+                    return false;
+                }
+                if instruction.opcode == op::Load {
+                    self.load_memory_rcx(size);
+                    self.store_stack(RCX, size, 0);
+                    self.add_r12(size);
+                } else {
+                    self.load_stack(RCX, type_tag, top);
+                    self.store_memory_rcx(size);
+                    if instruction.opcode == op::Store {
+                        self.sub_r12(size);
+                    }
+                }
+            }
+            op::Goto => self.jump_to(code, instruction),
+            op::PopGotoIfTrue => {
+                self.sub_r12(1);
+                self.bytes(&[0x41, 0x80, 0x3C, 0x24, 0x00]); // cmp byte [r12], 0
+                self.conditional_jump_to(code, instruction);
+            }
+            op::PeekGotoIfTrue => {
+                self.bytes(&[0x41, 0x80, 0x7C, 0x24, 0xFF, 0x00]); // cmp byte [r12-1], 0
+                self.conditional_jump_to(code, instruction);
+            }
+            op::LogicAnd | op::LogicOr => {
+                self.load_stack(RAX, BOOL, -2);
+                self.load_stack(RCX, BOOL, -1);
+                let operation = if instruction.opcode == op::LogicAnd {
+                    0x20
+                } else {
+                    0x08
+                };
+                self.bytes(&[operation, 0xC8]);
+                self.store_stack(RAX, 1, -2);
+                self.sub_r12(1);
+            }
+            op::LogicNot => self.bytes(&[0x41, 0x80, 0x74, 0x24, 0xFF, 0x01]), // xor byte [r12-1], 1
+            op::TypeCast => {
+                return self.cast(type_tag, instruction.second_type_tag.unwrap_or(BOOL));
+            }
+            _ => return false,
+        }
+        true
+    }
+
+    /// `rax = rax <opcode> rcx` at the width of `type_tag`, leaving for the
+    /// interpreter at `offset` if the result overflows.
+    fn integer_operation(&mut self, opcode: u8, type_tag: u8, offset: usize) {
+        let size = bytecode::type_size(type_tag);
+        let signed = is_signed(type_tag);
+        match (opcode, size, signed) {
+            (op::Multiply, 1, true) => self.bytes(&[0xF6, 0xE9]),
+            (op::Multiply, 1, false) => self.bytes(&[0xF6, 0xE1]),
+            (op::Multiply, _, true) => {
+                self.width_prefix(size);
+                self.bytes(&[0x0F, 0xAF, 0xC1]);
+            }
+            (op::Multiply, _, false) => {
+                self.width_prefix(size);
+                self.bytes(&[0xF7, 0xE1]);
+            }
+            (operation, _, _) => {
+                self.width_prefix(size);
+                let opcode = if operation == op::Add { 0x00 } else { 0x28 };
+                self.bytes(&[if size == 1 { opcode } else { opcode + 1 }, 0xC8]);
+            }
+        }
+        self.jcc(if signed { JO } else { JB }, Label::Interpret(offset));
+    }
+
+    /// Operand-size prefix for integer operations on `rax` and `rcx`.
+    fn width_prefix(&mut self, size: usize) {
+        match size {
+            2 => self.bytes(&[0x66]),
+            8 => self.bytes(&[0x48]),
+            _ => {}
+        }
+    }
+
+    fn jump_to(&mut self, code: &[u8], instruction: &Decoded) {
+        let target = instruction.operand.unwrap_or_default();
+        if target >= code.len() {
+            self.jmp(Label::Exit(target));
+        } else {
+            self.jmp(Label::Instruction(target));
+        }
+    }
+
+    fn conditional_jump_to(&mut self, code: &[u8], instruction: &Decoded) {
+        let target = instruction.operand.unwrap_or_default();
+        if target >= code.len() {
+            self.jcc(JNE, Label::Exit(target));
+        } else {
+            self.jcc(JNE, Label::Instruction(target));
+        }
+    }
+
+    fn compare(&mut self, opcode: u8, type_tag: u8) {
+        let size = bytecode::type_size(type_tag);
+        self.compare_to_al(opcode, type_tag);
+        self.store_stack(RAX, 1, -2 * size as i8);
+        self.sub_r12(2 * size - 1);
+    }
+
+    /// Compares the two values on top of the stack into `al`, without popping them.
+    fn compare_to_al(&mut self, opcode: u8, type_tag: u8) {
+        const SETE: u8 = 0x94;
+        const SETNE: u8 = 0x95;
+        const SETP: u8 = 0x9A;
+        const SETNP: u8 = 0x9B;
+        const SETA: u8 = 0x97;
+        const SETAE: u8 = 0x93;
+        const SETB: u8 = 0x92;
+        const SETBE: u8 = 0x96;
+        const SETG: u8 = 0x9F;
+        const SETGE: u8 = 0x9D;
+        const SETL: u8 = 0x9C;
+        const SETLE: u8 = 0x9E;
+
+        let size = bytecode::type_size(type_tag);
+        let top = -(size as i8);
+        let second = -2 * size as i8;
+        if is_float(type_tag) {
+            self.float_stack(type_tag, false, 0, second);
+            self.float_stack(type_tag, false, 1, top);
+            // Lesser comparisons swap the operands, so that an unordered result
+            // (NaN) reads as false like every other comparison with NaN.
+            let swapped = opcode >= op::CompareLesser;
+            if type_tag == F64 {
+                self.bytes(&[0x66]);
+            }
+            self.bytes(&[0x0F, 0x2E, if swapped { 0xC8 } else { 0xC1 }]);
+            match opcode {
+                op::CompareEqual => {
+                    self.setcc_al(SETE);
+                    self.setcc_cl(SETNP);
+                    self.bytes(&[0x20, 0xC8]);
+                }
+                op::CompareNotEqual => {
+                    self.setcc_al(SETNE);
+                    self.setcc_cl(SETP);
+                    self.bytes(&[0x08, 0xC8]);
+                }
+                op::CompareGreater | op::CompareLesser => self.setcc_al(SETA),
+                _ => self.setcc_al(SETAE),
+            }
+        } else {
+            self.load_stack(RAX, type_tag, second);
+            self.load_stack(RCX, type_tag, top);
+            self.bytes(&[0x48, 0x39, 0xC8]); // cmp rax, rcx
+            let signed = is_signed(type_tag);
+            self.setcc_al(match opcode {
+                op::CompareEqual => SETE,
+                op::CompareNotEqual => SETNE,
+                op::CompareGreater if signed => SETG,
+                op::CompareGreater => SETA,
+                op::CompareGreaterEqual if signed => SETGE,
+                op::CompareGreaterEqual => SETAE,
+                op::CompareLesser if signed => SETL,
+                op::CompareLesser => SETB,
+                _ if signed => SETLE,
+                _ => SETBE,
+            });
+        }
+    }
+
+    fn cast(&mut self, from: u8, to: u8) -> bool {
+        let from_size = bytecode::type_size(from);
+        let to_size = bytecode::type_size(to);
+        let top = -(from_size as i8);
+        match (is_float(from), is_float(to)) {
+            (false, false) => {
+                self.load_stack(RAX, from, top);
+                self.store_stack(RAX, to_size, top);
+            }
+            (false, true) => {
+                // cvtsi2ss/cvtsi2sd only read signed 64-bit integers.
+                if from == U64 {
+                    return false;
+                }
+                self.load_stack(RAX, from, top);
+                let prefix = if to == F32 { 0xF3 } else { 0xF2 };
+                self.bytes(&[prefix, 0x48, 0x0F, 0x2A, 0xC0]);
+                self.float_stack(to, true, 0, top);
+            }
+            (true, true) => {
+                self.float_stack(from, false, 0, top);
+                let prefix = if from == F32 { 0xF3 } else { 0xF2 };
+                self.bytes(&[prefix, 0x0F, 0x5A, 0xC0]);
+                self.float_stack(to, true, 0, top);
+            }
+            // `as` saturates and maps NaN to zero, cvttss2si does neither.
+            (true, false) => return false,
+        }
+        if to_size > from_size {
+            self.add_r12(to_size - from_size);
+        } else if from_size > to_size {
+            self.sub_r12(from_size - to_size);
+        }
+        true
+    }
+}
+
+/// Native code for a whole program.
+pub(crate) struct JitProgram {
+    memory: ExecutableMemory,
+    entries: HashMap<usize, usize>,
+    len: usize,
+}
+
+impl JitProgram {
+    pub(crate) fn new(code: &[u8]) -> JitProgram {
+        let mut emitter = Emitter {
+            code: Vec::new(),
+            labels: HashMap::new(),
+            fixups: Vec::new(),
+        };
+        // push rbx; push r12; push r13; push r14; push r15
+        emitter.bytes(&[0x53, 0x41, 0x54, 0x41, 0x55, 0x41, 0x56, 0x41, 0x57]);
+        emitter.bytes(&[0x49, 0x89, 0xFE]); // mov r14, rdi
+        emitter.bytes(&[0x4D, 0x8B, 0x66, 0x00]); // mov r12, [r14]
+        emitter.bytes(&[0x4D, 0x8B, 0x6E, 0x08]); // mov r13, [r14+8]
+        emitter.bytes(&[0x4D, 0x8B, 0x7E, 0x10]); // mov r15, [r14+16]
+        emitter.bytes(&[0xFF, 0xE6]); // jmp rsi
+
+        let mut entries = HashMap::new();
+        for instruction in bytecode::decode_all(code) {
+            emitter.bind(Label::Instruction(instruction.offset));
+            entries.insert(instruction.offset, emitter.code.len());
+            let start = emitter.code.len();
+            let fixups = emitter.fixups.len();
+            if let Some((popped, pushed)) = bytecode::stack_effect(&instruction) {
+                emitter.stack_bounds(popped, pushed, instruction.offset);
+            }
+            if !emitter.instruction(code, &instruction) {
+                emitter.code.truncate(start);
+                emitter.fixups.truncate(fixups);
+                emitter.jmp(Label::Interpret(instruction.offset));
+            }
+        }
+        emitter.jmp(Label::Exit(code.len()));
+
+        let mut exits: Vec<Label> = emitter
+            .fixups
+            .iter()
+            .map(|(_, label)| *label)
+            .filter(|label| !matches!(label, Label::Instruction(_)))
+            .collect();
+        exits.sort_by_key(|label| match label {
+            Label::Exit(offset) => (*offset, 0),
+            Label::Interpret(offset) => (*offset, 1),
+            Label::Instruction(offset) => (*offset, 2),
+        });
+        exits.dedup();
+        let common_exit = emitter.code.len() + exits.len() * 20;
+        for label in exits {
+            emitter.bind(label);
+            let (offset, interpret) = match label {
+                Label::Exit(offset) => (offset, 0u32),
+                Label::Interpret(offset) => (offset, 1),
+                Label::Instruction(_) => unreachable!(),
+            };
+            emitter.mov_rax_imm64(offset as u64);
+            emitter.bytes(&[0xB9]); // mov ecx, imm32
+            emitter.bytes(&interpret.to_le_bytes());
+            let next = emitter.code.len() + 5;
+            emitter.bytes(&[0xE9]);
+            emitter.bytes(&((common_exit as i64 - next as i64) as i32).to_le_bytes());
+        }
+        assert_eq!(emitter.code.len(), common_exit);
+        emitter.bytes(&[0x4D, 0x89, 0x66, 0x00]); // mov [r14], r12
+        emitter.bytes(&[0x49, 0x89, 0x46, 0x18]); // mov [r14+24], rax
+        emitter.bytes(&[0x49, 0x89, 0x4E, 0x20]); // mov [r14+32], rcx
+                                                  // pop r15; pop r14; pop r13; pop r12; pop rbx; ret
+        emitter.bytes(&[0x41, 0x5F, 0x41, 0x5E, 0x41, 0x5D, 0x41, 0x5C, 0x5B, 0xC3]);
+
+        for (position, label) in emitter.fixups.iter() {
+            let target = emitter.labels[label] as i64;
+            let relative = (target - (*position as i64 + 4)) as i32;
+            emitter.code[*position..*position + 4].copy_from_slice(&relative.to_le_bytes());
+        }
+        JitProgram {
+            memory: ExecutableMemory::new(&emitter.code),
+            entries,
+            len: code.len(),
+        }
+    }
+}
+
+impl StackUpperVector {
+    /// Same as `execute_all`, but running `program`'s native code wherever possible.
+    pub(crate) fn execute_jit(&mut self, program: &JitProgram) {
+        type Entry = unsafe extern "sysv64" fn(*mut JitState, *const u8);
+        let entry: Entry = unsafe { std::mem::transmute(program.memory.pointer) };
+        while self.cursor_offset() < program.len {
+            let start = program.entries[&self.cursor_offset()];
+            let mut state = JitState {
+                stack_end: self.lower_stack.end,
+                memory: self.buffer.buffer.as_mut_ptr(),
+                memory_len: self.buffer.buffer.len(),
+                exit_offset: 0,
+                interpret: 0,
+                stack_base: self.lower_stack.stack.as_ptr(),
+                stack_limit: self.lower_stack.stack.as_ptr_range().end,
+            };
+            unsafe {
+                entry(&mut state, (program.memory.pointer as *const u8).add(start));
+            }
+            self.lower_stack.end = state.stack_end;
+            self.goto(state.exit_offset);
+            if state.interpret != 0 {
+                self.do_Token();
+            }
+        }
+    }
+}
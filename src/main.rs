@@ -3,23 +3,156 @@ use num::{cast::AsPrimitive, traits::bounds::UpperBounded};
 use std::collections::{hash_map, HashMap};
 use std::fs::File;
 use std::io::{BufRead, BufReader};
+use std::path::{Path, PathBuf};
 use std::process::Output;
 use std::vec;
+use instructions::Token;
+mod assembler;
+mod bench;
+mod bytecode;
+mod expression;
+mod fuel;
+mod host;
+mod instructions;
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+mod jit;
+mod optimizer;
+mod peephole;
+mod predecode;
+mod profiler;
+#[cfg(test)]
+mod reference;
+mod register;
+mod snapshot;
+mod threaded;
+mod verifier;
+const DEFAULT_STACK_SIZE: usize = 10_000;
+const DEFAULT_MEMORY_SIZE: usize = 100_000;
+
+/// How `StackUpperVector::run` dispatches instructions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Engine {
+    /// `do_Token` straight over `token_byte_sequence`.
+    Interpreter,
+    /// One `match` over the `predecode::Kind` of each instruction.
+    Predecoded,
+    /// Indirect calls through a table of handler function pointers.
+    Threaded,
+    /// Virtual registers translated from the verified stack bytecode.
+    Register,
+    /// Native x86-64 code, falling back to `do_Token` for what it cannot compile.
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    Jit,
+}
+impl Engine {
+    #[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+    const ALL: [Engine; 4] = [
+        Engine::Interpreter,
+        Engine::Predecoded,
+        Engine::Threaded,
+        Engine::Register,
+    ];
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    const ALL: [Engine; 5] = [
+        Engine::Interpreter,
+        Engine::Predecoded,
+        Engine::Threaded,
+        Engine::Register,
+        Engine::Jit,
+    ];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Engine::Interpreter => "interpreter",
+            Engine::Predecoded => "predecoded",
+            Engine::Threaded => "threaded",
+            Engine::Register => "register",
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            Engine::Jit => "jit",
+        }
+    }
+    fn from_name(name: &str) -> Option<Engine> {
+        Engine::ALL.into_iter().find(|engine| engine.name() == name)
+    }
+}
+
+/// Sizes of the VM memories and the dispatch engine, fixed at construction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct VmConfig {
+    stack_size: usize,
+    memory_size: usize,
+    /// When larger than `memory_size`, `BufferArray` grows on demand up to this size.
+    max_memory_size: usize,
+    engine: Engine,
+}
+impl Default for VmConfig {
+    fn default() -> VmConfig {
+        VmConfig {
+            stack_size: DEFAULT_STACK_SIZE,
+            memory_size: DEFAULT_MEMORY_SIZE,
+            max_memory_size: DEFAULT_MEMORY_SIZE,
+            engine: Engine::Interpreter,
+        }
+    }
+}
 struct BufferArray {
-    buffer: [u8; 100_000],
+    buffer: Vec<u8>,
+    max_size: usize,
 }
 impl BufferArray {
-    fn new() -> BufferArray {
+    fn with_size(size: usize, max_size: usize) -> BufferArray {
         BufferArray {
-            buffer: [0; 100_000],
+            buffer: vec![0; size],
+            max_size: max_size.max(size),
         }
     }
+    fn check_bounds(&self, id: usize, size: usize) -> usize {
+        match id.checked_add(size) {
+            Some(end) if end <= self.max_size => end,
+            _ => panic!(
+                "Memory access at {} of {} bytes is out of bounds ({} bytes)",
+                id, size, self.max_size
+            ),
+        }
+    }
+    /// Doubles the buffer until `end` fits, without going over `max_size`.
+    fn grow(&mut self, end: usize) {
+        let mut new_size = self.buffer.len().max(1);
+        while new_size < end {
+            new_size *= 2;
+        }
+        self.buffer.resize(new_size.min(self.max_size), 0);
+    }
+    /// Copies `data` to the start of the buffer, as a program's directives lay it out.
+    fn initialize(&mut self, data: &[u8]) {
+        let end = self.check_bounds(0, data.len());
+        if end > self.buffer.len() {
+            self.grow(end);
+        }
+        self.buffer[..end].copy_from_slice(data);
+    }
+    /// Adds `amount` to the value stored at `id`, like `load`, `push`, `add`, `store`.
+    fn increment<T: std::ops::AddAssign>(&mut self, id: usize, amount: T) {
+        let mut value = self.load::<T>(id);
+        value += amount;
+        self.store::<T>(id, value);
+    }
 }
 impl Buffer for BufferArray {
     fn load<T>(&self, id: usize) -> T {
+        let end = self.check_bounds(id, std::mem::size_of::<T>());
+        if end > self.buffer.len() {
+            // Not grown yet, so this part of memory has never been written.
+            let zeroes = vec![0u8; std::mem::size_of::<T>()];
+            return unsafe { (zeroes.as_ptr() as *const T).read_unaligned() };
+        }
         unsafe { (self.buffer.as_ptr().add(id) as *const T).read_unaligned() }
     }
     fn store<T>(&mut self, id: usize, value: T) -> () {
+        let end = self.check_bounds(id, std::mem::size_of::<T>());
+        if end > self.buffer.len() {
+            self.grow(end);
+        }
         unsafe {
             (self.buffer.as_mut_ptr().add(id) as *mut T).write_unaligned(value);
         }
@@ -59,7 +192,7 @@ trait StackMachine {
     fn load<StoreType, T: Buffer>(&mut self, buffer: &T, id: usize) -> ();
 }
 struct StackArray {
-    stack: [u8; 10_000],
+    stack: Box<[u8]>,
     end: *mut u8,
 }
 macro_rules! cast_type_to_type_same_size {
@@ -75,9 +208,12 @@ macro_rules! cast_type_to_type_same_size {
 }
 impl StackArray {
     fn new() -> StackArray {
+        StackArray::with_size(DEFAULT_STACK_SIZE)
+    }
+    fn with_size(size: usize) -> StackArray {
         let mut stack = StackArray {
-            stack: [0u8; 10_000],
-            end: 0 as *mut u8,
+            stack: vec![0u8; size].into_boxed_slice(),
+            end: std::ptr::null_mut(),
         };
         stack.end = stack.stack.as_mut_ptr();
         stack
@@ -85,66 +221,85 @@ impl StackArray {
     fn init(&mut self) -> () {
         self.end = self.stack.as_mut_ptr();
     }
+    fn check_underflow<T>(&self) {
+        if self.depth() < std::mem::size_of::<T>() {
+            panic!("Stack underflow");
+        }
+    }
+    /// Adds `value` to the top of the stack, like `push` followed by `add`.
+    fn add_immediate<T: std::ops::AddAssign>(&mut self, value: T) {
+        let mut top = self.pop::<T>();
+        top += value;
+        self.push::<T>(top);
+    }
+    /// Pops two values and compares the second with the top one, like the
+    /// `compare_*` instructions.
+    fn pop_compare<T>(&mut self, condition: fn(&T, &T) -> bool) -> bool {
+        let value1 = self.pop::<T>();
+        let value2 = self.pop::<T>();
+        condition(&value2, &value1)
+    }
 }
 impl StackMachine for StackArray {
     fn push<T>(&mut self, value: T) -> () {
+        if self.stack.len() - self.depth() < std::mem::size_of::<T>() {
+            panic!("Stack overflow: the stack holds {} bytes", self.stack.len());
+        }
         unsafe {
             (self.end as *mut T).write_unaligned(value);
             self.end = self.end.add(std::mem::size_of::<T>());
         }
     }
     fn pop<T>(&mut self) -> T {
+        self.check_underflow::<T>();
         unsafe {
             self.end = self.end.sub(std::mem::size_of::<T>());
             (self.end as *const T).read_unaligned()
         }
     }
     fn peek<T>(&self) -> T {
+        self.check_underflow::<T>();
         unsafe { (self.end.sub(std::mem::size_of::<T>()) as *const T).read_unaligned() }
     }
 
     fn add<T: std::ops::AddAssign>(&mut self) -> () {
         let value = self.pop::<T>();
-        unsafe {
-            *(self.end.sub(std::mem::size_of::<T>()) as *mut T) += value;
-        }
+        let mut top = self.pop::<T>();
+        top += value;
+        self.push::<T>(top);
     }
     fn subtract<T: std::ops::SubAssign>(&mut self) -> () {
         let value = self.pop::<T>();
-        unsafe {
-            *(self.end.sub(std::mem::size_of::<T>()) as *mut T) -= value;
-        }
+        let mut top = self.pop::<T>();
+        top -= value;
+        self.push::<T>(top);
     }
     fn multiply<T: std::ops::MulAssign>(&mut self) -> () {
         let value = self.pop::<T>();
-        unsafe {
-            *(self.end.sub(std::mem::size_of::<T>()) as *mut T) *= value;
-        }
+        let mut top = self.pop::<T>();
+        top *= value;
+        self.push::<T>(top);
     }
     fn divide<T: std::ops::DivAssign>(&mut self) -> () {
         let value = self.pop::<T>();
-        unsafe {
-            *(self.end.sub(std::mem::size_of::<T>()) as *mut T) /= value;
-        }
+        let mut top = self.pop::<T>();
+        top /= value;
+        self.push::<T>(top);
     }
 
     fn logic_and(&mut self) -> () {
         let value = self.pop::<bool>();
-        unsafe {
-            *(self.end.sub(std::mem::size_of::<bool>()) as *mut bool) &= value;
-        }
+        let top = self.pop::<bool>();
+        self.push::<bool>(top & value);
     }
     fn logic_or(&mut self) -> () {
         let value = self.pop::<bool>();
-        unsafe {
-            *(self.end.sub(std::mem::size_of::<bool>()) as *mut bool) |= value;
-        }
+        let top = self.pop::<bool>();
+        self.push::<bool>(top | value);
     }
     fn logic_not(&mut self) -> () {
-        unsafe {
-            let ptr = self.end.sub(std::mem::size_of::<bool>()) as *mut bool;
-            *ptr = !(*ptr);
-        }
+        let value = self.pop::<bool>();
+        self.push::<bool>(!value);
     }
 
     fn compare_equal<T: std::cmp::PartialOrd>(&mut self) -> () {
@@ -182,14 +337,8 @@ impl StackMachine for StackArray {
     }
 
     fn cast_from_to<From: AsPrimitive<To>, To: 'static + Copy>(&mut self) -> () {
-        unsafe {
-            let pointer: *mut u8 = self.end.sub(std::mem::size_of::<From>());
-            let value: From = (pointer as *const From).read_unaligned();
-            (pointer as *mut To).write_unaligned(value.as_());
-            let OFFSET: isize =
-                std::mem::size_of::<To>() as isize - std::mem::size_of::<From>() as isize;
-            self.end = self.end.offset(OFFSET);
-        }
+        let value = self.pop::<From>();
+        self.push::<To>(value.as_());
     }
     fn store<StoreType, T: Buffer>(&mut self, buffer: &mut T, id: usize) -> () {
         buffer.store::<StoreType>(id, self.pop::<StoreType>());
@@ -202,194 +351,172 @@ impl StackMachine for StackArray {
     }
 }
 fn create_mapping() -> HashMap<String, u8> {
-    HashMap::from([
-        ("push".to_owned(), Token::Push as u8),
-        ("pop".to_owned(), Token::Pop as u8),
-        ("peek".to_owned(), Token::Peek as u8),
-        ("clone_push".to_owned(), Token::ClonePush as u8),
-        ("add".to_owned(), Token::Add as u8),
-        ("subtract".to_owned(), Token::Subtract as u8),
-        ("multiply".to_owned(), Token::Multiply as u8),
-        ("divide".to_owned(), Token::Divide as u8),
-        ("store".to_owned(), Token::Store as u8),
-        ("peek_store".to_owned(), Token::PeekStore as u8),
-        ("load".to_owned(), Token::Load as u8),
-        ("goto".to_owned(), Token::Goto as u8),
-        ("pop_goto_if_true".to_owned(), Token::PopGotoIfTrue as u8),
-        ("peek_goto_if_true".to_owned(), Token::PeekGotoIfTrue as u8),
-        ("logic_and".to_owned(), Token::LogicAnd as u8),
-        ("logic_or".to_owned(), Token::LogicOr as u8),
-        ("logic_not".to_owned(), Token::LogicNot as u8),
-        ("compare_equal".to_owned(), Token::CompareEqual as u8),
-        ("compare_not_equal".to_owned(), Token::CompareNotEqual as u8),
-        ("compare_greater".to_owned(), Token::CompareGreater as u8),
-        (
-            "compare_greater_equal".to_owned(),
-            Token::CompareGreaterEqual as u8,
-        ),
-        ("compare_lesser".to_owned(), Token::CompareLesser as u8),
-        (
-            "compare_lesser_equal".to_owned(),
-            Token::CompareLesserEqual as u8,
-        ),
-        ("type_cast".to_owned(), Token::TypeCast as u8),
-        ("bool".to_owned(), Token::Bool as u8),
-        ("i8".to_owned(), Token::I8 as u8),
-        ("i16".to_owned(), Token::I16 as u8),
-        ("i32".to_owned(), Token::I32 as u8),
-        ("i64".to_owned(), Token::I64 as u8),
-        ("u8".to_owned(), Token::U8 as u8),
-        ("u16".to_owned(), Token::U16 as u8),
-        ("u32".to_owned(), Token::U32 as u8),
-        ("u64".to_owned(), Token::U64 as u8),
-        ("f32".to_owned(), Token::F32 as u8),
-        ("f64".to_owned(), Token::F64 as u8),
-    ])
+    let instructions = instructions::INSTRUCTIONS
+        .iter()
+        .map(|instruction| (instruction.mnemonic, instruction.opcode));
+    let types = instructions::TYPES
+        .iter()
+        .map(|type_tag| (type_tag.mnemonic, type_tag.tag));
+    instructions
+        .chain(types)
+        .map(|(mnemonic, opcode)| (mnemonic.to_owned(), opcode))
+        .collect()
 }
-fn try_parse_value(number_token: u8, string_val: &str) -> (i32, [u8; 8]) {
-    let mut arr = [0u8; 8];
-    const Bool: u8 = Token::Bool as u8;
-    const I8: u8 = Token::I8 as u8;
-    const I16: u8 = Token::I16 as u8;
-    const I32: u8 = Token::I32 as u8;
-    const I64: u8 = Token::I64 as u8;
-    const U8: u8 = Token::U8 as u8;
-    const U16: u8 = Token::U16 as u8;
-    const U32: u8 = Token::U32 as u8;
-    const U64: u8 = Token::U64 as u8;
-    const F32: u8 = Token::F32 as u8;
-    const F64: u8 = Token::F64 as u8;
-
-    match number_token {
-        Bool => {
-            if string_val == "true" {
-                arr[0] = 1;
-                return (1, arr);
-            }
-            else if string_val == "false"{
-                arr[0] = 0;
-                return (1, arr);
-            }
-            else{
-                panic!("Unexpected string! {}", string_val);
-            }
-        },
-        I8 => {
-            let val = string_val.parse::<i8>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut i8).write_unaligned(val);
-            }
-            return (std::mem::size_of::<i8>() as i32, arr);
-        },
-        I16 => {
-            let val = string_val.parse::<i16>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut i16).write_unaligned(val);
-            }
-            return (std::mem::size_of::<i16>() as i32, arr);
-        },
-        I32 => {
-            let val = string_val.parse::<i32>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut i32).write_unaligned(val);
-            }
-            return (std::mem::size_of::<i32>() as i32, arr);
-        },
-        I64 => {
-            let val = string_val.parse::<i64>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut i64).write_unaligned(val);
-            }
-            return (std::mem::size_of::<i64>() as i32, arr);
-        },
-        U8 => {
-            let val = string_val.parse::<u8>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut u8).write_unaligned(val);
-            }
-            return (std::mem::size_of::<u8>() as i32, arr);
-        },
-        U16 => {
-            let val = string_val.parse::<u16>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut u16).write_unaligned(val);
-            }
-            return (std::mem::size_of::<u16>() as i32, arr);
-        },
-        U32 => {
-            let val = string_val.parse::<u32>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut u32).write_unaligned(val);
-            }
-            return (std::mem::size_of::<u32>() as i32, arr);
-        },
-        U64 => {
-            let val = string_val.parse::<u64>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut u64).write_unaligned(val);
-            }
-            return (std::mem::size_of::<u64>() as i32, arr);
-        },
-        F32 => {
-            let val = string_val.parse::<f32>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut f32).write_unaligned(val);
-            }
-            return (std::mem::size_of::<f32>() as i32, arr);
-        },
-        F64 => {
-            let val = string_val.parse::<f64>().expect(&format!("The parse failed! {}", string_val));
-            unsafe {
-                (arr.as_mut_ptr() as *mut f64).write_unaligned(val);
-            }
-            return (std::mem::size_of::<f64>() as i32, arr);
-        },
-        _ => {panic!("The wrong token passed! {}", number_token)},
-    }
-}
-fn parse_to_vector(path: &str) -> Vec<u8> {
-    let mut prev_token: u8 = 0;
-    let mut output = Vec::<u8>::new();
-    let hash_map = create_mapping();
+fn parse_to_vector(
+    path: &str,
+    search_paths: &[PathBuf],
+) -> Result<assembler::Program, assembler::AsmError> {
     let reader = BufReader::new(File::open(path).expect("Cannot open file.txt"));
-    for line in reader.lines() {
-        for word in line.unwrap().split_whitespace() {
-            let option = hash_map.get(word);
-            if (option.is_none()) {
-                if (prev_token >= 24 && prev_token <= 34) {
-                    let value = try_parse_value(prev_token, word);
-                    for i in 0..value.0{
-                        output.push(value.1[i as usize]);
-                    }
-                    continue;
-                }
-                else if(prev_token >= 11 && prev_token <= 13){
-                    let mut arr = [0u8; 8];
-                    unsafe{
-                        (arr.as_mut_ptr() as *mut usize).write_unaligned(word.parse::<usize>().expect(&format!("Unexpected token {}", word)));
-                    }
-                    for i in 0..8{
-                        output.push(arr[i as usize]);
-                    }
-                    continue;
+    let lines: Vec<String> = reader.lines().map(|line| line.unwrap()).collect();
+    assembler::assemble_file(Path::new(path), &lines.join("\n"), search_paths)
+}
+/// The bytecode of `source`, which must assemble. Memory initialized by its
+/// directives is dropped.
+fn parse_source(source: &str) -> Vec<u8> {
+    assembler::assemble(source)
+        .unwrap_or_else(|error| panic!("{}", error))
+        .code
+}
+fn main() {
+    let mut path = "./data/file2.txt".to_owned();
+    let mut profile = false;
+    let mut trace = false;
+    let mut fuel: Option<fuel::Fuel> = None;
+    let mut costs = Vec::<(u8, u64)>::new();
+    let mut snapshot_path: Option<String> = None;
+    let mut resume_path: Option<String> = None;
+    let mut config = VmConfig::default();
+    let mut optimizations: Option<peephole::PeepholeOptions> = None;
+    let mut search_paths = Vec::new();
+    let mut listing_path: Option<String> = None;
+    let mut args = std::env::args().skip(1);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--profile" => profile = true,
+            "--trace" => trace = true,
+            "--bench" => {
+                bench::run_benchmarks();
+                return;
+            }
+            "--opcodes" => {
+                print!("{}", instructions::reference());
+                return;
+            }
+            "--fuel" => {
+                let amount = args.next().expect("--fuel needs an amount");
+                fuel = Some(fuel::Fuel::new(
+                    amount
+                        .parse()
+                        .unwrap_or_else(|_| panic!("Bad fuel amount {}", amount)),
+                ));
+            }
+            "--cost" => {
+                let setting = args.next().expect("--cost needs opcode=cost");
+                let (name, cost) = setting
+                    .split_once('=')
+                    .unwrap_or_else(|| panic!("Bad cost setting {}", setting));
+                let opcode = *create_mapping()
+                    .get(name)
+                    .unwrap_or_else(|| panic!("Unknown opcode {}", name));
+                let cost = cost
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Bad cost {}", cost));
+                costs.push((opcode, cost));
+            }
+            "--snapshot" => {
+                snapshot_path = Some(args.next().expect("--snapshot needs a file"));
+            }
+            "--resume" => {
+                resume_path = Some(args.next().expect("--resume needs a file"));
+            }
+            "--optimize" => optimizations = Some(peephole::PeepholeOptions::default()),
+            "--no-optimize" => {
+                let name = args.next().expect("--no-optimize needs an optimization");
+                if !optimizations
+                    .get_or_insert_with(peephole::PeepholeOptions::default)
+                    .set(&name, false)
+                {
+                    panic!(
+                        "Unknown optimization {}, expected one of {}",
+                        name,
+                        peephole::PeepholeOptions::NAMES.join(", ")
+                    );
                 }
-                else{
-                    panic!("Unexpected token: {}", word);
-
+            }
+            "--listing" => {
+                listing_path = Some(args.next().expect("--listing needs a file"));
+            }
+            "--include-path" => {
+                let directory = args.next().expect("--include-path needs a directory");
+                search_paths.push(PathBuf::from(directory));
+            }
+            "--engine" => {
+                let name = args.next().expect("--engine needs a name");
+                config.engine = Engine::from_name(&name)
+                    .unwrap_or_else(|| panic!("Unknown engine {}", name));
+            }
+            "--stack-size" | "--memory-size" | "--max-memory-size" => {
+                let size = args.next().unwrap_or_else(|| panic!("{} needs a size", arg));
+                let size = size
+                    .parse()
+                    .unwrap_or_else(|_| panic!("Bad size {}", size));
+                match arg.as_str() {
+                    "--stack-size" => config.stack_size = size,
+                    "--memory-size" => config.memory_size = size,
+                    _ => config.max_memory_size = size,
                 }
             }
-            let result = option.unwrap();
-
-            output.push(*result);
-            prev_token = *result;
+            _ => path = arg,
         }
     }
-    return output;
-}
-fn main() {
-    let mut stack = StackUpperVector::new();
 
-    stack.token_byte_sequence = parse_to_vector("./data/file2.txt");
+    if fuel.is_some() && config.engine != Engine::Interpreter {
+        panic!("--fuel only runs on the interpreter, not {}", config.engine.name());
+    }
+    let mut stack = StackUpperVector::with_config(config);
+    host::register_builtins(&mut stack.host);
+
+    // Empty for a resumed snapshot, which has no source.
+    let mut line_table = assembler::LineTable::default();
+    if let Some(resume_path) = &resume_path {
+        let bytes = std::fs::read(resume_path)
+            .unwrap_or_else(|error| panic!("Cannot read {}: {}", resume_path, error));
+        if let Err(error) = stack.restore(&bytes) {
+            panic!("Cannot resume from {}: {}", resume_path, error);
+        }
+    } else {
+        let program = parse_to_vector(&path, &search_paths)
+            .and_then(|program| {
+                program.check_memory(stack.buffer.max_size)?;
+                Ok(program)
+            })
+            .unwrap_or_else(|error| {
+                eprintln!("{}", error);
+                std::process::exit(1);
+            });
+        if let Some(listing_path) = &listing_path {
+            std::fs::write(listing_path, program.listing())
+                .unwrap_or_else(|error| panic!("Cannot write {}: {}", listing_path, error));
+        }
+        let mut code = program.code;
+        line_table = program.line_table;
+        if let Some(optimizations) = &optimizations {
+            let ops = optimizer::optimize_ops(bytecode::lift(&code), optimizations);
+            line_table = line_table.remap(&bytecode::origins(&ops));
+            code = bytecode::layout(&ops);
+            let ops = peephole::optimize_ops(bytecode::lift(&code), optimizations);
+            line_table = line_table.remap(&bytecode::origins(&ops));
+            code = bytecode::layout(&ops);
+        }
+        if let Err(error) = stack.load_program(code) {
+            match line_table.lookup(error.offset) {
+                Some(position) => eprintln!("{}: {}", position, error.message),
+                None => eprintln!("{}: {}", path, error),
+            }
+            std::process::exit(1);
+        }
+        stack.buffer.initialize(&program.data);
+    }
     /*stack.token_byte_sequence = vec![
         Token::Push as u8,
         Token::Bool as u8,
@@ -503,135 +630,106 @@ fn main() {
         println!("");
 
     }*/
-    stack.init();
-    stack.execute_all();
-    //tests();
+    // The panic hook still reports where in the VM a trap happened, which is what
+    // tells a trap apart from a bug in the VM; the last line says where in the program.
+    let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+        execute(&mut stack, profile, trace, fuel, costs, &line_table, snapshot_path.as_deref())
+    }));
+    if let Err(payload) = result {
+        let message = payload
+            .downcast_ref::<&str>()
+            .map(|message| message.to_string())
+            .or_else(|| payload.downcast_ref::<String>().cloned())
+            .unwrap_or_default();
+        // Otherwise the cursor is past the opcode of the instruction that trapped.
+        let offset = stack
+            .trap_offset
+            .unwrap_or_else(|| stack.cursor_offset().saturating_sub(1));
+        match line_table.lookup(offset) {
+            Some(position) => eprintln!("{}: {}", position, message),
+            None => eprintln!("{}: {}", path, message),
+        }
+        std::process::exit(101);
+    }
+}
+
+/// Runs the loaded program the way the command line asks for.
+fn execute(
+    stack: &mut StackUpperVector,
+    profile: bool,
+    trace: bool,
+    fuel: Option<fuel::Fuel>,
+    costs: Vec<(u8, u64)>,
+    line_table: &assembler::LineTable,
+    snapshot_path: Option<&str>,
+) {
+    if profile {
+        let mut profiler = profiler::Profiler::new(&stack.token_byte_sequence);
+        profiler.line_table = line_table.clone();
+        stack.execute_profiled(&mut profiler);
+        eprint!("{}", profiler.report());
+    } else if trace {
+        stack.execute_traced(line_table);
+    } else if let Some(mut fuel) = fuel {
+        for (opcode, cost) in costs {
+            fuel.set_cost(opcode, cost);
+        }
+        if stack.execute_with_fuel(&mut fuel) == fuel::ExecutionOutcome::OutOfFuel {
+            let offset = stack.cursor_offset();
+            match line_table.lookup(offset) {
+                Some(position) => eprintln!("Out of fuel at offset {} ({})", offset, position),
+                None => eprintln!("Out of fuel at offset {}", offset),
+            }
+            if let Some(snapshot_path) = snapshot_path {
+                std::fs::write(snapshot_path, stack.snapshot())
+                    .unwrap_or_else(|error| panic!("Cannot write {}: {}", snapshot_path, error));
+                eprintln!("Snapshot written to {}", snapshot_path);
+            }
+            std::process::exit(2);
+        }
+    } else {
+        stack.run();
+    }
 }
 struct StackUpperVector {
     lower_stack: StackArray,
     buffer: BufferArray,
     token_byte_sequence: Vec<u8>,
     cursor: *mut u8,
+    host: host::HostRegistry,
+    /// Output of `pop`, `peek` and host functions; written to stdout when `None`.
+    output: Option<Vec<u8>>,
+    engine: Engine,
+    /// Offset of the instruction that trapped, set by the engines that do not
+    /// keep the cursor on the instruction they run.
+    trap_offset: Option<usize>,
 }
-macro_rules! match_all_types {
-    ($operation: ident, $self: expr) => {
-        let _type = $self.get::<u8>();
-        match _type {
-            Bool => {
-                $self.$operation::<bool>();
-            }
-            I8 => {
-                $self.$operation::<i8>();
-            }
-            I16 => {
-                $self.$operation::<i16>();
-            }
-            I32 => {
-                $self.$operation::<i32>();
-            }
-            I64 => {
-                $self.$operation::<i64>();
-            }
-            U8 => {
-                $self.$operation::<u8>();
-            }
-            U16 => {
-                $self.$operation::<u16>();
-            }
-            U32 => {
-                $self.$operation::<u32>();
-            }
-            U64 => {
-                $self.$operation::<u64>();
-            }
-            F32 => {
-                $self.$operation::<f32>();
-            }
-            F64 => {
-                $self.$operation::<f64>();
-            }
-            _ => {
-                panic!("Unknown type")
-            }
-        }
-    };
-}
-macro_rules! match_all_numeric_types {
-    ($operation: ident, $self: expr) => {
-        let _type = $self.get::<u8>();
-        match _type {
-            I8 => {
-                $self.$operation::<i8>();
-            }
-            I16 => {
-                $self.$operation::<i16>();
-            }
-            I32 => {
-                $self.$operation::<i32>();
-            }
-            I64 => {
-                $self.$operation::<i64>();
-            }
-            U8 => {
-                $self.$operation::<u8>();
-            }
-            U16 => {
-                $self.$operation::<u16>();
-            }
-            U32 => {
-                $self.$operation::<u32>();
-            }
-            U64 => {
-                $self.$operation::<u64>();
-            }
-            F32 => {
-                $self.$operation::<f32>();
-            }
-            F64 => {
-                $self.$operation::<f64>();
-            }
-            _ => {
-                panic!("Unknown type")
-            }
-        }
-    };
-}
-macro_rules! cast_from_to_types {
-    ($case: ident, $type: ty, $match_type: expr) => {
-        $case => {
-            match $match_type {
-                I8 => self.lower_stack.cast_from_to::<$type, i8>(),
-                I16 => self.lower_stack.cast_from_to::<$type, i16>(),
-                I32 => self.lower_stack.cast_from_to::<$type, i32>(),
-                I64 => self.lower_stack.cast_from_to::<$type, i64>(),
-
-                U8 =>  self.lower_stack.cast_from_to::<$type, u8>(),
-                U16 => self.lower_stack.cast_from_to::<$type, u16>(),
-                U32 => self.lower_stack.cast_from_to::<$type, u32>(),
-                U64 => self.lower_stack.cast_from_to::<$type, u64>(),
-
-                F32 => self.lower_stack.cast_from_to::<$type, f32>(),
-                F64 => self.lower_stack.cast_from_to::<$type, f64>(),
-
-                _ => panic!("Invalid type cast!"),
-            }
-        }
-    };
-}
-
 impl StackUpperVector {
     fn new() -> StackUpperVector {
+        StackUpperVector::with_config(VmConfig::default())
+    }
+    fn with_config(config: VmConfig) -> StackUpperVector {
         StackUpperVector {
-            lower_stack: StackArray::new(),
-            buffer: BufferArray::new(),
+            lower_stack: StackArray::with_size(config.stack_size),
+            buffer: BufferArray::with_size(config.memory_size, config.max_memory_size),
             token_byte_sequence: Vec::new(),
             cursor: 0 as *mut u8,
+            host: host::HostRegistry::new(),
+            output: None,
+            engine: config.engine,
+            trap_offset: None,
         }
     }
     fn init(&mut self) -> () {
         self.lower_stack.init();
         self.cursor = self.token_byte_sequence.as_mut_ptr();
     }
+    fn cursor_offset(&self) -> usize {
+        unsafe {
+            self.cursor
+                .offset_from(self.token_byte_sequence.as_ptr()) as usize
+        }
+    }
     fn goto(&mut self, cursor_bytes_id: usize) -> () {
         unsafe {
             self.cursor = self.token_byte_sequence.as_mut_ptr().add(cursor_bytes_id);
@@ -644,15 +742,26 @@ impl StackUpperVector {
             value
         }
     }
-    fn push<T>(&mut self) -> () {
-        let value = self.get::<T>();
+    fn push<T>(&mut self, value: T) -> () {
         self.lower_stack.push::<T>(value);
     }
     fn pop<T: std::fmt::Display>(&mut self) -> () {
-        println!("{:.3}", self.lower_stack.pop::<T>());
+        let value = self.lower_stack.pop::<T>();
+        self.print(format_args!("{:.3}", value));
     }
     fn peek<T: std::fmt::Display>(&mut self) -> () {
-        println!("{:.3}", self.lower_stack.peek::<T>());
+        let value = self.lower_stack.peek::<T>();
+        self.print(format_args!("{:.3}", value));
+    }
+    /// Writes one line of program output, to stdout unless it is being captured.
+    fn print(&mut self, line: std::fmt::Arguments) {
+        match &mut self.output {
+            Some(captured) => {
+                use std::io::Write;
+                writeln!(captured, "{}", line).unwrap();
+            }
+            None => println!("{}", line),
+        }
     }
     fn add<T: std::ops::AddAssign>(&mut self) -> () {
         self.lower_stack.add::<T>();
@@ -685,18 +794,15 @@ impl StackUpperVector {
     fn compare_lesser_equal<T: std::cmp::PartialOrd>(&mut self) -> () {
         self.lower_stack.compare_lesser_equal::<T>();
     }
-    fn store<T>(&mut self) -> () {
-        let id = self.get::<usize>();
+    fn store<T>(&mut self, id: usize) -> () {
         self.lower_stack
             .store::<T, BufferArray>(&mut self.buffer, id);
     }
-    fn peek_store<T>(&mut self) -> () {
-        let id = self.get::<usize>();
+    fn peek_store<T>(&mut self, id: usize) -> () {
         self.lower_stack
             .peek_store::<T, BufferArray>(&mut self.buffer, id);
     }
-    fn load<T>(&mut self) -> () {
-        let id = self.get::<usize>();
+    fn load<T>(&mut self, id: usize) -> () {
         self.lower_stack
             .load::<T, BufferArray>(&mut self.buffer, id);
     }
@@ -704,506 +810,1686 @@ impl StackUpperVector {
         let value = self.lower_stack.peek::<T>();
         self.lower_stack.push::<T>(value);
     }
+    fn add_immediate<T: std::ops::AddAssign>(&mut self, value: T) {
+        self.lower_stack.add_immediate::<T>(value);
+    }
+    fn pop_goto_if_true(&mut self, cursor_bytes_id: usize) {
+        if self.lower_stack.pop::<bool>() {
+            self.goto(cursor_bytes_id);
+        }
+    }
+    fn peek_goto_if_true(&mut self, cursor_bytes_id: usize) {
+        if self.lower_stack.peek::<bool>() {
+            self.goto(cursor_bytes_id);
+        }
+    }
+    fn logic_and(&mut self) {
+        self.lower_stack.logic_and();
+    }
+    fn logic_or(&mut self) {
+        self.lower_stack.logic_or();
+    }
+    fn logic_not(&mut self) {
+        self.lower_stack.logic_not();
+    }
+    fn type_cast<From: AsPrimitive<To>, To: Copy + 'static>(&mut self) {
+        self.lower_stack.cast_from_to::<From, To>();
+    }
+    /// Pops two values and jumps to `cursor_bytes_id` if `condition(second, top)` holds.
+    fn goto_if<T>(&mut self, cursor_bytes_id: usize, condition: fn(&T, &T) -> bool) {
+        if self.lower_stack.pop_compare::<T>(condition) {
+            self.goto(cursor_bytes_id);
+        }
+    }
+    fn goto_if_equal<T: std::cmp::PartialOrd>(&mut self, cursor_bytes_id: usize) {
+        self.goto_if::<T>(cursor_bytes_id, T::eq);
+    }
+    fn goto_if_not_equal<T: std::cmp::PartialOrd>(&mut self, cursor_bytes_id: usize) {
+        self.goto_if::<T>(cursor_bytes_id, T::ne);
+    }
+    fn goto_if_greater<T: std::cmp::PartialOrd>(&mut self, cursor_bytes_id: usize) {
+        self.goto_if::<T>(cursor_bytes_id, T::gt);
+    }
+    fn goto_if_greater_equal<T: std::cmp::PartialOrd>(&mut self, cursor_bytes_id: usize) {
+        self.goto_if::<T>(cursor_bytes_id, T::ge);
+    }
+    fn goto_if_lesser<T: std::cmp::PartialOrd>(&mut self, cursor_bytes_id: usize) {
+        self.goto_if::<T>(cursor_bytes_id, T::lt);
+    }
+    fn goto_if_lesser_equal<T: std::cmp::PartialOrd>(&mut self, cursor_bytes_id: usize) {
+        self.goto_if::<T>(cursor_bytes_id, T::le);
+    }
+    fn increment<T: std::ops::AddAssign>(&mut self, id: usize, amount: T) {
+        self.buffer.increment::<T>(id, amount);
+    }
     fn do_Token(&mut self) -> () {
         let Token = self.get::<u8>();
-        const Push: u8 = Token::Push as u8;
-        const Pop: u8 = Token::Pop as u8;
-        const Peek: u8 = Token::Peek as u8;
-        const ClonePush: u8 = Token::ClonePush as u8;
-        const Add: u8 = Token::Add as u8;
-        const Subtract: u8 = Token::Subtract as u8;
-        const Multiply: u8 = Token::Multiply as u8;
-        const Divide: u8 = Token::Divide as u8;
-        const Store: u8 = Token::Store as u8;
-        const PeekStore: u8 = Token::PeekStore as u8;
-        const Load: u8 = Token::Load as u8;
-        const Goto: u8 = Token::Goto as u8;
-        const PopGotoIfTrue: u8 = Token::PopGotoIfTrue as u8;
-        const PeekGotoIfTrue: u8 = Token::PeekGotoIfTrue as u8;
-        const LogicAnd: u8 = Token::LogicAnd as u8;
-        const LogicOr: u8 = Token::LogicOr as u8;
-        const LogicNot: u8 = Token::LogicNot as u8;
-        const TypeCast: u8 = Token::TypeCast as u8;
-        const CompareEqual: u8 = Token::CompareEqual as u8;
-        const CompareNotEqual: u8 = Token::CompareNotEqual as u8;
-        const CompareGreater: u8 = Token::CompareGreater as u8;
-        const CompareGreaterEqual: u8 = Token::CompareGreaterEqual as u8;
-        const CompareLesser: u8 = Token::CompareLesser as u8;
-        const CompareLesserEqual: u8 = Token::CompareLesserEqual as u8;
-
-        const Bool: u8 = Token::Bool as u8;
-        const I8: u8 = Token::I8 as u8;
-        const I16: u8 = Token::I16 as u8;
-        const I32: u8 = Token::I32 as u8;
-        const I64: u8 = Token::I64 as u8;
-        const U8: u8 = Token::U8 as u8;
-        const U16: u8 = Token::U16 as u8;
-        const U32: u8 = Token::U32 as u8;
-        const U64: u8 = Token::U64 as u8;
-        const F32: u8 = Token::F32 as u8;
-        const F64: u8 = Token::F64 as u8;
-
-        match Token {
-            Push => {
-                match_all_types!(push, self);
-            }
-            Pop => {
-                match_all_types!(pop, self);
-            }
-            Peek => {
-                match_all_types!(peek, self);
-            }
-            ClonePush => {
-                match_all_types!(clone_push, self);
-            }
-            Add => {
-                match_all_numeric_types!(add, self);
-            }
-            Subtract => {
-                match_all_numeric_types!(subtract, self);
-            }
-            Multiply => {
-                match_all_numeric_types!(multiply, self);
+        /// Reads the operands that follow the type tags and calls the method
+        /// named after the instruction with them.
+        macro_rules! interpret {
+            ($mnemonic:ident [$($T:ty),*] []) => {
+                self.$mnemonic::<$($T),*>()
+            };
+            ($mnemonic:ident [$T:ty] [Value]) => {{
+                let value = self.get::<$T>();
+                self.$mnemonic::<$T>(value)
+            }};
+            ($mnemonic:ident [$T:ty] [Address, Value]) => {{
+                let id = self.get::<usize>();
+                let value = self.get::<$T>();
+                self.$mnemonic::<$T>(id, value)
+            }};
+            ($mnemonic:ident [] [HostFunction]) => {{
+                let id = self.get::<u32>();
+                self.$mnemonic(id)
+            }};
+            // An address or a jump target.
+            ($mnemonic:ident [$($T:ty),*] [$operand:ident]) => {{
+                let cursor_bytes_id = self.get::<usize>();
+                self.$mnemonic::<$($T),*>(cursor_bytes_id)
+            }};
+        }
+        instructions::dispatch!(Token, self.get::<u8>(), self.get::<u8>(), interpret)
+    }
+    /// Runs the program to the end on the engine chosen at construction.
+    fn run(&mut self) {
+        match self.engine {
+            Engine::Interpreter => self.execute_all(),
+            Engine::Predecoded => {
+                let program = predecode::PredecodedProgram::new(&self.token_byte_sequence);
+                self.execute_predecoded(&program);
+            }
+            Engine::Threaded => {
+                let program = threaded::ThreadedProgram::new(&self.token_byte_sequence);
+                self.execute_threaded(&program);
+            }
+            Engine::Register => {
+                let start = self.cursor_offset();
+                let stack_size = self.lower_stack.stack.len();
+                let code = &self.token_byte_sequence;
+                match register::RegisterProgram::new(code, &self.host, start, stack_size) {
+                    Ok(program) => self.execute_register(&program),
+                    // The interpreter reports what is wrong with the program when it gets there.
+                    Err(_) => self.execute_all(),
+                }
             }
-            Divide => {
-                match_all_numeric_types!(divide, self);
+            #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+            Engine::Jit => {
+                let program = jit::JitProgram::new(&self.token_byte_sequence);
+                self.execute_jit(&program);
             }
+        }
+    }
+    fn execute_all(&mut self) -> () {
+        let size = self.token_byte_sequence.len();
+        unsafe {
+            let end_ptr = self.token_byte_sequence.as_mut_ptr().add(size);
 
-            Store => {
-                match_all_types!(store, self);
-            }
-            PeekStore => {
-                match_all_types!(peek_store, self);
-            }
-            Load => {
-                match_all_types!(load, self);
+            while end_ptr.offset_from(self.cursor) > 0 {
+                self.do_Token();
             }
+        }
+    }
+}
+trait StackMachineUpper: StackMachine {
+    fn goto(&mut self, row_id: usize) -> ();
+    fn goto_if_pop_true(&mut self, row_id: usize) -> ();
+    fn goto_if_peek_true(&mut self, row_id: usize) -> ();
+}
 
-            Goto => {
-                let cursor_bytes_id = self.get::<usize>();
-                self.goto(cursor_bytes_id);
-            }
-            PopGotoIfTrue => {
-                let cursor_bytes_id = self.get::<usize>();
-                if self.lower_stack.pop::<bool>() {
-                    self.goto(cursor_bytes_id);
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Options with every rewrite off, to turn them on one at a time.
+    fn no_optimizations() -> peephole::PeepholeOptions {
+        peephole::PeepholeOptions {
+            fold_constants: false,
+            remove_identities: false,
+            clone_pop_to_peek: false,
+            store_load_to_peek_store: false,
+            simplify_jumps: false,
+            superinstructions: false,
+        }
+    }
+
+    /// Runs `code` on `engine`, after spending `fuel` on the interpreter if given, so
+    /// the engine picks up mid-program. Returns the finished VM with its output
+    /// captured. Host function 3 turns `(i32, u8)` into `(i64, bool)`.
+    fn run(code: &[u8], engine: Engine, fuel: Option<u64>) -> StackUpperVector {
+        run_with(
+            code,
+            VmConfig {
+                engine,
+                ..VmConfig::default()
+            },
+            fuel,
+        )
+    }
+
+    /// `run` with the rest of the configuration as well.
+    fn run_with(code: &[u8], config: VmConfig, fuel: Option<u64>) -> StackUpperVector {
+        let mut vm = StackUpperVector::with_config(VmConfig {
+            engine: Engine::Interpreter,
+            ..config
+        });
+        vm.output = Some(Vec::new());
+        vm.host
+            .register(3, &[Token::I32, Token::U8], &[Token::I64, Token::Bool], |args, _| {
+                match args {
+                    [host::HostValue::I32(a), host::HostValue::U8(b)] => vec![
+                        host::HostValue::I64(*a as i64 * 10 + *b as i64),
+                        host::HostValue::Bool(*b > 2),
+                    ],
+                    _ => unreachable!(),
                 }
-            }
-            PeekGotoIfTrue => {
-                let cursor_bytes_id = self.get::<usize>();
-                if self.lower_stack.peek::<bool>() {
-                    self.goto(cursor_bytes_id);
+            });
+        vm.load_program(code.to_vec())
+            .unwrap_or_else(|error| panic!("{}", error));
+        if let Some(amount) = fuel {
+            vm.execute_with_fuel(&mut fuel::Fuel::new(amount));
+        }
+        vm.engine = config.engine;
+        vm.run();
+        vm
+    }
+
+    #[test]
+    fn stack_array_arithmetic() {
+        let mut stack = StackArray::new();
+        stack.init();
+        stack.push::<i32>(12);
+        stack.push::<i32>(2);
+        stack.push::<i32>(3);
+        stack.push::<i32>(4);
+        stack.multiply::<i32>();
+        stack.push::<i32>(10);
+        stack.push::<i32>(5);
+        stack.divide::<i32>();
+        stack.add::<i32>();
+        stack.multiply::<i32>();
+        stack.add::<u32>();
+
+        assert_eq!(stack.pop::<i32>(), 40);
+        assert_eq!(stack.end, stack.stack.as_mut_ptr());
+    }
+
+    #[test]
+    fn stack_array_long_expression() {
+        let mut stack = StackArray::new();
+        stack.init();
+        stack.push(1i32);
+        stack.push::<i32>(2);
+        stack.push::<i32>(3);
+        stack.push::<i32>(4);
+        stack.push::<i32>(5);
+        stack.push::<i32>(6);
+        stack.push::<i32>(7);
+        stack.push::<i32>(8);
+        stack.push::<i32>(9);
+        stack.push::<i32>(20);
+        stack.push::<i32>(10);
+
+        stack.divide::<i32>();
+        stack.add::<i32>();
+        stack.subtract::<i32>();
+        stack.multiply::<i32>();
+        stack.multiply::<i32>();
+        stack.subtract::<i32>();
+        stack.add::<i32>();
+        stack.subtract::<i32>();
+        stack.add::<i32>();
+        stack.add::<i32>();
+
+        //1 2 3 4 5 6 7 8 9 10 + - * * - + - + +
+        assert_eq!(stack.pop::<i32>(), -129);
+        assert_eq!(stack.end, stack.stack.as_mut_ptr());
+    }
+    #[test]
+    fn stack_array_mixed_signedness() {
+        let mut stack = StackArray::new();
+        stack.init();
+        stack.push::<i32>(12);
+        stack.push::<i32>(2);
+        stack.push::<i32>(3);
+        stack.push::<i32>(4);
+        stack.multiply::<i32>();
+        let value = -10;
+        stack.push::<u32>(value as u32);
+        stack.push::<i32>(5);
+        stack.divide::<i32>();
+        stack.add::<i32>();
+        stack.multiply::<i32>();
+        stack.add::<u32>();
+        assert_eq!(stack.pop::<i32>(), 32);
+        assert_eq!(stack.end, stack.stack.as_mut_ptr());
+    }
+    #[test]
+    fn logic_and() {
+        let mut stack = StackArray::new();
+        stack.init();
+        stack.push(false);
+        stack.push(false);
+        stack.logic_and();
+        assert_eq!(stack.pop::<bool>(), false);
+
+        stack.push(false);
+        stack.push(true);
+        stack.logic_and();
+        assert_eq!(stack.pop::<bool>(), false);
+
+        stack.push(true);
+        stack.push(false);
+        stack.logic_and();
+        assert_eq!(stack.pop::<bool>(), false);
+
+        stack.push(true);
+        stack.push(true);
+        stack.logic_and();
+        assert_eq!(stack.pop::<bool>(), true);
+        assert_eq!(stack.end, stack.stack.as_mut_ptr());
+    }
+    #[test]
+    fn logic_or() {
+        let mut stack = StackArray::new();
+        stack.init();
+        stack.push(false);
+        stack.push(false);
+        stack.logic_or();
+        assert_eq!(stack.pop::<bool>(), false);
+
+        stack.push(false);
+        stack.push(true);
+        stack.logic_or();
+        assert_eq!(stack.pop::<bool>(), true);
+
+        stack.push(true);
+        stack.push(false);
+        stack.logic_or();
+        assert_eq!(stack.pop::<bool>(), true);
+
+        stack.push(true);
+        stack.push(true);
+        stack.logic_or();
+        assert_eq!(stack.pop::<bool>(), true);
+        assert_eq!(stack.end, stack.stack.as_mut_ptr());
+    }
+    #[test]
+    fn logic_not() {
+        let mut stack = StackArray::new();
+        stack.init();
+
+        stack.push(false);
+        stack.logic_not();
+        assert_eq!(stack.pop::<bool>(), true);
+
+        stack.push(true);
+        stack.logic_not();
+        assert_eq!(stack.pop::<bool>(), false);
+        assert_eq!(stack.end, stack.stack.as_mut_ptr());
+    }
+
+    #[test]
+    fn profiler() {
+        let mut stack = StackUpperVector::new();
+        stack.token_byte_sequence = vec![
+            Token::Push as u8,
+            Token::I32 as u8,
+            0,
+            0,
+            0,
+            0,
+            Token::ClonePush as u8,
+            Token::I32 as u8,
+            Token::Push as u8,
+            Token::I32 as u8,
+            10,
+            0,
+            0,
+            0,
+            Token::CompareGreaterEqual as u8,
+            Token::I32 as u8,
+            Token::PopGotoIfTrue as u8,
+            42,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            Token::Push as u8,
+            Token::I32 as u8,
+            1,
+            0,
+            0,
+            0,
+            Token::Add as u8,
+            Token::I32 as u8,
+            Token::Goto as u8,
+            6,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        stack.init();
+        let mut profiler = profiler::Profiler::new(&stack.token_byte_sequence);
+        stack.execute_profiled(&mut profiler);
+
+        assert_eq!(profiler.opcode_histogram()[0], ("push i32".to_owned(), 22));
+        let loops = profiler.hot_loops();
+        assert_eq!(loops.len(), 1);
+        assert_eq!((loops[0].0, loops[0].1, loops[0].2), (6, 42, 11));
+        let pairs = profiler.instruction_pairs();
+        assert!(pairs.contains(&(
+            "compare_greater_equal i32".to_owned(),
+            "pop_goto_if_true".to_owned(),
+            11
+        )));
+        assert_eq!(stack.lower_stack.pop::<i32>(), 10);
+    }
+
+    #[test]
+    fn fuel() {
+        let mut stack = StackUpperVector::new();
+        stack.token_byte_sequence = vec![Token::Goto as u8, 0, 0, 0, 0, 0, 0, 0, 0];
+        stack.init();
+        let mut fuel = fuel::Fuel::new(100);
+        assert_eq!(stack.execute_with_fuel(&mut fuel), fuel::ExecutionOutcome::OutOfFuel);
+        assert_eq!(fuel.remaining, 0);
+
+        let mut stack = StackUpperVector::new();
+        stack.token_byte_sequence = vec![
+            Token::Push as u8,
+            Token::I64 as u8,
+            7,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            Token::Push as u8,
+            Token::I64 as u8,
+            5,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            Token::Multiply as u8,
+            Token::I64 as u8,
+        ];
+        stack.init();
+        let mut fuel = fuel::Fuel::new(4);
+        fuel.set_cost(Token::Multiply as u8, 3);
+        assert_eq!(stack.execute_with_fuel(&mut fuel), fuel::ExecutionOutcome::OutOfFuel);
+        assert_eq!(stack.cursor_offset(), 20);
+        assert_eq!(fuel.remaining, 2);
+        fuel.remaining += 1;
+        assert_eq!(stack.execute_with_fuel(&mut fuel), fuel::ExecutionOutcome::Finished);
+        assert_eq!(fuel.remaining, 0);
+        assert_eq!(stack.lower_stack.pop::<i64>(), 35);
+    }
+
+    #[test]
+    fn snapshot() {
+        let program = vec![
+            Token::Push as u8,
+            Token::I32 as u8,
+            0,
+            0,
+            0,
+            0,
+            Token::ClonePush as u8,
+            Token::I32 as u8,
+            Token::Push as u8,
+            Token::I32 as u8,
+            100,
+            0,
+            0,
+            0,
+            Token::CompareGreaterEqual as u8,
+            Token::I32 as u8,
+            Token::PopGotoIfTrue as u8,
+            52,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            Token::Push as u8,
+            Token::I32 as u8,
+            3,
+            0,
+            0,
+            0,
+            Token::Add as u8,
+            Token::I32 as u8,
+            Token::PeekStore as u8,
+            Token::I32 as u8,
+            0x10,
+            0x27,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            Token::Goto as u8,
+            6,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        let mut uninterrupted = StackUpperVector::new();
+        uninterrupted.token_byte_sequence = program.clone();
+        uninterrupted.init();
+        uninterrupted.execute_all();
+
+        let mut first = StackUpperVector::new();
+        first.token_byte_sequence = program;
+        first.init();
+        let outcome = first.execute_with_fuel(&mut fuel::Fuel::new(123));
+        assert_eq!(outcome, fuel::ExecutionOutcome::OutOfFuel);
+        let bytes = first.snapshot();
+
+        let mut resumed = StackUpperVector::new();
+        resumed.restore(&bytes).unwrap();
+        assert_eq!(resumed.cursor_offset(), first.cursor_offset());
+        assert_eq!(resumed.snapshot(), bytes);
+        resumed.execute_all();
+        assert_eq!(resumed.snapshot(), uninterrupted.snapshot());
+        assert_eq!(resumed.buffer.load::<i32>(10_000), 102);
+        assert_eq!(resumed.lower_stack.pop::<i32>(), 102);
+
+        assert_eq!(
+            resumed.restore(&bytes[..bytes.len() - 1]),
+            Err(snapshot::SnapshotError::Truncated)
+        );
+        assert_eq!(resumed.restore(b"nope"), Err(snapshot::SnapshotError::BadMagic));
+
+        let mut huge = bytes.clone();
+        huge[29..37].copy_from_slice(&(1u64 << 60).to_le_bytes());
+        assert_eq!(
+            resumed.restore(&huge),
+            Err(snapshot::SnapshotError::Invalid(format!(
+                "maximum memory size {} exceeds this VM's {}",
+                1u64 << 60,
+                resumed.buffer.max_size
+            )))
+        );
+
+        let mut misaligned = bytes.clone();
+        misaligned[5..13].copy_from_slice(&1u64.to_le_bytes());
+        assert_eq!(
+            resumed.restore(&misaligned),
+            Err(snapshot::SnapshotError::Invalid(
+                "program counter 1 is not the start of an instruction".to_owned()
+            ))
+        );
+    }
+
+    #[test]
+    fn stack_bounds() {
+        let code = parse_source("push i64 1 push i64 2 push i64 3 add i64 add i64 pop i64");
+        for engine in Engine::ALL {
+            let config = VmConfig {
+                engine,
+                stack_size: 16,
+                ..VmConfig::default()
+            };
+            let mut vm = StackUpperVector::with_config(config);
+            vm.output = Some(Vec::new());
+            vm.load_program(code.clone()).unwrap();
+            let error = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.run()))
+                .expect_err(engine.name());
+            assert_eq!(
+                error.downcast_ref::<String>().map(String::as_str),
+                Some("Stack overflow: the stack holds 16 bytes"),
+                "{}",
+                engine.name()
+            );
+        }
+    }
+
+    #[test]
+    fn growable_memory() {
+        let config = VmConfig {
+            stack_size: 64,
+            memory_size: 16,
+            max_memory_size: 1024,
+            ..VmConfig::default()
+        };
+        let mut stack = StackUpperVector::with_config(config);
+        stack.token_byte_sequence = vec![
+            Token::Push as u8,
+            Token::I64 as u8,
+            42,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            Token::Store as u8,
+            Token::I64 as u8,
+            0xF4,
+            0x01,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+            Token::Load as u8,
+            Token::I32 as u8,
+            0x84,
+            0x03,
+            0,
+            0,
+            0,
+            0,
+            0,
+            0,
+        ];
+        stack.init();
+        stack.execute_all();
+        assert_eq!(stack.buffer.buffer.len(), 512);
+        assert_eq!(stack.buffer.load::<i64>(500), 42);
+        assert_eq!(stack.lower_stack.pop::<i32>(), 0);
+
+        let mut fixed = BufferArray::with_size(16, 16);
+        fixed.store::<u64>(8, 1);
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            fixed.store::<u64>(9, 1);
+        }));
+        assert!(result.is_err());
+        assert_eq!(fixed.buffer.len(), 16);
+    }
+
+    #[test]
+    fn builtins_write_to_captured_output() {
+        let program = assembler::assemble(
+            ".string greeting \"Hi\\n\"\n.lstring name \"Ünï\"\n\
+             push u64 greeting call_host 1 push u64 name call_host 2 push u8 33 call_host 0",
+        )
+        .unwrap();
+        for engine in Engine::ALL {
+            let mut vm = StackUpperVector::with_config(VmConfig { engine, ..VmConfig::default() });
+            host::register_builtins(&mut vm.host);
+            vm.output = Some(Vec::new());
+            vm.load_program(program.code.clone()).unwrap();
+            vm.buffer.initialize(&program.data);
+            vm.run();
+            assert_eq!(vm.output.unwrap(), "Hi\nÜnï!".as_bytes(), "{}", engine.name());
+        }
+    }
+
+    #[test]
+    fn host_functions() {
+        let program = vec![
+            Token::Push as u8,
+            Token::I32 as u8,
+            6,
+            0,
+            0,
+            0,
+            Token::Push as u8,
+            Token::I32 as u8,
+            7,
+            0,
+            0,
+            0,
+            Token::CallHost as u8,
+            3,
+            0,
+            0,
+            0,
+        ];
+        let calls = std::rc::Rc::new(std::cell::Cell::new(0));
+        let mut stack = StackUpperVector::new();
+        let counter = calls.clone();
+        stack
+            .host
+            .register(3, &[Token::I32, Token::I32], &[Token::I64], move |args, _| {
+                counter.set(counter.get() + 1);
+                match args {
+                    [host::HostValue::I32(a), host::HostValue::I32(b)] => {
+                        vec![host::HostValue::I64((*a as i64) * 10 + *b as i64)]
+                    }
+                    _ => panic!("Unexpected arguments {:?}", args),
                 }
+            });
+        stack.load_program(program.clone()).unwrap();
+        stack.execute_all();
+        assert_eq!(calls.get(), 1);
+        assert_eq!(stack.lower_stack.pop::<i64>(), 67);
+
+        let mut unknown = StackUpperVector::new();
+        let error = unknown.load_program(program.clone()).unwrap_err();
+        assert_eq!(error.offset, 12);
+
+        let mut mismatch = StackUpperVector::new();
+        mismatch
+            .host
+            .register(3, &[Token::I32, Token::F32], &[], |_, _| Vec::new());
+        let error = mismatch.load_program(program).unwrap_err();
+        assert_eq!(
+            error.message,
+            "host function 3 expects arguments [i32, f32], found [i32, i32]"
+        );
+
+        // Only types known to contradict a signature are rejected.
+        let mut lenient = StackUpperVector::new();
+        lenient.host.register(3, &[Token::I32, Token::I32], &[], |_, _| Vec::new());
+        lenient.load_program(parse_source("push i32 -1 pop u32")).unwrap();
+        let growing = "push i32 7 push bool true pop_goto_if_true 0 call_host 3";
+        lenient.load_program(parse_source(growing)).unwrap();
+        let contradicting = "push f32 1 push u32 2 add u32 push i32 3 call_host 3";
+        let error = lenient.load_program(parse_source(contradicting)).unwrap_err();
+        assert_eq!(
+            error.message,
+            "host function 3 expects arguments [i32, i32], found [u32, i32]"
+        );
+        let unknown = "push u8 1 push u8 2 pop u16 push i32 3 call_host 3";
+        lenient.load_program(parse_source(unknown)).unwrap();
+    }
+
+    #[test]
+    fn engines_agree() {
+        let programs = [
+            bench::integer_loop(100),
+            bench::float_loop(100),
+            parse_source(
+                "push i8 -3 type_cast i8 u16 peek u16 type_cast u16 f32 push f32 0.5 divide f32 \
+                 peek f32 type_cast f32 i64 clone_push i64 multiply i64 peek i64 \
+                 push i64 9 compare_greater i64 logic_not push bool true logic_or pop bool \
+                 push u32 4000000000 store u32 16 load u8 19 pop u8 push f64 2.5 peek_store f64 24 \
+                 load f64 24 compare_equal f64 peek_goto_if_true 126 push i32 1 pop i32 pop bool",
+            ),
+        ];
+        for code in programs.iter() {
+            let mut interpreted = StackUpperVector::new();
+            interpreted.output = Some(Vec::new());
+            interpreted.load_program(code.clone()).unwrap();
+            interpreted.run();
+
+            for engine in Engine::ALL {
+                let mut vm = StackUpperVector::with_config(VmConfig {
+                    engine,
+                    ..VmConfig::default()
+                });
+                vm.output = Some(Vec::new());
+                vm.load_program(code.clone()).unwrap();
+                vm.run();
+                assert_eq!(vm.output, interpreted.output, "{}", engine.name());
+                assert_eq!(vm.snapshot(), interpreted.snapshot(), "{}", engine.name());
             }
+        }
+    }
 
-            LogicAnd => {
-                self.lower_stack.logic_and();
+    #[test]
+    fn register() {
+        let programs = [
+            bench::integer_loop(20),
+            bench::float_loop(20),
+            // Both ways to the end leave different stacks behind.
+            bench::assemble_parts(
+                &[
+                    "push u8 1 clone_push u8 push u8 1 compare_equal u8 pop_goto_if_true {} \
+                     push u16 5 push u16 6",
+                    "",
+                ],
+                &[&[1], &[]],
+            ),
+            bench::assemble_parts(
+                &[
+                    "push i32 4 push u8 3 call_host 3 pop bool pop i64 push i32 -1 push u8 0 \
+                     call_host 3 store bool 0 store i64 8 load i64 8 push i64 2 multiply i64 \
+                     push bool true logic_not peek bool push bool true logic_and logic_not pop bool \
+                     type_cast i64 f32 peek f32 push f32 3 compare_lesser f32 peek_goto_if_true {} \
+                     push u8 9 pop u8",
+                    "pop bool",
+                ],
+                &[&[1], &[]],
+            ),
+        ];
+        for code in programs.iter() {
+            for fuel in [None, Some(1), Some(4), Some(30)] {
+                let register = run(code, Engine::Register, fuel);
+                let interpreted = run(code, Engine::Interpreter, fuel);
+                assert_eq!(register.output, interpreted.output, "{:?}", fuel);
+                assert_eq!(register.snapshot(), interpreted.snapshot(), "{:?}", fuel);
             }
-            LogicOr => {
-                self.lower_stack.logic_or();
+        }
+    }
+
+    #[test]
+    fn benchmark_programs() {
+        for engine in Engine::ALL {
+            let copied = run(&bench::memory_copy(5), engine, None).buffer;
+            assert_eq!(copied.load::<u64>(0), 5, "{}", engine.name());
+            assert_eq!(copied.load::<u64>(8 + 8 * 32), 4, "{}", engine.name());
+            assert_eq!(copied.buffer[16..264], copied.buffer[272..520]);
+
+            let branched = run(&bench::branching(256), engine, None).buffer;
+            let leaves: Vec<u64> = (0..16).map(|leaf| branched.load::<u64>(16 + 8 * leaf)).collect();
+            // 37 is odd, so `i * 37` hits every byte value once in 256 iterations.
+            assert_eq!(leaves, vec![16; 16], "{}", engine.name());
+        }
+    }
+
+    #[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+    #[test]
+    fn jit() {
+        fn check(source: &str, config: VmConfig) {
+            let code = parse_source(source);
+            // Some of these panic on purpose, so loading is checked outside `catch_unwind`.
+            StackUpperVector::with_config(config)
+                .load_program(code.clone())
+                .unwrap_or_else(|error| panic!("{}", error));
+            let attempt = |config| {
+                std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+                    let mut vm = run_with(&code, config, None);
+                    (vm.output.take(), vm.snapshot())
+                }))
+                .ok()
+            };
+            let interpreted = attempt(VmConfig {
+                engine: Engine::Interpreter,
+                ..config
+            });
+            let compiled = attempt(VmConfig {
+                engine: Engine::Jit,
+                ..config
+            });
+            assert_eq!(compiled, interpreted, "{}", source);
+        }
+
+        let types = [
+            "i8", "i16", "i32", "i64", "u8", "u16", "u32", "u64", "f32", "f64",
+        ];
+        let compares = [
+            "compare_equal",
+            "compare_not_equal",
+            "compare_greater",
+            "compare_greater_equal",
+            "compare_lesser",
+            "compare_lesser_equal",
+        ];
+        let mut source = String::new();
+        for type_name in types.iter() {
+            let signed = type_name.starts_with('i') || type_name.starts_with('f');
+            source += &format!(
+                "push {t} 12 push {t} 5 subtract {t} push {t} 3 multiply {t} push {t} 4 add {t} \
+                 clone_push {t} push {t} 2 divide {t} store {t} 40 peek_store {t} 48 load {t} 40 \
+                 pop {t} pop {t} ",
+                t = type_name
+            );
+            let mut pairs = vec![(3, 5), (5, 3), (5, 5)];
+            if signed {
+                pairs.push((-2, 1));
+            }
+            for compare in compares.iter() {
+                for (left, right) in pairs.iter() {
+                    source += &format!(
+                        "push {t} {} push {t} {} {} {t} pop bool ",
+                        left,
+                        right,
+                        compare,
+                        t = type_name
+                    );
+                }
             }
-            LogicNot => {
-                self.lower_stack.logic_not();
+            for target in types.iter().filter(|target| *target != type_name) {
+                let value = if signed { -100 } else { 200 };
+                source += &format!(
+                    "push {f} {} type_cast {f} {t} pop {t} ",
+                    value,
+                    f = type_name,
+                    t = target
+                );
             }
+        }
+        source += "push f64 0 push f64 0 divide f64 clone_push f64 clone_push f64 compare_equal f64 pop bool \
+                   clone_push f64 clone_push f64 compare_not_equal f64 pop bool \
+                   clone_push f64 push f64 1 compare_lesser_equal f64 pop bool \
+                   type_cast f64 f32 type_cast f32 i32 pop i32 \
+                   push bool true push bool false logic_or push bool false logic_and logic_not pop bool";
+        check(&source, VmConfig::default());
+
+        // Loops run natively; the loop counter is checked against the interpreter.
+        check(
+            "push u32 0 clone_push u32 push u32 1000 compare_lesser u32 logic_not \
+             pop_goto_if_true 43 push u32 1 add u32 goto 6 pop u32",
+            VmConfig::default(),
+        );
+
+        // Accesses past the current memory size leave native code to grow it.
+        check(
+            "push u64 5 store u64 100 load u64 100 pop u64 push u16 7 peek_store u16 500 load u16 500",
+            VmConfig {
+                memory_size: 16,
+                max_memory_size: 1024,
+                ..VmConfig::default()
+            },
+        );
+
+        // Overflow is left to the interpreter, so both panic in debug builds and wrap
+        // in release builds.
+        check("push u8 200 push u8 100 add u8 pop u8", VmConfig::default());
+        check("push i16 -30000 push i16 1000 subtract i16 pop i16", VmConfig::default());
+        check("push i64 4611686018427387904 push i64 2 multiply i64", VmConfig::default());
+        check("push u64 1 store u64 2000", VmConfig::default());
+    }
 
-            CompareEqual => {
-                match_all_numeric_types!(compare_equal, self);
-            }
-            CompareNotEqual => {
-                match_all_numeric_types!(compare_not_equal, self);
+    #[test]
+    fn peephole() {
+        // Optimized programs differ in code, so only what they print and store is compared.
+        let outcome = |code: &[u8]| {
+            let vm = run(code, Engine::Interpreter, None);
+            (vm.output, vm.buffer.buffer)
+        };
+        let all = peephole::PeepholeOptions::default();
+        let unfused = peephole::PeepholeOptions {
+            superinstructions: false,
+            ..all
+        };
+        let optimized = |source: &str| peephole::optimize(&parse_source(source), &unfused);
+
+        assert_eq!(
+            optimized("push i32 2 push i32 3 multiply i32 push i32 4 add i32 pop i32"),
+            parse_source("push i32 10 pop i32")
+        );
+        assert_eq!(
+            optimized("push i8 -1 type_cast i8 u16 pop u16"),
+            parse_source("push u16 65535 pop u16")
+        );
+        assert_eq!(
+            optimized("push u8 1 clone_push u8 pop u8"),
+            parse_source("push u8 1 peek u8")
+        );
+        assert_eq!(
+            optimized("push f64 1 store f64 8 load f64 8 push i64 0 add i64"),
+            parse_source("push f64 1 peek_store f64 8")
+        );
+        // Overflow and float identities that do not hold are left alone.
+        for source in [
+            "push u8 200 push u8 100 add u8",
+            "push i32 1 push i32 0 divide i32",
+            "load f32 0 push f32 0 add f32",
+        ] {
+            assert_eq!(optimized(source), parse_source(source), "{}", source);
+        }
+
+        let loop_program = bench::assemble_parts(
+            &[
+                "push i32 0 store i32 0",
+                "load i32 0 push i32 10 compare_lesser i32 logic_not logic_not logic_not \
+                 pop_goto_if_true {}",
+                "load i32 0 push i32 0 add i32 push i32 1 multiply i32 clone_push i32 pop i32 \
+                 push i32 2 push i32 3 multiply i32 add i32 store i32 0 load i32 0 pop i32 \
+                 push bool false pop_goto_if_true {} goto {}",
+                "goto {}",
+                "push bool true pop_goto_if_true {}",
+                "push u8 7 type_cast u8 f64 pop f64 push f32 -0 push f32 0 add f32 pop f32",
+            ],
+            &[&[], &[4], &[1, 3], &[1], &[5], &[]],
+        );
+        let optimized_loop = peephole::optimize(&loop_program, &all);
+        assert!(optimized_loop.len() < loop_program.len() - 60);
+
+        let programs = [
+            loop_program,
+            bench::integer_loop(50),
+            bench::float_loop(50),
+            parse_source(
+                "push i8 -3 type_cast i8 u16 peek u16 type_cast u16 f32 push f32 0.5 divide f32 \
+                 peek f32 type_cast f32 i64 clone_push i64 multiply i64 peek i64 \
+                 push i64 9 compare_greater i64 logic_not push bool true logic_or pop bool",
+            ),
+        ];
+        for code in programs.iter() {
+            let expected = outcome(code);
+            assert_eq!(outcome(&peephole::optimize(code, &all)), expected);
+            for name in peephole::PeepholeOptions::NAMES {
+                let mut only = no_optimizations();
+                assert!(only.set(name, true));
+                assert_eq!(outcome(&peephole::optimize(code, &only)), expected, "{}", name);
             }
-            CompareGreater => {
-                match_all_numeric_types!(compare_greater, self);
+        }
+        assert_eq!(
+            peephole::optimize(&programs[0], &no_optimizations()),
+            programs[0]
+        );
+    }
+
+    #[test]
+    fn constant_folding() {
+        // Optimized programs differ in code, so only what they print and store is compared.
+        let outcome = |code: &[u8]| {
+            let vm = run(code, Engine::Interpreter, None);
+            (vm.output, vm.buffer.buffer)
+        };
+        let all = peephole::PeepholeOptions::default();
+        let optimized = |source: &str| optimizer::optimize(&parse_source(source), &all);
+
+        // Values are followed through the whole block, not just a few instructions.
+        assert_eq!(
+            optimized(
+                "push i32 5 push i32 256 add i32 type_cast i32 f64 \
+                 push i32 26 type_cast i32 f64 divide f64 pop f64"
+            ),
+            parse_source(&format!("push f64 {} pop f64", 261f64 / 26f64))
+        );
+        assert_eq!(
+            optimized("push u8 1 clone_push u8 push u8 2 multiply u8 add u8 pop u8"),
+            parse_source("push u8 3 pop u8")
+        );
+        assert_eq!(
+            optimized("load i32 0 push i32 2 push i32 3 add i32 pop i32 pop i32"),
+            parse_source("load i32 0 push i32 5 pop i32 pop i32")
+        );
+        // Division by zero and overflow still happen at run time.
+        for source in [
+            "push i32 1 push i32 0 divide i32 pop i32",
+            "push u8 200 push u8 100 add u8 pop u8",
+        ] {
+            assert_eq!(optimized(source), parse_source(source), "{}", source);
+        }
+
+        // The branch is always taken, so the block it skips is deleted.
+        let branch = bench::assemble_parts(
+            &[
+                "push i32 3 push i32 4 compare_lesser i32 logic_not logic_not pop_goto_if_true {}",
+                "push u8 1 pop u8 goto {}",
+                "push u8 2 pop u8",
+            ],
+            &[&[2], &[0], &[]],
+        );
+        assert_eq!(
+            optimizer::optimize(&branch, &all),
+            parse_source("push u8 2 pop u8")
+        );
+        let never = bench::assemble_parts(
+            &[
+                "push bool false peek_goto_if_true {}",
+                "pop bool push u16 7 push u16 7 goto_if_not_equal u16 {} push u16 1 pop u16",
+                "push u16 2 pop u16",
+            ],
+            &[&[1], &[2], &[]],
+        );
+        assert_eq!(
+            optimizer::optimize(&never, &all),
+            parse_source("push bool false pop bool push u16 1 pop u16 push u16 2 pop u16")
+        );
+
+        let programs = [
+            branch,
+            never,
+            bench::integer_loop(50),
+            bench::float_loop(50),
+            bench::assemble_parts(
+                &[
+                    "push i64 0 store i64 0",
+                    "load i64 0 push i64 3 push i64 4 multiply i64 compare_lesser i64 \
+                     pop_goto_if_true {} goto {}",
+                    "load i64 0 peek i64 push i64 1 push i64 2 add i64 add i64 store i64 0 \
+                     push bool true push bool false logic_or pop_goto_if_true {} push i8 1 pop i8",
+                    "push f32 1.5 type_cast f32 i8 push i8 -2 multiply i8 pop i8",
+                ],
+                &[&[], &[2, 3], &[1], &[]],
+            ),
+        ];
+        for code in programs.iter() {
+            let expected = outcome(code);
+            let folded = optimizer::optimize(code, &all);
+            assert!(folded.len() <= code.len());
+            assert_eq!(outcome(&folded), expected);
+            assert_eq!(outcome(&peephole::optimize(&folded, &all)), expected);
+            for name in ["fold-constants", "simplify-jumps"] {
+                let mut only = no_optimizations();
+                only.set(name, true);
+                assert_eq!(outcome(&optimizer::optimize(code, &only)), expected, "{}", name);
+            }
+            assert_eq!(
+                optimizer::optimize(code, &no_optimizations()),
+                *code
+            );
+        }
+    }
+
+    #[test]
+    fn superinstructions() {
+        let vm = run(
+            &parse_source(
+                "push i32 5 add_immediate i32 -7 pop i32 increment u16 4 7 increment u16 4 7 \
+                 load u16 4 pop u16 push f64 1 add_immediate f64 0.5 peek f64 \
+                 push f64 2 goto_if_lesser f64 97 push bool true pop bool",
+            ),
+            Engine::Interpreter,
+            None,
+        );
+        assert_eq!(vm.output.unwrap(), b"-2\n14\n1.500\n");
+
+        let mut vm = StackUpperVector::new();
+        let error = vm
+            .load_program(parse_source("push bool true add_immediate bool true"))
+            .unwrap_err();
+        assert_eq!(error.message, "add_immediate bool is not defined for bool");
+
+        let fused = peephole::optimize(
+            &bench::integer_loop(10),
+            &peephole::PeepholeOptions::default(),
+        );
+        let opcodes: Vec<u8> = bytecode::decode_all(&fused)
+            .iter()
+            .map(|instruction| instruction.opcode)
+            .collect();
+        assert!(opcodes.contains(&(Token::AddImmediate as u8)));
+        assert!(opcodes.contains(&(Token::GotoIfGreaterEqual as u8)));
+
+        let counters = bench::assemble_parts(
+            &[
+                "push u64 0",
+                "load i64 8 push i64 -3 add i64 store i64 8 load u8 0 push u8 1 add u8 store u8 0 \
+                 load f32 16 push f32 0.25 add f32 store f32 16 \
+                 add_immediate u64 1 clone_push u64 push u64 20 compare_lesser u64 pop_goto_if_true {}",
+                "load u8 0 pop u8 load i64 8 pop i64 load f32 16 pop f32 \
+                 push f64 0 push f64 0 divide f64 push f64 1 compare_not_equal f64 \
+                 pop_goto_if_true {} push i8 -1 pop i8",
+                "push u64 18446744073709551615 push u64 1 compare_greater u64 pop_goto_if_true {}",
+                "push i16 -5 push i16 3 compare_lesser_equal i16 pop_goto_if_true {} push u32 9 pop u32",
+                "pop u64",
+            ],
+            &[&[], &[1], &[3], &[4], &[5], &[]],
+        );
+        let programs = [
+            counters,
+            bench::integer_loop(100),
+            bench::float_loop(100),
+        ];
+        for code in programs.iter() {
+            let fused = peephole::optimize(code, &peephole::PeepholeOptions::default());
+            assert!(fused.len() < code.len());
+            let expected = run(code, Engine::Interpreter, None).output;
+            let fused_snapshot = run(&fused, Engine::Interpreter, None).snapshot();
+            for engine in Engine::ALL {
+                let vm = run(&fused, engine, None);
+                assert_eq!(vm.output, expected, "{}", engine.name());
+                assert_eq!(vm.snapshot(), fused_snapshot, "{}", engine.name());
             }
-            CompareGreaterEqual => {
-                match_all_numeric_types!(compare_greater_equal, self);
+        }
+    }
+
+    #[test]
+    fn reference_agrees_with_engines() {
+        fn host() -> host::HostRegistry {
+            let mut host = host::HostRegistry::new();
+            reference::register_host_function(&mut host);
+            host
+        }
+        let config = VmConfig::default();
+
+        // Traps panic in the VM; every difference is reported at the end.
+        let mut failures = Vec::new();
+        for seed in 0..300 {
+            let code = reference::Generator::program(seed, 40);
+            let mut expected = reference::Reference::new(config.max_memory_size);
+            let trap = expected.run(&code, &mut host(), 1_000_000).err();
+            for engine in Engine::ALL {
+                let mut vm = StackUpperVector::with_config(VmConfig { engine, ..config });
+                vm.host = host();
+                vm.output = Some(Vec::new());
+                if let Err(error) = vm.load_program(code.clone()) {
+                    failures.push(format!("seed {}: {}", seed, error));
+                    break;
+                }
+                let panicked =
+                    std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.run())).is_err();
+                let stack = &vm.lower_stack.stack[..vm.lower_stack.depth()];
+                let differs = if panicked != trap.is_some() {
+                    Some(format!("panicked: {}, reference: {:?}", panicked, trap))
+                } else if vm.output.as_deref() != Some(&expected.output[..]) {
+                    Some(format!("printed {:?}, reference {:?}", vm.output, expected.output))
+                } else if !panicked && !reference::same_stack(&expected, stack) {
+                    Some(format!("stack {:?}, reference {:?}", stack, expected.stack))
+                } else if !panicked && !reference::same_memory(&expected, &vm.buffer.buffer) {
+                    Some("memory differs".to_owned())
+                } else {
+                    None
+                };
+                if let Some(difference) = differs {
+                    failures.push(format!("seed {} on {}: {}", seed, engine.name(), difference));
+                }
             }
-            CompareLesser => {
-                match_all_numeric_types!(compare_lesser, self);
+        }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
+    }
+
+    #[test]
+    fn every_instruction_runs_on_every_engine() {
+        use bytecode::{BOOL, F32, F64, I32};
+        use instructions::{Effect, Operand, Slot, Types, INSTRUCTIONS, TYPES};
+        fn value(type_tag: u8) -> &'static str {
+            match type_tag {
+                BOOL => "true",
+                F32 | F64 => "2.5",
+                _ => "3",
             }
-            CompareLesserEqual => {
-                match_all_numeric_types!(compare_lesser_equal, self);
+        }
+        // Every row of the table with every type it is defined for.
+        let mut sources = Vec::new();
+        for definition in INSTRUCTIONS {
+            let types: Vec<u8> = TYPES
+                .iter()
+                .map(|type_tag| type_tag.tag)
+                .filter(|tag| definition.types != Types::Numeric || *tag != BOOL)
+                .collect();
+            let type_count = definition
+                .operands
+                .iter()
+                .filter(|operand| **operand == Operand::Type)
+                .count();
+            let combinations: Vec<Vec<u8>> = match type_count {
+                0 => vec![Vec::new()],
+                1 => types.iter().map(|first| vec![*first]).collect(),
+                _ => types
+                    .iter()
+                    .flat_map(|first| types.iter().map(move |second| vec![*first, *second]))
+                    .filter(|pair| pair[0] != pair[1])
+                    .collect(),
+            };
+            for combination in combinations {
+                let first = combination.first().copied().unwrap_or(BOOL);
+                let slot_type = |slot: &Slot| match slot {
+                    Slot::First => first,
+                    Slot::Second => combination[1],
+                    Slot::Bool => BOOL,
+                };
+                let pushes: Vec<u8> = match definition.effect {
+                    Effect::Stack(pops, _) => pops.iter().map(slot_type).collect(),
+                    Effect::Host => vec![I32, I32],
+                };
+                let mut source = String::new();
+                for type_tag in pushes {
+                    let name = bytecode::mnemonic(type_tag);
+                    source += &format!("push {} {} ", name, value(type_tag));
+                }
+                source += definition.mnemonic;
+                let mut types = combination.iter();
+                for operand in definition.operands {
+                    source += " ";
+                    source += &match operand {
+                        Operand::Type => bytecode::mnemonic(*types.next().unwrap()),
+                        Operand::Value => value(first).to_owned(),
+                        Operand::Address => "0".to_owned(),
+                        Operand::Target => "end".to_owned(),
+                        Operand::HostFunction => reference::HOST_FUNCTION.to_string(),
+                    };
+                }
+                sources.push(source + " end:");
             }
+        }
 
-            TypeCast => {
-                let type_1 = self.get::<u8>();
-                let type_2 = self.get::<u8>();
-                match type_1 {
-                    I8 => {
-                        match type_2 {
-                            //I8 => self.lower_stack.cast_from_to::<i8, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<i8, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<i8, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<i8, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<i8, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<i8, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<i8, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<i8, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<i8, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<i8, f64>(),
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-
-                    I16 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<i16, i8>(),
-                            //I16 => self.lower_stack.cast_from_to::<i16, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<i16, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<i16, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<i16, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<i16, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<i16, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<i16, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<i16, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<i16, f64>(),
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    I32 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<i32, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<i32, i16>(),
-                            //I32 => self.lower_stack.cast_from_to::<i32, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<i32, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<i32, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<i32, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<i32, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<i32, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<i32, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<i32, f64>(),
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    I64 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<i64, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<i64, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<i64, i32>(),
-                            //I64 => self.lower_stack.cast_from_to::<i64, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<i64, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<i64, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<i64, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<i64, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<i64, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<i64, f64>(),
-
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    U8 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<u8, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<u8, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<u8, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<u8, i64>(),
-                            //U8 =>  self.lower_stack.cast_from_to::<u8, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<u8, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<u8, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<u8, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<u8, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<u8, f64>(),
-
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    U16 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<u16, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<u16, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<u16, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<u16, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<u16, u8>(),
-                            //U16 => self.lower_stack.cast_from_to::<u16, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<u16, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<u16, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<u16, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<u16, f64>(),
-
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    U32 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<u32, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<u32, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<u32, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<u32, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<u32, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<u32, u16>(),
-                            //U32 => self.lower_stack.cast_from_to::<u32, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<u32, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<u32, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<u32, f64>(),
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    U64 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<u64, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<u64, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<u64, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<u64, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<u64, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<u64, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<u64, u32>(),
-                            //U64 => self.lower_stack.cast_from_to::<u64, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<u64, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<u64, f64>(),
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    F32 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<f32, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<f32, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<f32, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<f32, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<f32, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<f32, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<f32, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<f32, u64>(),
-                            //F32 => self.lower_stack.cast_from_to::<f32, f32>(),
-                            F64 => self.lower_stack.cast_from_to::<f32, f64>(),
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    F64 => {
-                        match type_2 {
-                            I8 => self.lower_stack.cast_from_to::<f64, i8>(),
-                            I16 => self.lower_stack.cast_from_to::<f64, i16>(),
-                            I32 => self.lower_stack.cast_from_to::<f64, i32>(),
-                            I64 => self.lower_stack.cast_from_to::<f64, i64>(),
-                            U8 => self.lower_stack.cast_from_to::<f64, u8>(),
-                            U16 => self.lower_stack.cast_from_to::<f64, u16>(),
-                            U32 => self.lower_stack.cast_from_to::<f64, u32>(),
-                            U64 => self.lower_stack.cast_from_to::<f64, u64>(),
-                            F32 => self.lower_stack.cast_from_to::<f64, f32>(),
-                            //F64 => self.lower_stack.cast_from_to::<f64, f64>(),
-                            _ => panic!("Invalid type cast!"),
-                        }
-                    }
-                    _ => {
-                        panic!("Unknown type!")
-                    }
+        // Only address 0 is used, so a small memory keeps comparing it cheap.
+        let config = VmConfig {
+            memory_size: 16,
+            max_memory_size: 16,
+            ..VmConfig::default()
+        };
+        let mut failures = Vec::new();
+        for source in sources.iter() {
+            let code = assembler::assemble(source).unwrap().code;
+            let mut host = host::HostRegistry::new();
+            reference::register_host_function(&mut host);
+            let mut expected = reference::Reference::new(config.max_memory_size);
+            if let Err(trap) = expected.run(&code, &mut host, 1_000) {
+                failures.push(format!("{}: reference traps: {:?}", source, trap));
+                continue;
+            }
+            for engine in Engine::ALL {
+                let mut vm = StackUpperVector::with_config(VmConfig { engine, ..config });
+                reference::register_host_function(&mut vm.host);
+                vm.output = Some(Vec::new());
+                vm.load_program(code.clone()).unwrap();
+                let run = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| vm.run()));
+                let stack = &vm.lower_stack.stack[..vm.lower_stack.depth()];
+                if run.is_err()
+                    || vm.output.as_deref() != Some(&expected.output[..])
+                    || !reference::same_stack(&expected, stack)
+                    || !reference::same_memory(&expected, &vm.buffer.buffer)
+                {
+                    failures.push(format!("{}: differs on {}", source, engine.name()));
                 }
             }
-            _ => {
-                panic!("Unknown Token! {}", Token)
-            }
         }
+        assert!(failures.is_empty(), "{}", failures.join("\n"));
     }
-    fn execute_all(&mut self) -> () {
-        let size = self.token_byte_sequence.len();
-        unsafe {
-            let end_ptr = self.token_byte_sequence.as_mut_ptr().add(size);
 
-            while end_ptr.offset_from(self.cursor) > 0 {
-                self.do_Token();
-            }
+    #[test]
+    fn memory_directives() {
+        let program = assembler::assemble(
+            ".var flag bool true\n.array words u16 3 7\n.data bytes u8 1 2\n.var wide u64\n\
+             load u16 words type_cast u16 u64 store u64 wide",
+        )
+        .unwrap();
+        // Every variable is aligned to the size of its type.
+        let mut data = vec![1, 0, 7, 0, 0, 0, 0, 0, 1, 2];
+        data.resize(24, 0);
+        assert_eq!(program.data, data);
+        assert_eq!(program.code, parse_source("load u16 2 type_cast u16 u64 store u64 16"));
+
+        let mut vm = StackUpperVector::with_config(VmConfig {
+            memory_size: 0,
+            ..VmConfig::default()
+        });
+        vm.load_program(program.code).unwrap();
+        vm.buffer.initialize(&program.data);
+        vm.run();
+        assert_eq!(vm.buffer.load::<u64>(16), 7);
+
+        for (source, line) in [
+            (".var twice i32\n.var twice i8", 2),
+            ("push i32 1\nstore i32 nowhere", 2),
+            (".array short u8", 1),
+            (".data some u8 1 2\n.array few u8 1 1 2", 2),
+            (".var thing\n", 1),
+            (".var push i8", 1),
+            ("push u8 1\n.global name u8", 2),
+        ] {
+            let error = assembler::assemble(source).unwrap_err();
+            assert_eq!(error.line, line, "{}: {}", source, error);
         }
+
+        let program = assembler::assemble(".var small u8\n.array big u64 4\n.var after u8").unwrap();
+        assert_eq!(program.check_memory(41), Ok(()));
+        let error = program.check_memory(39).unwrap_err();
+        assert_eq!(error.line, 2);
+        assert_eq!(
+            error.message,
+            "Variables need 40 bytes of memory, more than the maximum of 39"
+        );
     }
-}
-trait StackMachineUpper: StackMachine {
-    fn goto(&mut self, row_id: usize) -> ();
-    fn goto_if_pop_true(&mut self, row_id: usize) -> ();
-    fn goto_if_peek_true(&mut self, row_id: usize) -> ();
-}
-#[derive(Debug)]
-enum Token {
-    Push = 0,
-    Pop,
-    Peek,
-    ClonePush,
-
-    Add,
-    Subtract,
-    Multiply,
-    Divide,
-
-    Store,
-    PeekStore,
-    Load,
-
-    Goto,
-    PopGotoIfTrue,
-    PeekGotoIfTrue,
-
-    LogicAnd,
-    LogicOr,
-    LogicNot,
-
-    CompareEqual,
-    CompareNotEqual,
-    CompareGreater,
-    CompareGreaterEqual,
-    CompareLesser,
-    CompareLesserEqual,
-
-    TypeCast,
-
-    Bool,
-
-    I8,
-    I16,
-    I32,
-    I64,
-
-    U8,
-    U16,
-    U32,
-    U64,
-
-    F32,
-    F64,
-}
 
-fn tests() -> () {
-    test1();
-    test2();
-    test3();
-    test_logic_and();
-    test_logic_or();
-    test_logic_not();
-}
+    #[test]
+    fn string_directives() {
+        let program = assembler::assemble(
+            ".string hello \"a b\\t\\\"\\u{e9}\"\n.lstring counted \"xyz\"  \n\
+             load u8 hello load u64 counted pop u64 pop u8",
+        )
+        .unwrap();
+        let mut data = b"a b\t\"\xc3\xa9\0".to_vec();
+        data.resize(8, 0);
+        data.extend(3u64.to_le_bytes());
+        data.extend(b"xyz");
+        assert_eq!(program.data, data);
+        assert_eq!(program.code, parse_source("load u8 0 load u64 8 pop u64 pop u8"));
+
+        for source in [
+            ".string unterminated \"abc",
+            ".string escape \"\\q\"",
+            ".string \"no name\"",
+            ".lstring trailing \"abc\" def",
+            ".string short \"\\x4\"",
+            ".string missing quotes",
+        ] {
+            assert!(assembler::assemble(source).is_err(), "{}", source);
+        }
+    }
 
-fn test1() -> () {
-    let mut stack = StackArray::new();
-    stack.init();
-    stack.push::<i32>(12);
-    stack.push::<i32>(2);
-    stack.push::<i32>(3);
-    stack.push::<i32>(4);
-    stack.multiply::<i32>();
-    stack.push::<i32>(10);
-    stack.push::<i32>(5);
-    stack.divide::<i32>();
-    stack.add::<i32>();
-    stack.multiply::<i32>();
-    stack.add::<u32>();
-
-    assert_eq!(stack.pop::<i32>(), 40);
-    assert_eq!(stack.end, stack.stack.as_mut_ptr());
-
-    println!("Test 1 passed");
-}
+    #[test]
+    fn macros_and_constants() {
+        let program = assembler::assemble(
+            "swap_add i64 TWO\n\
+             .const TWO 2\n\
+             .macro swap_add type amount\n\
+             push type amount\n\
+             twice add type\n\
+             .endm\n\
+             .macro twice op type\n\
+             push type 1 op type op type\n\
+             .endm",
+        )
+        .unwrap();
+        assert_eq!(
+            program.code,
+            parse_source("push i64 2 push i64 1 add i64 add i64")
+        );
+
+        let error = |source: &str| assembler::assemble(source).unwrap_err();
+        let unknown = error(".macro save\nstore u8 nowhere\n.endm\npush u8 1\nsave");
+        assert_eq!(unknown.line, 5);
+        assert_eq!(unknown.notes, vec!["in macro save at line 2"]);
+        let arguments = error(".macro one a\npush u8 a\n.endm\none 1 2");
+        assert_eq!(arguments.line, 4);
+        assert_eq!(arguments.notes, vec!["macro one is defined at line 1"]);
+        let recursive = error(".macro again\npush u8 1\nagain\n.endm\nagain");
+        assert_eq!(recursive.line, 5);
+        assert_eq!(
+            recursive.notes,
+            vec![
+                "macro again is defined at line 1",
+                "in macro again at line 3 (64 times)"
+            ]
+        );
+        let mutual = error(".macro ping
+pong
+.endm
+.macro pong
+ping
+.endm
+ping");
+        assert_eq!(
+            mutual.notes[1..],
+            ["in macro pong at line 5 (32 times)", "in macro ping at line 2 (32 times)"]
+        );
+        let twice = error(".const A 1\n.const A 2");
+        assert_eq!((twice.line, twice.notes), (2, vec!["first defined at line 1".to_owned()]));
+        assert_eq!(error("push u8 1\n.macro open\n").line, 2);
+        assert_eq!(error(".endm").line, 1);
+        assert_eq!(error(".const push 1").line, 1);
+        assert_eq!(error(".macro outer\n.macro inner\n.endm\n.endm").line, 2);
+    }
 
-fn test2() -> () {
-    let mut stack = StackArray::new();
-    stack.init();
-    stack.push(1i32);
-    stack.push::<i32>(2);
-    stack.push::<i32>(3);
-    stack.push::<i32>(4);
-    stack.push::<i32>(5);
-    stack.push::<i32>(6);
-    stack.push::<i32>(7);
-    stack.push::<i32>(8);
-    stack.push::<i32>(9);
-    stack.push::<i32>(20);
-    stack.push::<i32>(10);
-
-    stack.divide::<i32>();
-    stack.add::<i32>();
-    stack.subtract::<i32>();
-    stack.multiply::<i32>();
-    stack.multiply::<i32>();
-    stack.subtract::<i32>();
-    stack.add::<i32>();
-    stack.subtract::<i32>();
-    stack.add::<i32>();
-    stack.add::<i32>();
-
-    //1 2 3 4 5 6 7 8 9 10 + - * * - + - + +
-    assert_eq!(stack.pop::<i32>(), -129);
-    assert_eq!(stack.end, stack.stack.as_mut_ptr());
-
-    println!("Test 2 passed");
-}
-fn test3() -> () {
-    let mut stack = StackArray::new();
-    stack.init();
-    stack.push::<i32>(12);
-    stack.push::<i32>(2);
-    stack.push::<i32>(3);
-    stack.push::<i32>(4);
-    stack.multiply::<i32>();
-    let value = -10;
-    stack.push::<u32>(value as u32);
-    stack.push::<i32>(5);
-    stack.divide::<i32>();
-    stack.add::<i32>();
-    stack.multiply::<i32>();
-    stack.add::<u32>();
-    assert_eq!(stack.pop::<i32>(), 32);
-    assert_eq!(stack.end, stack.stack.as_mut_ptr());
-
-    println!("Test 3 passed");
-}
-fn test_logic_and() -> () {
-    let mut stack = StackArray::new();
-    stack.init();
-    stack.push(false);
-    stack.push(false);
-    stack.logic_and();
-    assert_eq!(stack.pop::<bool>(), false);
-
-    stack.push(false);
-    stack.push(true);
-    stack.logic_and();
-    assert_eq!(stack.pop::<bool>(), false);
-
-    stack.push(true);
-    stack.push(false);
-    stack.logic_and();
-    assert_eq!(stack.pop::<bool>(), false);
-
-    stack.push(true);
-    stack.push(true);
-    stack.logic_and();
-    assert_eq!(stack.pop::<bool>(), true);
-    assert_eq!(stack.end, stack.stack.as_mut_ptr());
-
-    println!("Test logic and passed");
-}
-fn test_logic_or() -> () {
-    let mut stack = StackArray::new();
-    stack.init();
-    stack.push(false);
-    stack.push(false);
-    stack.logic_or();
-    assert_eq!(stack.pop::<bool>(), false);
-
-    stack.push(false);
-    stack.push(true);
-    stack.logic_or();
-    assert_eq!(stack.pop::<bool>(), true);
-
-    stack.push(true);
-    stack.push(false);
-    stack.logic_or();
-    assert_eq!(stack.pop::<bool>(), true);
-
-    stack.push(true);
-    stack.push(true);
-    stack.logic_or();
-    assert_eq!(stack.pop::<bool>(), true);
-    assert_eq!(stack.end, stack.stack.as_mut_ptr());
-
-    println!("Test logic or passed");
-}
-fn test_logic_not() -> () {
-    let mut stack = StackArray::new();
-    stack.init();
+    #[test]
+    fn labels() {
+        let program = assembler::assemble(
+            "goto skip\n\
+             start: push u8 1 store u8 0\n\
+             skip: push bool true pop_goto_if_true start",
+        )
+        .unwrap();
+        let skip = 9 + 2 + 1 + 2 + 8;
+        assert_eq!(
+            program.code,
+            parse_source(&format!(
+                "goto {} push u8 1 store u8 0 push bool true pop_goto_if_true 9",
+                skip
+            ))
+        );
+
+        let error = |source: &str| assembler::assemble(source).unwrap_err();
+        let twice = error("here:\nhere: push u8 1");
+        assert_eq!((twice.line, twice.notes), (2, vec!["first defined at line 1".to_owned()]));
+        assert_eq!(error("push u8 1\ngoto nowhere").line, 2);
+        assert_eq!(error("push: goto 0").line, 1);
+        assert_eq!(error("spin@1: goto 0").message, "spin@1 cannot be a name");
+
+        let expanded = |source: &str| assembler::assemble(source).unwrap().code;
+        let spin = ".macro spin\nagain: goto again\n.endm\n";
+        assert_eq!(expanded(&format!("{}spin\nspin", spin)), parse_source("goto 0 goto 9"));
+        let passed = ".macro jump to\ngoto to\n.endm\n.macro outer\ndone: jump done\n.endm\n";
+        assert_eq!(expanded(&format!("{}outer\nouter", passed)), parse_source("goto 0 goto 9"));
+        let twice = error(".macro twice\nhere: here:\n.endm\ntwice");
+        assert_eq!(twice.message, "Label here@1 is defined twice");
+    }
 
-    stack.push(false);
-    stack.logic_not();
-    assert_eq!(stack.pop::<bool>(), true);
+    #[test]
+    fn dotted_mnemonics() {
+        let spaced = parse_source(
+            "push i32 7 type_cast i32 f64 push f64 0.5 add f64 store f64 0 \
+             increment u8 8 1 goto_if_lesser u8 0",
+        );
+        let dotted = parse_source(
+            "push.i32 7 cast.i32.f64 push.f64 0.5 add.f64 store.f64 0 \
+             increment.u8 8 1 goto_if_lesser.u8 0",
+        );
+        assert_eq!(dotted, spaced);
+        assert_eq!(parse_source("cast i8 u8 logic_not"), parse_source("type_cast.i8.u8 logic_not"));
+
+        let error = |source: &str| assembler::assemble(source).unwrap_err().message;
+        assert_eq!(error("type_cast.i32 push.i32 1"), "type_cast takes 2 types, got 1");
+        assert_eq!(error("logic_not.bool"), "logic_not takes 0 types, got 1");
+        assert_eq!(error("add.int"), "Unexpected token: add.int");
+        assert_eq!(error("push.u8"), "push needs a value");
+        assert_eq!(error("pop add i32"), "pop needs a type, found add");
+        assert_eq!(error("call_host"), "call_host needs a host function id");
+    }
 
-    stack.push(true);
-    stack.logic_not();
-    assert_eq!(stack.pop::<bool>(), false);
-    assert_eq!(stack.end, stack.stack.as_mut_ptr());
+    #[test]
+    fn operand_expressions() {
+        let program = assembler::assemble(
+            ".var flag u8\n\
+             .const STRIDE sizeof(u32)\n\
+             .const OFFSET 4 - 3\n\
+             .array table u32 STRIDE*2\n\
+             start: load u32 table + STRIDE * ( 1 + 1 )\n\
+             push u64 end - start\n\
+             push u8 10 * OFFSET push i8 -2*OFFSET increment i8 flag -1\n\
+             goto end+0\n\
+             end:",
+        )
+        .unwrap();
+        assert_eq!(
+            program.code,
+            parse_source(
+                "load u32 12 push u64 46 push u8 10 push i8 -2 increment i8 0 -1 goto 46"
+            )
+        );
+
+        let error = |source: &str| assembler::assemble(source).unwrap_err().message;
+        assert_eq!(error("push u8 200 + 100"), "200 + 100 is out of range for u8 (0 to 255)");
+        assert_eq!(error("push u8 1 / ( 2 - 2 )"), "Division by zero in 1 / 0");
+        assert_eq!(
+            error("push u64 0x7fff_ffff_ffff_ffff_ffff_ffff_ffff_ffff+1"),
+            "Overflow in 170141183460469231731687303715884105727 + 1"
+        );
+        assert_eq!(error("goto 0 - 1"), "0 - 1 is out of range for a jump target");
+        assert_eq!(error("push u8 nowhere"), "Unknown name nowhere");
+        assert_eq!(error("push u8 ( 1 + 2"), "( 1 + 2 ends too early");
+        assert_eq!(
+            error(".const A B\n.const B A\npush u8 A+1"),
+            "Constant B is defined in terms of itself"
+        );
+    }
 
-    println!("Test logic not passed");
+    #[test]
+    fn instruction_table() {
+        let reference = instructions::reference();
+        for instruction in instructions::INSTRUCTIONS {
+            let mut source = instruction.mnemonic.to_owned();
+            let mut types = ["i32", "u8"].into_iter();
+            for operand in instruction.operands {
+                source.push(' ');
+                source.push_str(match operand {
+                    instructions::Operand::Type => types.next().unwrap(),
+                    instructions::Operand::HostFunction => "3",
+                    _ => "0",
+                });
+            }
+            let code = parse_source(&source);
+            let decoded = bytecode::decode(&code, 0);
+            assert_eq!(decoded.size, code.len(), "{}", source);
+            assert_eq!(bytecode::mnemonic(decoded.opcode), instruction.mnemonic);
+            assert_eq!(bytecode::layout(&bytecode::lift(&code)), code, "{}", source);
+            assert!(reference.contains(instruction.mnemonic));
+        }
+        for type_tag in instructions::TYPES {
+            assert_eq!(create_mapping()[type_tag.mnemonic], type_tag.tag);
+            assert_eq!(bytecode::type_size(type_tag.tag), type_tag.size);
+        }
+        assert_eq!(
+            create_mapping().len(),
+            instructions::INSTRUCTIONS.len() + instructions::TYPES.len()
+        );
+    }
+
+    #[test]
+    fn listing() {
+        let program =
+            assembler::assemble(".var total u16 7\npush u8 1 pop u8\n\nend: load u16 total").unwrap();
+        assert_eq!(
+            program.listing(),
+            "     0  00 1D 01                                   2                push u8 1 pop u8\n\
+             \x20    3  01 1D\n\
+             \x20    5  0A 1E 00 00 00 00 00 00 00 00              4                end: load u16 total\n\
+             \n\
+             labels\n\
+             \x20    5  end\n\
+             \n\
+             variables\n\
+             \x20    0  total\n"
+        );
+        assert_eq!(program.lines[1].code, 5..15);
+    }
+
+    #[test]
+    fn line_table() {
+        let program = assembler::assemble(
+            ".macro drop type\n\
+             pop type\n\
+             .endm\n\
+             push u8 1   push u8 2 add u8\n\
+             \x20 start: drop u8",
+        )
+        .unwrap();
+        let position = |offset| {
+            let position = program.line_table.lookup(offset).unwrap();
+            (position.line, position.column)
+        };
+        assert_eq!(position(0), (4, 1));
+        assert_eq!(position(2), (4, 1));
+        assert_eq!(position(3), (4, 13));
+        assert_eq!(position(6), (4, 23));
+        assert_eq!(position(8), (5, 10));
+        assert_eq!(program.line_table.lookup(8).unwrap().to_string(), "line 5, column 10");
+
+        // Folding the additions leaves one push, which keeps the position of the first.
+        let ops = optimizer::optimize_ops(
+            bytecode::lift(&program.code),
+            &peephole::PeepholeOptions::default(),
+        );
+        let optimized = program.line_table.remap(&bytecode::origins(&ops));
+        assert_eq!(bytecode::layout(&ops), parse_source("push u8 3 pop u8"));
+        let line = |offset| optimized.lookup(offset).unwrap().line;
+        assert_eq!((line(0), line(3)), (4, 5));
+    }
+
+    #[test]
+    fn includes() {
+        let directory = std::env::temp_dir().join(format!("includes-{}", std::process::id()));
+        let library = directory.join("lib");
+        std::fs::create_dir_all(&library).unwrap();
+        let write = |name: &str, source: &str| std::fs::write(directory.join(name), source).unwrap();
+        write(
+            "lib/math.asm",
+            ".include \"util.asm\"\n\
+             .macro double type\n\
+             clone_push type add type\n\
+             .endm\n\
+             done: goto end",
+        );
+        write("lib/util.asm", "done: goto end");
+        write("cycle.asm", ".include \"cycle.asm\"");
+        write("other.asm", "done: push u8 1");
+        let assemble = |source: &str, search_paths: &[PathBuf]| {
+            assembler::assemble_file(&directory.join("main.asm"), source, search_paths)
+        };
+
+        let source = ".include \"lib/math.asm\"\n\
+                      .include \"lib/math.asm\"\n\
+                      push u8 2\n\
+                      double u8\n\
+                      goto math.done\n\
+                      end:";
+        let program = assemble(source, &[]).unwrap();
+        assert_eq!(
+            program.code,
+            parse_source("goto 34 goto 34 push u8 2 clone_push u8 add u8 goto 9")
+        );
+        let searched = assemble(".include \"math.asm\" as m\ngoto m.done\nend:", &[library]);
+        assert!(searched.is_ok(), "{:?}", searched);
+
+        let cycle = assemble("push u8 1\n.include \"cycle.asm\"", &[]).unwrap_err();
+        assert_eq!(cycle.line, 1);
+        assert!(cycle.file.unwrap().ends_with("cycle.asm"));
+        assert_eq!(cycle.notes.len(), 1);
+        assert!(cycle.notes[0].ends_with("main.asm:2"));
+        let duplicate = assemble(".include \"other.asm\" as lib\nlib.done:", &[]).unwrap_err();
+        assert_eq!(duplicate.line, 2);
+        let missing = assemble(".include \"missing.asm\"", &[]).unwrap_err();
+        assert_eq!(missing.to_string().lines().count(), 1);
+        assert!(missing.to_string().contains("main.asm:1: Cannot find missing.asm"));
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn numeric_literals() {
+        use assembler::try_parse_value as parse;
+        use bytecode::{F32, F64, I32, I64, I8, U16, U32, U8};
+        use host::HostValue;
+        for (type_tag, word, value) in [
+            (U32, "0xFF", HostValue::U32(255)),
+            (U8, "'A'", HostValue::U8(65)),
+            (U8, "'\\n'", HostValue::U8(10)),
+            (U8, "'\\x7f'", HostValue::U8(127)),
+            (U8, "'\\''", HostValue::U8(39)),
+            (U32, "'\\u{1F600}'", HostValue::U32(0x1F600)),
+            (I64, "1_000_000", HostValue::I64(1_000_000)),
+            (I8, "-0x80", HostValue::I8(-128)),
+            (U16, "0b1010_1010", HostValue::U16(0xAA)),
+            (I32, "0o777", HostValue::I32(511)),
+            (I32, "+12", HostValue::I32(12)),
+            (F64, "1.5e3", HostValue::F64(1500.0)),
+            (F32, "1_000.25", HostValue::F32(1000.25)),
+            (F64, "-inf", HostValue::F64(f64::NEG_INFINITY)),
+        ] {
+            assert_eq!(parse(type_tag, word), Ok(value), "{}", word);
+        }
+        assert!(matches!(parse(F32, "nan"), Ok(HostValue::F32(value)) if value.is_nan()));
+
+        for (type_tag, word, message) in [
+            (U8, "256", "256 is out of range for u8 (0 to 255)"),
+            (I8, "0x80", "0x80 is out of range for i8 (-128 to 127)"),
+            (U32, "-1", "-1 is out of range for u32 (0 to 4294967295)"),
+            (U8, "'\u{20ac}'", "'\u{20ac}' is out of range for u8 (0 to 255)"),
+            (F32, "1e39", "1e39 is out of range for f32"),
+            (I32, "1__0", "1__0 is not a valid i32 literal"),
+            (I32, "_1", "_1 is not a valid i32 literal"),
+            (U8, "0x", "0x is not a valid u8 literal"),
+            (I32, "0x-1", "0x-1 is not a valid i32 literal"),
+            (U8, "'ab'", "'ab' is not a valid u8 literal"),
+            (F64, "1._5", "1._5 is not a valid f64 literal"),
+        ] {
+            assert_eq!(parse(type_tag, word), Err(message.to_owned()));
+        }
+
+        let error = assembler::assemble("push u8 1\npush u8 300").unwrap_err();
+        assert_eq!(error.to_string(), "line 2: 300 is out of range for u8 (0 to 255)");
+    }
+
+    #[test]
+    fn golden_programs_cover_every_token() {
+        let directory = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/programs");
+        let mut words = std::collections::HashSet::new();
+        for entry in std::fs::read_dir(directory).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_some_and(|extension| extension == "txt") {
+                let source = std::fs::read_to_string(path).unwrap();
+                words.extend(source.split_whitespace().map(str::to_owned));
+            }
+        }
+        let names = instructions::INSTRUCTIONS
+            .iter()
+            .map(|instruction| instruction.mnemonic)
+            .chain(instructions::TYPES.iter().map(|type_tag| type_tag.mnemonic));
+        let missing: Vec<&str> = names.filter(|name| !words.contains(*name)).collect();
+        assert!(missing.is_empty(), "No golden program uses {:?}", missing);
+    }
 }
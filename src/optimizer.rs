@@ -0,0 +1,250 @@
+use crate::bytecode::{self, Op};
+use crate::host::HostValue;
+use crate::instructions::op;
+use crate::peephole::{self, PeepholeOptions};
+
+/// `ops[start..end]`: entered only at `start`, left only after `end - 1`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct Block {
+    start: usize,
+    end: usize,
+}
+
+/// Splits `ops` at every jump target and after every jump.
+fn blocks(ops: &[Op]) -> Vec<Block> {
+    let mut leaders = vec![0];
+    for (index, op) in ops.iter().enumerate() {
+        if let Some(target) = op.jump_target() {
+            leaders.push(bytecode::resolve(ops, target));
+            leaders.push(index + 1);
+        }
+    }
+    leaders.retain(|leader| *leader < ops.len());
+    leaders.sort_unstable();
+    leaders.dedup();
+    let ends = leaders.iter().skip(1).copied().chain([ops.len()]);
+    leaders
+        .iter()
+        .zip(ends)
+        .map(|(start, end)| Block { start: *start, end })
+        .collect()
+}
+
+/// Indices of the blocks control can reach from the end of `blocks[index]`.
+/// Falling off the last block or jumping to the end of the program exits.
+fn successors(ops: &[Op], blocks: &[Block], index: usize) -> Vec<usize> {
+    let block_at = |start: usize| {
+        blocks
+            .binary_search_by_key(&start, |block| block.start)
+            .ok()
+    };
+    let last = &ops[blocks[index].end - 1];
+    let mut successors = Vec::new();
+    if last.opcode != op::Goto {
+        successors.extend(block_at(blocks[index].end));
+    }
+    if let Some(target) = last.jump_target() {
+        successors.extend(block_at(bytecode::resolve(ops, target)));
+    }
+    successors
+}
+
+/// A constant that sits on top of the stack in the block being folded but has
+/// not been written back yet, together with the instruction that pushes it.
+struct Pending {
+    value: HostValue,
+    op: Op,
+}
+
+/// Folds one basic block. Constants are only written back when an
+/// instruction needs them on the real stack, so everything computed from
+/// constants alone disappears into a single `push`.
+fn fold_block(ops: &[Op], options: &PeepholeOptions) -> Vec<Op> {
+    let mut folded = Vec::with_capacity(ops.len());
+    let mut pending: Vec<Pending> = Vec::new();
+    let flush = |pending: &mut Vec<Pending>, folded: &mut Vec<Op>| {
+        folded.extend(pending.drain(..).map(|constant| constant.op));
+    };
+    let replace = |pending: &mut Vec<Pending>, count: usize, value: HostValue| {
+        let origin = pending[pending.len() - count].op.origin;
+        pending.truncate(pending.len() - count);
+        pending.push(Pending {
+            value,
+            op: peephole::push(origin, value),
+        });
+    };
+
+    for op in ops {
+        let top = pending.last().map(|constant| constant.value);
+        let below = pending
+            .len()
+            .checked_sub(2)
+            .map(|index| pending[index].value);
+        let typed =
+            |value: Option<HostValue>| value.filter(|value| Some(value.type_tag()) == op.type_tag);
+
+        if let Some(value) = peephole::constant(op) {
+            pending.push(Pending {
+                value,
+                op: op.clone(),
+            });
+            continue;
+        }
+        if let (op::ClonePush, Some(value)) = (op.opcode, typed(top)) {
+            pending.push(Pending {
+                value,
+                op: op.clone(),
+            });
+            continue;
+        }
+
+        if options.fold_constants {
+            let result = match op.opcode {
+                op::Add
+                | op::Subtract
+                | op::Multiply
+                | op::Divide
+                | op::CompareEqual
+                | op::CompareNotEqual
+                | op::CompareGreater
+                | op::CompareGreaterEqual
+                | op::CompareLesser
+                | op::CompareLesserEqual => match (typed(below), typed(top)) {
+                    (Some(left), Some(right)) => {
+                        peephole::fold_binary(op.opcode, left, right).map(|result| (2, result))
+                    }
+                    _ => None,
+                },
+                op::AddImmediate => typed(top)
+                    .and_then(|value| peephole::fold_binary(op::Add, value, peephole::added(op)?))
+                    .map(|result| (1, result)),
+                op::TypeCast => typed(top)
+                    .and_then(|value| peephole::fold_cast(value, op.second_type_tag?))
+                    .map(|result| (1, result)),
+                op::LogicAnd | op::LogicOr => match (below, top) {
+                    (Some(HostValue::Bool(left)), Some(HostValue::Bool(right))) => {
+                        let result = if op.opcode == op::LogicAnd {
+                            left && right
+                        } else {
+                            left || right
+                        };
+                        Some((2, HostValue::Bool(result)))
+                    }
+                    _ => None,
+                },
+                op::LogicNot => match top {
+                    Some(HostValue::Bool(value)) => Some((1, HostValue::Bool(!value))),
+                    _ => None,
+                },
+                _ => None,
+            };
+            if let Some((count, result)) = result {
+                replace(&mut pending, count, result);
+                continue;
+            }
+        }
+
+        if options.simplify_jumps {
+            let condition = match op.opcode {
+                op::PopGotoIfTrue | op::PeekGotoIfTrue => match top {
+                    Some(HostValue::Bool(condition)) => Some(condition),
+                    _ => None,
+                },
+                opcode if bytecode::is_conditional_goto(opcode) => {
+                    match (typed(below), typed(top)) {
+                        (Some(left), Some(right)) => {
+                            match peephole::fold_binary(
+                                bytecode::goto_condition(opcode),
+                                left,
+                                right,
+                            ) {
+                                Some(HostValue::Bool(condition)) => Some(condition),
+                                _ => None,
+                            }
+                        }
+                        _ => None,
+                    }
+                }
+                _ => None,
+            };
+            if let Some(condition) = condition {
+                match op.opcode {
+                    op::PeekGotoIfTrue => {}
+                    op::PopGotoIfTrue => {
+                        pending.pop();
+                    }
+                    _ => {
+                        pending.truncate(pending.len() - 2);
+                    }
+                }
+                if condition {
+                    flush(&mut pending, &mut folded);
+                    let mut goto = Op::new(op.origin, op::Goto, None);
+                    goto.operand = op.operand;
+                    folded.push(goto);
+                }
+                continue;
+            }
+        }
+
+        flush(&mut pending, &mut folded);
+        folded.push(op.clone());
+    }
+    flush(&mut pending, &mut folded);
+    folded
+}
+
+/// Keeps the blocks reachable from the entry and drops every `goto` to the
+/// instruction right after it.
+fn eliminate_dead_code(ops: Vec<Op>) -> Vec<Op> {
+    let blocks = blocks(&ops);
+    let mut reachable = vec![false; blocks.len()];
+    let mut work: Vec<usize> = (0..blocks.len().min(1)).collect();
+    while let Some(index) = work.pop() {
+        if !reachable[index] {
+            reachable[index] = true;
+            work.extend(successors(&ops, &blocks, index));
+        }
+    }
+
+    let live: Vec<Op> = blocks
+        .iter()
+        .zip(reachable)
+        .filter(|(_, reachable)| *reachable)
+        .flat_map(|(block, _)| ops[block.start..block.end].iter().cloned())
+        .collect();
+    live.iter()
+        .enumerate()
+        .filter(|(index, op)| {
+            op.opcode != op::Goto
+                || op.operand.map(|target| bytecode::resolve(&live, target)) != Some(index + 1)
+        })
+        .map(|(_, op)| op.clone())
+        .collect()
+}
+
+/// Folds constant arithmetic, casts, compares and branches within each basic
+/// block, then deletes the blocks that can no longer be reached, until
+/// neither changes anything. Unlike `peephole::optimize` this follows values
+/// through a whole block, however long. Only `fold_constants` and
+/// `simplify_jumps` of `options` apply here.
+pub(crate) fn optimize(code: &[u8], options: &PeepholeOptions) -> Vec<u8> {
+    bytecode::layout(&optimize_ops(bytecode::lift(code), options))
+}
+
+/// `optimize` on `lift`ed instructions, which keeps where they came from.
+pub(crate) fn optimize_ops(mut ops: Vec<Op>, options: &PeepholeOptions) -> Vec<Op> {
+    loop {
+        let mut folded = Vec::with_capacity(ops.len());
+        for block in blocks(&ops) {
+            folded.extend(fold_block(&ops[block.start..block.end], options));
+        }
+        if options.simplify_jumps {
+            folded = eliminate_dead_code(folded);
+        }
+        if folded == ops {
+            return ops;
+        }
+        ops = folded;
+    }
+}
@@ -0,0 +1,364 @@
+use crate::bytecode::{self, Op, BOOL, F32, F64, I16, I32, I64, I8, U16, U32, U64, U8};
+use crate::host::HostValue;
+use crate::instructions::op;
+use std::collections::HashSet;
+
+/// Longest instruction sequence any rewrite looks at.
+const MAX_WINDOW: usize = 3;
+
+/// Which rewrites `optimize` applies. `Default` enables all of them.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct PeepholeOptions {
+    /// `push a push b <operation>` and `push a type_cast` become one `push`.
+    pub fold_constants: bool,
+    /// Drops `push 0 add`, `push 1 multiply`, `logic_not logic_not` and similar no-ops.
+    pub remove_identities: bool,
+    /// `clone_push T pop T` becomes `peek T`.
+    pub clone_pop_to_peek: bool,
+    /// `store T X load T X` becomes `peek_store T X`.
+    pub store_load_to_peek_store: bool,
+    /// Branches on constants become `goto` or disappear, as does a `goto` to
+    /// the next instruction.
+    pub simplify_jumps: bool,
+    /// `push c add` becomes `add_immediate c`, `compare_* pop_goto_if_true`
+    /// becomes `goto_if_*` and `load X add_immediate c store X` becomes
+    /// `increment X c`.
+    pub superinstructions: bool,
+}
+
+impl Default for PeepholeOptions {
+    fn default() -> PeepholeOptions {
+        PeepholeOptions {
+            fold_constants: true,
+            remove_identities: true,
+            clone_pop_to_peek: true,
+            store_load_to_peek_store: true,
+            simplify_jumps: true,
+            superinstructions: true,
+        }
+    }
+}
+
+impl PeepholeOptions {
+    pub(crate) const NAMES: [&'static str; 6] = [
+        "fold-constants",
+        "remove-identities",
+        "clone-pop-to-peek",
+        "store-load-to-peek-store",
+        "simplify-jumps",
+        "superinstructions",
+    ];
+
+    /// Turns the optimization called `name` (one of `NAMES`) on or off.
+    /// Returns `false` for unknown names.
+    pub(crate) fn set(&mut self, name: &str, enabled: bool) -> bool {
+        let option = match name {
+            "fold-constants" => &mut self.fold_constants,
+            "remove-identities" => &mut self.remove_identities,
+            "clone-pop-to-peek" => &mut self.clone_pop_to_peek,
+            "store-load-to-peek-store" => &mut self.store_load_to_peek_store,
+            "simplify-jumps" => &mut self.simplify_jumps,
+            "superinstructions" => &mut self.superinstructions,
+            _ => return false,
+        };
+        *option = enabled;
+        true
+    }
+}
+
+fn compare<T: PartialOrd>(opcode: u8, left: T, right: T) -> Option<HostValue> {
+    let result = match opcode {
+        op::CompareEqual => left == right,
+        op::CompareNotEqual => left != right,
+        op::CompareGreater => left > right,
+        op::CompareGreaterEqual => left >= right,
+        op::CompareLesser => left < right,
+        op::CompareLesserEqual => left <= right,
+        _ => return None,
+    };
+    Some(HostValue::Bool(result))
+}
+
+/// Computes `left <opcode> right` at compile time. Integer operations that
+/// would overflow or divide by zero are left for run time.
+pub(crate) fn fold_binary(opcode: u8, left: HostValue, right: HostValue) -> Option<HostValue> {
+    macro_rules! integer {
+        ($left:expr, $right:expr, $variant:ident) => {
+            match opcode {
+                op::Add => $left.checked_add($right).map(HostValue::$variant),
+                op::Subtract => $left.checked_sub($right).map(HostValue::$variant),
+                op::Multiply => $left.checked_mul($right).map(HostValue::$variant),
+                op::Divide => $left.checked_div($right).map(HostValue::$variant),
+                _ => compare(opcode, $left, $right),
+            }
+        };
+    }
+    macro_rules! float {
+        ($left:expr, $right:expr, $variant:ident) => {
+            match opcode {
+                op::Add => Some(HostValue::$variant($left + $right)),
+                op::Subtract => Some(HostValue::$variant($left - $right)),
+                op::Multiply => Some(HostValue::$variant($left * $right)),
+                op::Divide => Some(HostValue::$variant($left / $right)),
+                _ => compare(opcode, $left, $right),
+            }
+        };
+    }
+    match (left, right) {
+        (HostValue::I8(left), HostValue::I8(right)) => integer!(left, right, I8),
+        (HostValue::I16(left), HostValue::I16(right)) => integer!(left, right, I16),
+        (HostValue::I32(left), HostValue::I32(right)) => integer!(left, right, I32),
+        (HostValue::I64(left), HostValue::I64(right)) => integer!(left, right, I64),
+        (HostValue::U8(left), HostValue::U8(right)) => integer!(left, right, U8),
+        (HostValue::U16(left), HostValue::U16(right)) => integer!(left, right, U16),
+        (HostValue::U32(left), HostValue::U32(right)) => integer!(left, right, U32),
+        (HostValue::U64(left), HostValue::U64(right)) => integer!(left, right, U64),
+        (HostValue::F32(left), HostValue::F32(right)) => float!(left, right, F32),
+        (HostValue::F64(left), HostValue::F64(right)) => float!(left, right, F64),
+        _ => None,
+    }
+}
+
+/// `value as <to>`, with the same semantics as `type_cast`.
+pub(crate) fn fold_cast(value: HostValue, to: u8) -> Option<HostValue> {
+    macro_rules! cast {
+        ($value:expr) => {
+            match to {
+                I8 => HostValue::I8($value as i8),
+                I16 => HostValue::I16($value as i16),
+                I32 => HostValue::I32($value as i32),
+                I64 => HostValue::I64($value as i64),
+                U8 => HostValue::U8($value as u8),
+                U16 => HostValue::U16($value as u16),
+                U32 => HostValue::U32($value as u32),
+                U64 => HostValue::U64($value as u64),
+                F32 => HostValue::F32($value as f32),
+                F64 => HostValue::F64($value as f64),
+                _ => return None,
+            }
+        };
+    }
+    Some(match value {
+        HostValue::Bool(_) => return None,
+        HostValue::I8(value) => cast!(value),
+        HostValue::I16(value) => cast!(value),
+        HostValue::I32(value) => cast!(value),
+        HostValue::I64(value) => cast!(value),
+        HostValue::U8(value) => cast!(value),
+        HostValue::U16(value) => cast!(value),
+        HostValue::U32(value) => cast!(value),
+        HostValue::U64(value) => cast!(value),
+        HostValue::F32(value) => cast!(value),
+        HostValue::F64(value) => cast!(value),
+    })
+}
+
+/// The constant `op` pushes, if it is a `push`.
+pub(crate) fn constant(op: &Op) -> Option<HostValue> {
+    if op.opcode != op::Push {
+        return None;
+    }
+    Some(HostValue::from_le_bytes(op.type_tag?, &op.immediate))
+}
+
+/// The amount `op` adds, if it is an `add_immediate`.
+pub(crate) fn added(op: &Op) -> Option<HostValue> {
+    if op.opcode != op::AddImmediate {
+        return None;
+    }
+    Some(HostValue::from_le_bytes(op.type_tag?, &op.immediate))
+}
+
+pub(crate) fn push(origin: usize, value: HostValue) -> Op {
+    Op::push(origin, value.type_tag(), value.to_le_bytes())
+}
+
+/// Whether `<op> T` with `value` as its right operand leaves the left operand
+/// unchanged. Adding zero is not an identity for floats: `-0.0 + 0.0` is `0.0`.
+fn is_identity(opcode: u8, value: HostValue) -> bool {
+    let type_tag = value.type_tag();
+    let is_zero = value.to_le_bytes().iter().all(|byte| *byte == 0);
+    let is_one = fold_cast(HostValue::U8(1), type_tag) == Some(value);
+    match opcode {
+        op::Add => is_zero && type_tag != F32 && type_tag != F64,
+        op::Subtract => is_zero,
+        op::Multiply | op::Divide => is_one,
+        op::LogicAnd => value == HostValue::Bool(true),
+        op::LogicOr => value == HostValue::Bool(false),
+        _ => false,
+    }
+}
+
+/// Tries every enabled rewrite on the instructions starting at `ops[index]`,
+/// looking at no more than `window` of them. Returns how many instructions
+/// were matched and what replaces them.
+fn rewrite(
+    ops: &[Op],
+    index: usize,
+    window: usize,
+    options: &PeepholeOptions,
+) -> Option<(usize, Vec<Op>)> {
+    let first = &ops[index];
+    let second = ops.get(index + 1).filter(|_| window >= 2);
+    let third = ops.get(index + 2).filter(|_| window >= 3);
+    let origin = first.origin;
+
+    if options.fold_constants {
+        if let (Some(left), Some(second), Some(operation)) = (constant(first), second, third) {
+            if let Some(right) = constant(second) {
+                if left.type_tag() == right.type_tag() && operation.type_tag == second.type_tag {
+                    if let Some(result) = fold_binary(operation.opcode, left, right) {
+                        return Some((3, vec![push(origin, result)]));
+                    }
+                }
+            }
+        }
+        if let (Some(left), Some(right)) = (constant(first), second.and_then(added)) {
+            if let Some(result) = fold_binary(op::Add, left, right) {
+                return Some((2, vec![push(origin, result)]));
+            }
+        }
+        if let (Some(value), Some(cast)) = (constant(first), second) {
+            if cast.opcode == op::TypeCast && cast.type_tag == first.type_tag {
+                if let Some(result) = fold_cast(value, cast.second_type_tag?) {
+                    return Some((2, vec![push(origin, result)]));
+                }
+            }
+        }
+    }
+
+    if options.remove_identities {
+        if let (Some(value), Some(operation)) = (constant(first), second) {
+            let typed = operation.type_tag == first.type_tag || operation.type_tag.is_none();
+            if typed && is_identity(operation.opcode, value) {
+                return Some((2, Vec::new()));
+            }
+        }
+        if let Some(second) = second {
+            if first.opcode == op::LogicNot && second.opcode == op::LogicNot {
+                return Some((2, Vec::new()));
+            }
+        }
+        if let Some(value) = added(first) {
+            if is_identity(op::Add, value) {
+                return Some((1, Vec::new()));
+            }
+        }
+    }
+
+    if options.clone_pop_to_peek {
+        if let Some(second) = second {
+            if first.opcode == op::ClonePush
+                && second.opcode == op::Pop
+                && first.type_tag == second.type_tag
+            {
+                return Some((2, vec![Op::new(origin, op::Peek, first.type_tag)]));
+            }
+        }
+    }
+
+    if options.store_load_to_peek_store {
+        if let Some(second) = second {
+            if first.opcode == op::Store
+                && second.opcode == op::Load
+                && first.type_tag == second.type_tag
+                && first.operand == second.operand
+            {
+                let mut peek_store = Op::new(origin, op::PeekStore, first.type_tag);
+                peek_store.operand = first.operand;
+                return Some((2, vec![peek_store]));
+            }
+        }
+    }
+
+    if options.simplify_jumps {
+        if let (Some(HostValue::Bool(condition)), Some(branch)) = (constant(first), second) {
+            if branch.opcode == op::PopGotoIfTrue {
+                if !condition {
+                    return Some((2, Vec::new()));
+                }
+                let mut goto = Op::new(origin, op::Goto, None);
+                goto.operand = branch.operand;
+                return Some((2, vec![goto]));
+            }
+        }
+        if first.opcode == op::Goto && bytecode::resolve(ops, first.operand?) == index + 1 {
+            return Some((1, Vec::new()));
+        }
+    }
+
+    if options.superinstructions {
+        if let (Some(amount), Some(store)) = (second, third) {
+            if first.opcode == op::Load
+                && store.opcode == op::Store
+                && added(amount).is_some()
+                && first.type_tag == amount.type_tag
+                && first.type_tag == store.type_tag
+                && first.operand == store.operand
+            {
+                let mut increment = Op::new(origin, op::Increment, first.type_tag);
+                increment.operand = first.operand;
+                increment.immediate = amount.immediate.clone();
+                return Some((3, vec![increment]));
+            }
+        }
+        if let (Some(value), Some(add)) = (constant(first), second) {
+            if add.opcode == op::Add && add.type_tag == first.type_tag && value.type_tag() != BOOL {
+                let mut add_immediate = Op::new(origin, op::AddImmediate, first.type_tag);
+                add_immediate.immediate = first.immediate.clone();
+                return Some((2, vec![add_immediate]));
+            }
+        }
+        if let Some(branch) = second {
+            let fused = bytecode::fused_goto(first.opcode);
+            if let (Some(opcode), op::PopGotoIfTrue) = (fused, branch.opcode) {
+                let mut goto_if = Op::new(origin, opcode, first.type_tag);
+                goto_if.operand = branch.operand;
+                return Some((2, vec![goto_if]));
+            }
+        }
+    }
+    None
+}
+
+/// Rewrites `code` into an equivalent, cheaper program until no enabled
+/// rewrite applies any more, and fixes up every jump for the new offsets.
+/// A rewrite never spans a jump target, so every jump still lands where it did.
+pub(crate) fn optimize(code: &[u8], options: &PeepholeOptions) -> Vec<u8> {
+    bytecode::layout(&optimize_ops(bytecode::lift(code), options))
+}
+
+/// `optimize` on `lift`ed instructions, which keeps where they came from.
+pub(crate) fn optimize_ops(mut ops: Vec<Op>, options: &PeepholeOptions) -> Vec<Op> {
+    loop {
+        let targets: HashSet<usize> = ops
+            .iter()
+            .filter_map(Op::jump_target)
+            .map(|target| bytecode::resolve(&ops, target))
+            .collect();
+        let mut changed = false;
+        let mut optimized = Vec::with_capacity(ops.len());
+        let mut index = 0;
+        while index < ops.len() {
+            let window = 1
+                + (index + 1..ops.len().min(index + MAX_WINDOW))
+                    .take_while(|next| !targets.contains(next))
+                    .count();
+            match rewrite(&ops, index, window, options) {
+                Some((matched, replacement)) => {
+                    optimized.extend(replacement);
+                    index += matched;
+                    changed = true;
+                }
+                None => {
+                    optimized.push(ops[index].clone());
+                    index += 1;
+                }
+            }
+        }
+        ops = optimized;
+        if !changed {
+            return ops;
+        }
+    }
+}
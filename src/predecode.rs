@@ -0,0 +1,782 @@
+use crate::bytecode::{self, Decoded};
+use crate::instructions::{self, row, Operand};
+use crate::{Buffer, BufferArray, StackMachine, StackUpperVector};
+use num::cast::AsPrimitive;
+
+/// Runs one instruction and returns the index of the next one.
+pub(crate) type Handler = fn(&mut StackUpperVector, u64, usize) -> usize;
+
+/// One instruction with its opcode and type tags already resolved, so that
+/// running it is a single `match` on `kind`. Jump operands are indices into
+/// `PredecodedProgram::instructions`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) struct Instruction {
+    pub kind: Kind,
+    /// A pushed value as `pack` lays it out, a buffer address, a jump index
+    /// or a host function id.
+    pub operand: u64,
+}
+
+/// `token_byte_sequence` translated once at load time.
+pub(crate) struct PredecodedProgram {
+    pub instructions: Vec<Instruction>,
+    /// Byte offset of every instruction, plus the end of the program.
+    offsets: Vec<usize>,
+    /// Operands of `increment`s. Only kept alive here, the handler reads
+    /// them through pointers.
+    _increments: Vec<(usize, u64)>,
+}
+
+fn unpack<T>(operand: u64) -> T {
+    let bytes = operand.to_ne_bytes();
+    unsafe { (bytes.as_ptr() as *const T).read_unaligned() }
+}
+
+fn push<T>(vm: &mut StackUpperVector, operand: u64, next: usize) -> usize {
+    vm.lower_stack.push::<T>(unpack(operand));
+    next
+}
+fn pop<T: std::fmt::Display>(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.pop::<T>();
+    next
+}
+fn peek<T: std::fmt::Display>(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.peek::<T>();
+    next
+}
+fn clone_push<T>(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.clone_push::<T>();
+    next
+}
+fn add<T: std::ops::AddAssign>(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.lower_stack.add::<T>();
+    next
+}
+fn subtract<T: std::ops::SubAssign>(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.lower_stack.subtract::<T>();
+    next
+}
+fn multiply<T: std::ops::MulAssign>(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.lower_stack.multiply::<T>();
+    next
+}
+fn divide<T: std::ops::DivAssign>(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.lower_stack.divide::<T>();
+    next
+}
+fn store<T>(vm: &mut StackUpperVector, operand: u64, next: usize) -> usize {
+    vm.lower_stack
+        .store::<T, BufferArray>(&mut vm.buffer, operand as usize);
+    next
+}
+fn peek_store<T>(vm: &mut StackUpperVector, operand: u64, next: usize) -> usize {
+    vm.lower_stack
+        .peek_store::<T, BufferArray>(&mut vm.buffer, operand as usize);
+    next
+}
+fn load<T>(vm: &mut StackUpperVector, operand: u64, next: usize) -> usize {
+    vm.lower_stack
+        .push::<T>(vm.buffer.load::<T>(operand as usize));
+    next
+}
+fn goto(_: &mut StackUpperVector, operand: u64, _: usize) -> usize {
+    operand as usize
+}
+fn pop_goto_if_true(vm: &mut StackUpperVector, operand: u64, next: usize) -> usize {
+    if vm.lower_stack.pop::<bool>() {
+        operand as usize
+    } else {
+        next
+    }
+}
+fn peek_goto_if_true(vm: &mut StackUpperVector, operand: u64, next: usize) -> usize {
+    if vm.lower_stack.peek::<bool>() {
+        operand as usize
+    } else {
+        next
+    }
+}
+fn logic_and(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.lower_stack.logic_and();
+    next
+}
+fn logic_or(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.lower_stack.logic_or();
+    next
+}
+fn logic_not(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.lower_stack.logic_not();
+    next
+}
+fn compare_equal<T: std::cmp::PartialOrd>(vm: &mut StackUpperVector, _: u64, next: usize) -> usize {
+    vm.lower_stack.compare_equal::<T>();
+    next
+}
+fn compare_not_equal<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    _: u64,
+    next: usize,
+) -> usize {
+    vm.lower_stack.compare_not_equal::<T>();
+    next
+}
+fn compare_greater<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    _: u64,
+    next: usize,
+) -> usize {
+    vm.lower_stack.compare_greater::<T>();
+    next
+}
+fn compare_greater_equal<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    _: u64,
+    next: usize,
+) -> usize {
+    vm.lower_stack.compare_greater_equal::<T>();
+    next
+}
+fn compare_lesser<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    _: u64,
+    next: usize,
+) -> usize {
+    vm.lower_stack.compare_lesser::<T>();
+    next
+}
+fn compare_lesser_equal<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    _: u64,
+    next: usize,
+) -> usize {
+    vm.lower_stack.compare_lesser_equal::<T>();
+    next
+}
+fn type_cast<From: AsPrimitive<To>, To: 'static + Copy>(
+    vm: &mut StackUpperVector,
+    _: u64,
+    next: usize,
+) -> usize {
+    vm.lower_stack.cast_from_to::<From, To>();
+    next
+}
+fn call_host(vm: &mut StackUpperVector, operand: u64, next: usize) -> usize {
+    vm.call_host(operand as u32);
+    next
+}
+fn add_immediate<T: std::ops::AddAssign>(
+    vm: &mut StackUpperVector,
+    operand: u64,
+    next: usize,
+) -> usize {
+    vm.lower_stack.add_immediate::<T>(unpack(operand));
+    next
+}
+fn goto_if_equal<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    operand: u64,
+    next: usize,
+) -> usize {
+    if vm.lower_stack.pop_compare::<T>(T::eq) {
+        operand as usize
+    } else {
+        next
+    }
+}
+fn goto_if_not_equal<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    operand: u64,
+    next: usize,
+) -> usize {
+    if vm.lower_stack.pop_compare::<T>(T::ne) {
+        operand as usize
+    } else {
+        next
+    }
+}
+fn goto_if_greater<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    operand: u64,
+    next: usize,
+) -> usize {
+    if vm.lower_stack.pop_compare::<T>(T::gt) {
+        operand as usize
+    } else {
+        next
+    }
+}
+fn goto_if_greater_equal<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    operand: u64,
+    next: usize,
+) -> usize {
+    if vm.lower_stack.pop_compare::<T>(T::ge) {
+        operand as usize
+    } else {
+        next
+    }
+}
+fn goto_if_lesser<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    operand: u64,
+    next: usize,
+) -> usize {
+    if vm.lower_stack.pop_compare::<T>(T::lt) {
+        operand as usize
+    } else {
+        next
+    }
+}
+fn goto_if_lesser_equal<T: std::cmp::PartialOrd>(
+    vm: &mut StackUpperVector,
+    operand: u64,
+    next: usize,
+) -> usize {
+    if vm.lower_stack.pop_compare::<T>(T::le) {
+        operand as usize
+    } else {
+        next
+    }
+}
+/// `increment` has two immediates, so its operand points at an
+/// `(address, amount)` pair owned by the `PredecodedProgram`.
+fn increment<T: std::ops::AddAssign>(
+    vm: &mut StackUpperVector,
+    operand: u64,
+    next: usize,
+) -> usize {
+    let (address, amount) = unsafe { *(operand as *const (usize, u64)) };
+    vm.buffer.increment::<T>(address, unpack(amount));
+    next
+}
+
+/// Finds the `Kind` of an instruction from its `row` type and the Rust types
+/// of its type tags.
+trait Specialized {
+    const KIND: Kind;
+}
+
+/// Generates `Kind` from the specializations below, the `match` that runs
+/// them, the handler table of `ThreadedProgram` and the `Specialized` impls
+/// `translate` finds them through, so a row or type missing here does not
+/// compile.
+macro_rules! specializations {
+    ($($kind:ident = $mnemonic:ident($($T:ty),*);)*) => {
+        /// Every instruction with every type it takes, each one its own variant.
+        #[derive(Debug, Clone, Copy, PartialEq)]
+        pub(crate) enum Kind {
+            $($kind,)*
+        }
+
+        $(impl Specialized for (row::$mnemonic, ($($T,)*)) {
+            const KIND: Kind = Kind::$kind;
+        })*
+
+        impl Kind {
+            #[inline(always)]
+            fn run(self, vm: &mut StackUpperVector, operand: u64, next: usize) -> usize {
+                match self {
+                    $(Kind::$kind => $mnemonic::<$($T),*>(vm, operand, next),)*
+                }
+            }
+
+            pub(crate) fn handler(self) -> Handler {
+                match self {
+                    $(Kind::$kind => $mnemonic::<$($T),*>,)*
+                }
+            }
+        }
+    };
+}
+
+specializations! {
+    PushBool = push(bool);
+    PushI8 = push(i8);
+    PushI16 = push(i16);
+    PushI32 = push(i32);
+    PushI64 = push(i64);
+    PushU8 = push(u8);
+    PushU16 = push(u16);
+    PushU32 = push(u32);
+    PushU64 = push(u64);
+    PushF32 = push(f32);
+    PushF64 = push(f64);
+    PopBool = pop(bool);
+    PopI8 = pop(i8);
+    PopI16 = pop(i16);
+    PopI32 = pop(i32);
+    PopI64 = pop(i64);
+    PopU8 = pop(u8);
+    PopU16 = pop(u16);
+    PopU32 = pop(u32);
+    PopU64 = pop(u64);
+    PopF32 = pop(f32);
+    PopF64 = pop(f64);
+    PeekBool = peek(bool);
+    PeekI8 = peek(i8);
+    PeekI16 = peek(i16);
+    PeekI32 = peek(i32);
+    PeekI64 = peek(i64);
+    PeekU8 = peek(u8);
+    PeekU16 = peek(u16);
+    PeekU32 = peek(u32);
+    PeekU64 = peek(u64);
+    PeekF32 = peek(f32);
+    PeekF64 = peek(f64);
+    ClonePushBool = clone_push(bool);
+    ClonePushI8 = clone_push(i8);
+    ClonePushI16 = clone_push(i16);
+    ClonePushI32 = clone_push(i32);
+    ClonePushI64 = clone_push(i64);
+    ClonePushU8 = clone_push(u8);
+    ClonePushU16 = clone_push(u16);
+    ClonePushU32 = clone_push(u32);
+    ClonePushU64 = clone_push(u64);
+    ClonePushF32 = clone_push(f32);
+    ClonePushF64 = clone_push(f64);
+    AddI8 = add(i8);
+    AddI16 = add(i16);
+    AddI32 = add(i32);
+    AddI64 = add(i64);
+    AddU8 = add(u8);
+    AddU16 = add(u16);
+    AddU32 = add(u32);
+    AddU64 = add(u64);
+    AddF32 = add(f32);
+    AddF64 = add(f64);
+    SubtractI8 = subtract(i8);
+    SubtractI16 = subtract(i16);
+    SubtractI32 = subtract(i32);
+    SubtractI64 = subtract(i64);
+    SubtractU8 = subtract(u8);
+    SubtractU16 = subtract(u16);
+    SubtractU32 = subtract(u32);
+    SubtractU64 = subtract(u64);
+    SubtractF32 = subtract(f32);
+    SubtractF64 = subtract(f64);
+    MultiplyI8 = multiply(i8);
+    MultiplyI16 = multiply(i16);
+    MultiplyI32 = multiply(i32);
+    MultiplyI64 = multiply(i64);
+    MultiplyU8 = multiply(u8);
+    MultiplyU16 = multiply(u16);
+    MultiplyU32 = multiply(u32);
+    MultiplyU64 = multiply(u64);
+    MultiplyF32 = multiply(f32);
+    MultiplyF64 = multiply(f64);
+    DivideI8 = divide(i8);
+    DivideI16 = divide(i16);
+    DivideI32 = divide(i32);
+    DivideI64 = divide(i64);
+    DivideU8 = divide(u8);
+    DivideU16 = divide(u16);
+    DivideU32 = divide(u32);
+    DivideU64 = divide(u64);
+    DivideF32 = divide(f32);
+    DivideF64 = divide(f64);
+    StoreBool = store(bool);
+    StoreI8 = store(i8);
+    StoreI16 = store(i16);
+    StoreI32 = store(i32);
+    StoreI64 = store(i64);
+    StoreU8 = store(u8);
+    StoreU16 = store(u16);
+    StoreU32 = store(u32);
+    StoreU64 = store(u64);
+    StoreF32 = store(f32);
+    StoreF64 = store(f64);
+    PeekStoreBool = peek_store(bool);
+    PeekStoreI8 = peek_store(i8);
+    PeekStoreI16 = peek_store(i16);
+    PeekStoreI32 = peek_store(i32);
+    PeekStoreI64 = peek_store(i64);
+    PeekStoreU8 = peek_store(u8);
+    PeekStoreU16 = peek_store(u16);
+    PeekStoreU32 = peek_store(u32);
+    PeekStoreU64 = peek_store(u64);
+    PeekStoreF32 = peek_store(f32);
+    PeekStoreF64 = peek_store(f64);
+    LoadBool = load(bool);
+    LoadI8 = load(i8);
+    LoadI16 = load(i16);
+    LoadI32 = load(i32);
+    LoadI64 = load(i64);
+    LoadU8 = load(u8);
+    LoadU16 = load(u16);
+    LoadU32 = load(u32);
+    LoadU64 = load(u64);
+    LoadF32 = load(f32);
+    LoadF64 = load(f64);
+    Goto = goto();
+    PopGotoIfTrue = pop_goto_if_true();
+    PeekGotoIfTrue = peek_goto_if_true();
+    LogicAnd = logic_and();
+    LogicOr = logic_or();
+    LogicNot = logic_not();
+    CompareEqualI8 = compare_equal(i8);
+    CompareEqualI16 = compare_equal(i16);
+    CompareEqualI32 = compare_equal(i32);
+    CompareEqualI64 = compare_equal(i64);
+    CompareEqualU8 = compare_equal(u8);
+    CompareEqualU16 = compare_equal(u16);
+    CompareEqualU32 = compare_equal(u32);
+    CompareEqualU64 = compare_equal(u64);
+    CompareEqualF32 = compare_equal(f32);
+    CompareEqualF64 = compare_equal(f64);
+    CompareNotEqualI8 = compare_not_equal(i8);
+    CompareNotEqualI16 = compare_not_equal(i16);
+    CompareNotEqualI32 = compare_not_equal(i32);
+    CompareNotEqualI64 = compare_not_equal(i64);
+    CompareNotEqualU8 = compare_not_equal(u8);
+    CompareNotEqualU16 = compare_not_equal(u16);
+    CompareNotEqualU32 = compare_not_equal(u32);
+    CompareNotEqualU64 = compare_not_equal(u64);
+    CompareNotEqualF32 = compare_not_equal(f32);
+    CompareNotEqualF64 = compare_not_equal(f64);
+    CompareGreaterI8 = compare_greater(i8);
+    CompareGreaterI16 = compare_greater(i16);
+    CompareGreaterI32 = compare_greater(i32);
+    CompareGreaterI64 = compare_greater(i64);
+    CompareGreaterU8 = compare_greater(u8);
+    CompareGreaterU16 = compare_greater(u16);
+    CompareGreaterU32 = compare_greater(u32);
+    CompareGreaterU64 = compare_greater(u64);
+    CompareGreaterF32 = compare_greater(f32);
+    CompareGreaterF64 = compare_greater(f64);
+    CompareGreaterEqualI8 = compare_greater_equal(i8);
+    CompareGreaterEqualI16 = compare_greater_equal(i16);
+    CompareGreaterEqualI32 = compare_greater_equal(i32);
+    CompareGreaterEqualI64 = compare_greater_equal(i64);
+    CompareGreaterEqualU8 = compare_greater_equal(u8);
+    CompareGreaterEqualU16 = compare_greater_equal(u16);
+    CompareGreaterEqualU32 = compare_greater_equal(u32);
+    CompareGreaterEqualU64 = compare_greater_equal(u64);
+    CompareGreaterEqualF32 = compare_greater_equal(f32);
+    CompareGreaterEqualF64 = compare_greater_equal(f64);
+    CompareLesserI8 = compare_lesser(i8);
+    CompareLesserI16 = compare_lesser(i16);
+    CompareLesserI32 = compare_lesser(i32);
+    CompareLesserI64 = compare_lesser(i64);
+    CompareLesserU8 = compare_lesser(u8);
+    CompareLesserU16 = compare_lesser(u16);
+    CompareLesserU32 = compare_lesser(u32);
+    CompareLesserU64 = compare_lesser(u64);
+    CompareLesserF32 = compare_lesser(f32);
+    CompareLesserF64 = compare_lesser(f64);
+    CompareLesserEqualI8 = compare_lesser_equal(i8);
+    CompareLesserEqualI16 = compare_lesser_equal(i16);
+    CompareLesserEqualI32 = compare_lesser_equal(i32);
+    CompareLesserEqualI64 = compare_lesser_equal(i64);
+    CompareLesserEqualU8 = compare_lesser_equal(u8);
+    CompareLesserEqualU16 = compare_lesser_equal(u16);
+    CompareLesserEqualU32 = compare_lesser_equal(u32);
+    CompareLesserEqualU64 = compare_lesser_equal(u64);
+    CompareLesserEqualF32 = compare_lesser_equal(f32);
+    CompareLesserEqualF64 = compare_lesser_equal(f64);
+    CastI8ToI8 = type_cast(i8, i8);
+    CastI8ToI16 = type_cast(i8, i16);
+    CastI8ToI32 = type_cast(i8, i32);
+    CastI8ToI64 = type_cast(i8, i64);
+    CastI8ToU8 = type_cast(i8, u8);
+    CastI8ToU16 = type_cast(i8, u16);
+    CastI8ToU32 = type_cast(i8, u32);
+    CastI8ToU64 = type_cast(i8, u64);
+    CastI8ToF32 = type_cast(i8, f32);
+    CastI8ToF64 = type_cast(i8, f64);
+    CastI16ToI8 = type_cast(i16, i8);
+    CastI16ToI16 = type_cast(i16, i16);
+    CastI16ToI32 = type_cast(i16, i32);
+    CastI16ToI64 = type_cast(i16, i64);
+    CastI16ToU8 = type_cast(i16, u8);
+    CastI16ToU16 = type_cast(i16, u16);
+    CastI16ToU32 = type_cast(i16, u32);
+    CastI16ToU64 = type_cast(i16, u64);
+    CastI16ToF32 = type_cast(i16, f32);
+    CastI16ToF64 = type_cast(i16, f64);
+    CastI32ToI8 = type_cast(i32, i8);
+    CastI32ToI16 = type_cast(i32, i16);
+    CastI32ToI32 = type_cast(i32, i32);
+    CastI32ToI64 = type_cast(i32, i64);
+    CastI32ToU8 = type_cast(i32, u8);
+    CastI32ToU16 = type_cast(i32, u16);
+    CastI32ToU32 = type_cast(i32, u32);
+    CastI32ToU64 = type_cast(i32, u64);
+    CastI32ToF32 = type_cast(i32, f32);
+    CastI32ToF64 = type_cast(i32, f64);
+    CastI64ToI8 = type_cast(i64, i8);
+    CastI64ToI16 = type_cast(i64, i16);
+    CastI64ToI32 = type_cast(i64, i32);
+    CastI64ToI64 = type_cast(i64, i64);
+    CastI64ToU8 = type_cast(i64, u8);
+    CastI64ToU16 = type_cast(i64, u16);
+    CastI64ToU32 = type_cast(i64, u32);
+    CastI64ToU64 = type_cast(i64, u64);
+    CastI64ToF32 = type_cast(i64, f32);
+    CastI64ToF64 = type_cast(i64, f64);
+    CastU8ToI8 = type_cast(u8, i8);
+    CastU8ToI16 = type_cast(u8, i16);
+    CastU8ToI32 = type_cast(u8, i32);
+    CastU8ToI64 = type_cast(u8, i64);
+    CastU8ToU8 = type_cast(u8, u8);
+    CastU8ToU16 = type_cast(u8, u16);
+    CastU8ToU32 = type_cast(u8, u32);
+    CastU8ToU64 = type_cast(u8, u64);
+    CastU8ToF32 = type_cast(u8, f32);
+    CastU8ToF64 = type_cast(u8, f64);
+    CastU16ToI8 = type_cast(u16, i8);
+    CastU16ToI16 = type_cast(u16, i16);
+    CastU16ToI32 = type_cast(u16, i32);
+    CastU16ToI64 = type_cast(u16, i64);
+    CastU16ToU8 = type_cast(u16, u8);
+    CastU16ToU16 = type_cast(u16, u16);
+    CastU16ToU32 = type_cast(u16, u32);
+    CastU16ToU64 = type_cast(u16, u64);
+    CastU16ToF32 = type_cast(u16, f32);
+    CastU16ToF64 = type_cast(u16, f64);
+    CastU32ToI8 = type_cast(u32, i8);
+    CastU32ToI16 = type_cast(u32, i16);
+    CastU32ToI32 = type_cast(u32, i32);
+    CastU32ToI64 = type_cast(u32, i64);
+    CastU32ToU8 = type_cast(u32, u8);
+    CastU32ToU16 = type_cast(u32, u16);
+    CastU32ToU32 = type_cast(u32, u32);
+    CastU32ToU64 = type_cast(u32, u64);
+    CastU32ToF32 = type_cast(u32, f32);
+    CastU32ToF64 = type_cast(u32, f64);
+    CastU64ToI8 = type_cast(u64, i8);
+    CastU64ToI16 = type_cast(u64, i16);
+    CastU64ToI32 = type_cast(u64, i32);
+    CastU64ToI64 = type_cast(u64, i64);
+    CastU64ToU8 = type_cast(u64, u8);
+    CastU64ToU16 = type_cast(u64, u16);
+    CastU64ToU32 = type_cast(u64, u32);
+    CastU64ToU64 = type_cast(u64, u64);
+    CastU64ToF32 = type_cast(u64, f32);
+    CastU64ToF64 = type_cast(u64, f64);
+    CastF32ToI8 = type_cast(f32, i8);
+    CastF32ToI16 = type_cast(f32, i16);
+    CastF32ToI32 = type_cast(f32, i32);
+    CastF32ToI64 = type_cast(f32, i64);
+    CastF32ToU8 = type_cast(f32, u8);
+    CastF32ToU16 = type_cast(f32, u16);
+    CastF32ToU32 = type_cast(f32, u32);
+    CastF32ToU64 = type_cast(f32, u64);
+    CastF32ToF32 = type_cast(f32, f32);
+    CastF32ToF64 = type_cast(f32, f64);
+    CastF64ToI8 = type_cast(f64, i8);
+    CastF64ToI16 = type_cast(f64, i16);
+    CastF64ToI32 = type_cast(f64, i32);
+    CastF64ToI64 = type_cast(f64, i64);
+    CastF64ToU8 = type_cast(f64, u8);
+    CastF64ToU16 = type_cast(f64, u16);
+    CastF64ToU32 = type_cast(f64, u32);
+    CastF64ToU64 = type_cast(f64, u64);
+    CastF64ToF32 = type_cast(f64, f32);
+    CastF64ToF64 = type_cast(f64, f64);
+    CallHost = call_host();
+    AddImmediateI8 = add_immediate(i8);
+    AddImmediateI16 = add_immediate(i16);
+    AddImmediateI32 = add_immediate(i32);
+    AddImmediateI64 = add_immediate(i64);
+    AddImmediateU8 = add_immediate(u8);
+    AddImmediateU16 = add_immediate(u16);
+    AddImmediateU32 = add_immediate(u32);
+    AddImmediateU64 = add_immediate(u64);
+    AddImmediateF32 = add_immediate(f32);
+    AddImmediateF64 = add_immediate(f64);
+    GotoIfEqualI8 = goto_if_equal(i8);
+    GotoIfEqualI16 = goto_if_equal(i16);
+    GotoIfEqualI32 = goto_if_equal(i32);
+    GotoIfEqualI64 = goto_if_equal(i64);
+    GotoIfEqualU8 = goto_if_equal(u8);
+    GotoIfEqualU16 = goto_if_equal(u16);
+    GotoIfEqualU32 = goto_if_equal(u32);
+    GotoIfEqualU64 = goto_if_equal(u64);
+    GotoIfEqualF32 = goto_if_equal(f32);
+    GotoIfEqualF64 = goto_if_equal(f64);
+    GotoIfNotEqualI8 = goto_if_not_equal(i8);
+    GotoIfNotEqualI16 = goto_if_not_equal(i16);
+    GotoIfNotEqualI32 = goto_if_not_equal(i32);
+    GotoIfNotEqualI64 = goto_if_not_equal(i64);
+    GotoIfNotEqualU8 = goto_if_not_equal(u8);
+    GotoIfNotEqualU16 = goto_if_not_equal(u16);
+    GotoIfNotEqualU32 = goto_if_not_equal(u32);
+    GotoIfNotEqualU64 = goto_if_not_equal(u64);
+    GotoIfNotEqualF32 = goto_if_not_equal(f32);
+    GotoIfNotEqualF64 = goto_if_not_equal(f64);
+    GotoIfGreaterI8 = goto_if_greater(i8);
+    GotoIfGreaterI16 = goto_if_greater(i16);
+    GotoIfGreaterI32 = goto_if_greater(i32);
+    GotoIfGreaterI64 = goto_if_greater(i64);
+    GotoIfGreaterU8 = goto_if_greater(u8);
+    GotoIfGreaterU16 = goto_if_greater(u16);
+    GotoIfGreaterU32 = goto_if_greater(u32);
+    GotoIfGreaterU64 = goto_if_greater(u64);
+    GotoIfGreaterF32 = goto_if_greater(f32);
+    GotoIfGreaterF64 = goto_if_greater(f64);
+    GotoIfGreaterEqualI8 = goto_if_greater_equal(i8);
+    GotoIfGreaterEqualI16 = goto_if_greater_equal(i16);
+    GotoIfGreaterEqualI32 = goto_if_greater_equal(i32);
+    GotoIfGreaterEqualI64 = goto_if_greater_equal(i64);
+    GotoIfGreaterEqualU8 = goto_if_greater_equal(u8);
+    GotoIfGreaterEqualU16 = goto_if_greater_equal(u16);
+    GotoIfGreaterEqualU32 = goto_if_greater_equal(u32);
+    GotoIfGreaterEqualU64 = goto_if_greater_equal(u64);
+    GotoIfGreaterEqualF32 = goto_if_greater_equal(f32);
+    GotoIfGreaterEqualF64 = goto_if_greater_equal(f64);
+    GotoIfLesserI8 = goto_if_lesser(i8);
+    GotoIfLesserI16 = goto_if_lesser(i16);
+    GotoIfLesserI32 = goto_if_lesser(i32);
+    GotoIfLesserI64 = goto_if_lesser(i64);
+    GotoIfLesserU8 = goto_if_lesser(u8);
+    GotoIfLesserU16 = goto_if_lesser(u16);
+    GotoIfLesserU32 = goto_if_lesser(u32);
+    GotoIfLesserU64 = goto_if_lesser(u64);
+    GotoIfLesserF32 = goto_if_lesser(f32);
+    GotoIfLesserF64 = goto_if_lesser(f64);
+    GotoIfLesserEqualI8 = goto_if_lesser_equal(i8);
+    GotoIfLesserEqualI16 = goto_if_lesser_equal(i16);
+    GotoIfLesserEqualI32 = goto_if_lesser_equal(i32);
+    GotoIfLesserEqualI64 = goto_if_lesser_equal(i64);
+    GotoIfLesserEqualU8 = goto_if_lesser_equal(u8);
+    GotoIfLesserEqualU16 = goto_if_lesser_equal(u16);
+    GotoIfLesserEqualU32 = goto_if_lesser_equal(u32);
+    GotoIfLesserEqualU64 = goto_if_lesser_equal(u64);
+    GotoIfLesserEqualF32 = goto_if_lesser_equal(f32);
+    GotoIfLesserEqualF64 = goto_if_lesser_equal(f64);
+    IncrementI8 = increment(i8);
+    IncrementI16 = increment(i16);
+    IncrementI32 = increment(i32);
+    IncrementI64 = increment(i64);
+    IncrementU8 = increment(u8);
+    IncrementU16 = increment(u16);
+    IncrementU32 = increment(u32);
+    IncrementU64 = increment(u64);
+    IncrementF32 = increment(f32);
+    IncrementF64 = increment(f64);
+}
+
+/// The immediates of `decoded` as the one operand its handler takes. The
+/// pair `increment` has goes into `increments`, and the operand points at it.
+fn operand(
+    code: &[u8],
+    decoded: &Decoded,
+    target: usize,
+    increments: &mut Vec<(usize, u64)>,
+) -> u64 {
+    let operands = bytecode::operands(decoded.opcode).unwrap_or_default();
+    let mut immediates = Vec::new();
+    let mut cursor = decoded.offset + 1;
+    for operand in operands {
+        let size = operand.size(decoded.type_tag);
+        match operand {
+            Operand::Type => {}
+            Operand::Value => {
+                let mut bytes = [0u8; 8];
+                bytes[..size].copy_from_slice(&code[cursor..cursor + size]);
+                immediates.push(u64::from_ne_bytes(bytes));
+            }
+            Operand::Target => immediates.push(target as u64),
+            Operand::Address | Operand::HostFunction => {
+                immediates.push(decoded.operand.unwrap_or_default() as u64)
+            }
+        }
+        cursor += size;
+    }
+    match immediates[..] {
+        [] => 0,
+        [immediate] => immediate,
+        [address, amount] => {
+            increments.push((address as usize, amount));
+            increments.last().unwrap() as *const (usize, u64) as u64
+        }
+        _ => panic!("Invalid instruction {}", bytecode::describe(decoded)),
+    }
+}
+
+fn translate(
+    code: &[u8],
+    decoded: &Decoded,
+    target: usize,
+    increments: &mut Vec<(usize, u64)>,
+) -> Instruction {
+    macro_rules! specialized {
+        ($mnemonic:ident [$($T:ty),*] [$($operand:ident),*]) => {
+            <(row::$mnemonic, ($($T,)*)) as Specialized>::KIND
+        };
+    }
+    let kind = instructions::dispatch!(
+        decoded.opcode,
+        decoded.type_tag.unwrap_or_default(),
+        decoded.second_type_tag.unwrap_or_default(),
+        specialized
+    );
+    Instruction {
+        kind,
+        operand: operand(code, decoded, target, increments),
+    }
+}
+
+impl PredecodedProgram {
+    pub(crate) fn new(code: &[u8]) -> PredecodedProgram {
+        let decoded = bytecode::decode_all(code);
+        let mut offsets: Vec<usize> = decoded
+            .iter()
+            .map(|instruction| instruction.offset)
+            .collect();
+        offsets.push(code.len());
+        let index_of = |target: usize| {
+            offsets
+                .binary_search(&target)
+                .unwrap_or_else(|_| panic!("Jump to {} is not the start of an instruction", target))
+        };
+        // One slot per instruction, so pushing never moves earlier operands.
+        let mut increments = Vec::with_capacity(decoded.len());
+        let instructions = decoded
+            .iter()
+            .map(|instruction| {
+                let target = instruction.jump_target().map_or(0, index_of);
+                translate(code, instruction, target, &mut increments)
+            })
+            .collect();
+        PredecodedProgram {
+            instructions,
+            offsets,
+            _increments: increments,
+        }
+    }
+    pub(crate) fn index_of_offset(&self, offset: usize) -> usize {
+        self.offsets
+            .binary_search(&offset)
+            .unwrap_or_else(|_| panic!("Offset {} is not the start of an instruction", offset))
+    }
+    pub(crate) fn offset_of_index(&self, index: usize) -> usize {
+        self.offsets[index]
+    }
+}
+
+impl StackUpperVector {
+    /// Runs one pre-decoded instruction and returns the index of the next one.
+    #[inline(always)]
+    pub(crate) fn execute_instruction(&mut self, instruction: Instruction, next: usize) -> usize {
+        instruction.kind.run(self, instruction.operand, next)
+    }
+
+    /// Same as `execute_all`, but over a program translated by `PredecodedProgram::new`.
+    /// Starts at the current cursor and leaves the cursor at the end, or sets
+    /// `trap_offset` if an instruction traps.
+    pub(crate) fn execute_predecoded(&mut self, program: &PredecodedProgram) {
+        let instructions = &program.instructions[..];
+        let mut index = program.index_of_offset(self.cursor_offset());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            while index < instructions.len() {
+                index = self.execute_instruction(instructions[index], index + 1);
+            }
+        }));
+        if let Err(payload) = result {
+            self.trap_offset = Some(program.offset_of_index(index));
+            std::panic::resume_unwind(payload);
+        }
+        self.goto(program.offset_of_index(index));
+    }
+}
@@ -0,0 +1,281 @@
+use crate::assembler::LineTable;
+use crate::bytecode::{self, Decoded};
+use crate::StackUpperVector;
+use std::collections::HashMap;
+use std::fmt::Write;
+use std::time::{Duration, Instant};
+
+/// Opcode together with the type tags that specialize it, e.g. `add i32` or `type_cast i32 f64`.
+type InstructionKind = (u8, Option<u8>, Option<u8>);
+
+fn kind_of(instruction: &Decoded) -> InstructionKind {
+    (
+        instruction.opcode,
+        instruction.type_tag,
+        instruction.second_type_tag,
+    )
+}
+
+fn describe_kind(kind: InstructionKind) -> String {
+    let mut text = bytecode::mnemonic(kind.0);
+    for tag in [kind.1, kind.2].into_iter().flatten() {
+        text.push(' ');
+        text.push_str(&bytecode::mnemonic(tag));
+    }
+    text
+}
+
+/// Collects execution counts and timings while `StackUpperVector::execute_profiled` runs.
+pub(crate) struct Profiler {
+    instructions: Vec<Decoded>,
+    index_by_offset: HashMap<usize, usize>,
+    counts: Vec<u64>,
+    times: Vec<Duration>,
+    kind_counts: HashMap<InstructionKind, u64>,
+    pair_counts: HashMap<(InstructionKind, InstructionKind), u64>,
+    previous: Option<InstructionKind>,
+    total_time: Duration,
+    pub report_limit: usize,
+    /// Source positions for the report; offsets only when empty.
+    pub line_table: LineTable,
+}
+
+impl Profiler {
+    pub(crate) fn new(code: &[u8]) -> Profiler {
+        let instructions = bytecode::decode_all(code);
+        let index_by_offset = instructions
+            .iter()
+            .enumerate()
+            .map(|(index, instruction)| (instruction.offset, index))
+            .collect();
+        Profiler {
+            counts: vec![0; instructions.len()],
+            times: vec![Duration::ZERO; instructions.len()],
+            instructions,
+            index_by_offset,
+            kind_counts: HashMap::new(),
+            pair_counts: HashMap::new(),
+            previous: None,
+            total_time: Duration::ZERO,
+            report_limit: 10,
+            line_table: LineTable::default(),
+        }
+    }
+
+    fn record(&mut self, offset: usize, elapsed: Duration) {
+        let index = *self
+            .index_by_offset
+            .get(&offset)
+            .unwrap_or_else(|| panic!("Jump into the middle of an instruction at {}", offset));
+        let kind = kind_of(&self.instructions[index]);
+        self.counts[index] += 1;
+        self.times[index] += elapsed;
+        self.total_time += elapsed;
+        *self.kind_counts.entry(kind).or_insert(0) += 1;
+        if let Some(previous) = self.previous {
+            *self.pair_counts.entry((previous, kind)).or_insert(0) += 1;
+        }
+        self.previous = Some(kind);
+    }
+
+    fn share(&self, time: Duration) -> f64 {
+        if self.total_time.is_zero() {
+            0.0
+        } else {
+            100.0 * time.as_secs_f64() / self.total_time.as_secs_f64()
+        }
+    }
+
+    fn range_totals(&self, start: usize, end: usize) -> (u64, Duration) {
+        let mut time = Duration::ZERO;
+        let mut entries = 0;
+        for (index, instruction) in self.instructions.iter().enumerate() {
+            if instruction.offset >= start && instruction.offset < end {
+                if instruction.offset == start {
+                    entries = self.counts[index];
+                }
+                time += self.times[index];
+            }
+        }
+        (entries, time)
+    }
+
+    /// Executions per opcode/type-tag pair, most frequent first.
+    pub(crate) fn opcode_histogram(&self) -> Vec<(String, u64)> {
+        let mut histogram: Vec<(InstructionKind, u64)> =
+            self.kind_counts.iter().map(|(k, v)| (*k, *v)).collect();
+        histogram.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        histogram
+            .into_iter()
+            .map(|(kind, count)| (describe_kind(kind), count))
+            .collect()
+    }
+
+    /// Consecutively executed instruction pairs, most frequent first. These are the
+    /// candidates for superinstructions.
+    pub(crate) fn instruction_pairs(&self) -> Vec<(String, String, u64)> {
+        let mut pairs: Vec<((InstructionKind, InstructionKind), u64)> =
+            self.pair_counts.iter().map(|(k, v)| (*k, *v)).collect();
+        pairs.sort_by(|a, b| b.1.cmp(&a.1).then(a.0.cmp(&b.0)));
+        pairs
+            .into_iter()
+            .map(|((first, second), count)| (describe_kind(first), describe_kind(second), count))
+            .collect()
+    }
+
+    /// Basic blocks as `(start, end, entries, time)`, most expensive first.
+    pub(crate) fn hot_blocks(&self) -> Vec<(usize, usize, u64, Duration)> {
+        let code_end = self
+            .instructions
+            .last()
+            .map_or(0, |instruction| instruction.offset + instruction.size);
+        let mut leaders = bytecode::block_leaders(&self.instructions);
+        leaders.retain(|leader| *leader < code_end);
+        let mut blocks: Vec<(usize, usize, u64, Duration)> = leaders
+            .iter()
+            .enumerate()
+            .map(|(i, start)| {
+                let end = leaders.get(i + 1).copied().unwrap_or(code_end);
+                let (entries, time) = self.range_totals(*start, end);
+                (*start, end, entries, time)
+            })
+            .collect();
+        blocks.sort_by(|a, b| b.3.cmp(&a.3).then(a.0.cmp(&b.0)));
+        blocks
+    }
+
+    /// Loops found through backward jumps as `(header, end, iterations, time)`,
+    /// most expensive first.
+    pub(crate) fn hot_loops(&self) -> Vec<(usize, usize, u64, Duration)> {
+        let mut loops: Vec<(usize, usize, u64, Duration)> = self
+            .instructions
+            .iter()
+            .filter_map(|instruction| {
+                let target = instruction.jump_target()?;
+                if target > instruction.offset {
+                    return None;
+                }
+                let end = instruction.offset + instruction.size;
+                let (iterations, time) = self.range_totals(target, end);
+                Some((target, end, iterations, time))
+            })
+            .collect();
+        loops.sort_by(|a, b| b.3.cmp(&a.3).then(a.0.cmp(&b.0)));
+        loops
+    }
+
+    /// `  at position` for the instruction at `offset`, if it is known.
+    fn position(&self, offset: usize) -> String {
+        self.line_table
+            .lookup(offset)
+            .map_or(String::new(), |position| format!("  at {}", position))
+    }
+
+    pub(crate) fn report(&self) -> String {
+        let limit = self.report_limit;
+        let mut text = String::new();
+        let total: u64 = self.counts.iter().sum();
+        writeln!(
+            text,
+            "== Profile: {} instructions executed in {:?} ==",
+            total, self.total_time
+        )
+        .unwrap();
+
+        writeln!(text, "\n-- Opcode histogram --").unwrap();
+        for (name, count) in self.opcode_histogram().into_iter().take(limit) {
+            let percent = 100.0 * count as f64 / total.max(1) as f64;
+            writeln!(text, "{:>12} {:>6.2}%  {}", count, percent, name).unwrap();
+        }
+
+        writeln!(text, "\n-- Hot offsets --").unwrap();
+        let mut offsets: Vec<usize> = (0..self.instructions.len()).collect();
+        offsets.sort_by(|a, b| self.counts[*b].cmp(&self.counts[*a]).then(a.cmp(b)));
+        for index in offsets.into_iter().take(limit) {
+            if self.counts[index] == 0 {
+                break;
+            }
+            let instruction = &self.instructions[index];
+            writeln!(
+                text,
+                "{:>8} {:>12} {:>6.2}%  {}{}",
+                instruction.offset,
+                self.counts[index],
+                self.share(self.times[index]),
+                bytecode::describe(instruction),
+                self.position(instruction.offset)
+            )
+            .unwrap();
+        }
+
+        writeln!(text, "\n-- Basic blocks by time --").unwrap();
+        for (start, end, entries, time) in self.hot_blocks().into_iter().take(limit) {
+            if entries == 0 {
+                break;
+            }
+            writeln!(
+                text,
+                "[{:>6}, {:>6}) entries {:>12} time {:>12?} {:>6.2}%{}",
+                start,
+                end,
+                entries,
+                time,
+                self.share(time),
+                self.position(start)
+            )
+            .unwrap();
+        }
+
+        writeln!(text, "\n-- Hot loops --").unwrap();
+        for (header, end, iterations, time) in self.hot_loops().into_iter().take(limit) {
+            writeln!(
+                text,
+                "[{:>6}, {:>6}) iterations {:>12} time {:>12?} {:>6.2}%{}",
+                header,
+                end,
+                iterations,
+                time,
+                self.share(time),
+                self.position(header)
+            )
+            .unwrap();
+        }
+
+        writeln!(text, "\n-- Frequent instruction pairs --").unwrap();
+        for (first, second, count) in self.instruction_pairs().into_iter().take(limit) {
+            writeln!(text, "{:>12}  {} -> {}", count, first, second).unwrap();
+        }
+        text
+    }
+}
+
+impl StackUpperVector {
+    /// Same as `execute_all`, but every instruction is counted and timed.
+    pub(crate) fn execute_profiled(&mut self, profiler: &mut Profiler) {
+        while self.cursor_offset() < self.token_byte_sequence.len() {
+            let offset = self.cursor_offset();
+            let start = Instant::now();
+            self.do_Token();
+            profiler.record(offset, start.elapsed());
+        }
+    }
+
+    /// Same as `execute_all`, but every instruction is printed to stderr
+    /// before it runs, with its source position if `line_table` knows it.
+    pub(crate) fn execute_traced(&mut self, line_table: &LineTable) {
+        while self.cursor_offset() < self.token_byte_sequence.len() {
+            let offset = self.cursor_offset();
+            let instruction = bytecode::decode(&self.token_byte_sequence, offset);
+            let position = line_table
+                .lookup(offset)
+                .map_or(String::new(), |position| position.to_string());
+            eprintln!(
+                "{:>8}  {:<24} {}",
+                offset,
+                bytecode::describe(&instruction),
+                position
+            );
+            self.do_Token();
+        }
+    }
+}
@@ -0,0 +1,763 @@
+use crate::bytecode::{self, Decoded, BOOL, F32, F64, I16, I32, I64, I8, U16, U32, U64, U8};
+use crate::host::{HostContext, HostRegistry, HostValue};
+use crate::instructions::op;
+use crate::Token;
+
+const NUMERIC: [u8; 10] = [I8, I16, I32, I64, U8, U16, U32, U64, F32, F64];
+const ARITHMETIC: [u8; 4] = [op::Add, op::Subtract, op::Multiply, op::Divide];
+const COMPARES: [u8; 6] = [
+    op::CompareEqual,
+    op::CompareNotEqual,
+    op::CompareGreater,
+    op::CompareGreaterEqual,
+    op::CompareLesser,
+    op::CompareLesserEqual,
+];
+
+/// Why the reference interpreter stopped before the end of the program.
+/// `StackUpperVector` panics in the same situations.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Trap {
+    pub offset: usize,
+    pub message: String,
+}
+
+/// A deliberately simple interpreter with the semantics of `StackUpperVector`:
+/// typed values instead of raw bytes and no `unsafe`, so that it can serve as
+/// the oracle in differential tests.
+pub(crate) struct Reference {
+    pub stack: Vec<HostValue>,
+    /// Memory written so far; reading past its end gives zeroes.
+    pub memory: Vec<u8>,
+    pub max_memory_size: usize,
+    pub output: Vec<u8>,
+}
+
+/// `left <opcode> right`. Integer overflow is an error where the VM's
+/// arithmetic panics: always for division, otherwise in debug builds only.
+fn arithmetic(opcode: u8, left: HostValue, right: HostValue) -> Result<HostValue, String> {
+    macro_rules! integer {
+        ($left:expr, $right:expr, $variant:ident) => {{
+            let checked = cfg!(debug_assertions);
+            let result = match opcode {
+                op::Add if checked => $left.checked_add($right),
+                op::Add => Some($left.wrapping_add($right)),
+                op::Subtract if checked => $left.checked_sub($right),
+                op::Subtract => Some($left.wrapping_sub($right)),
+                op::Multiply if checked => $left.checked_mul($right),
+                op::Multiply => Some($left.wrapping_mul($right)),
+                _ => $left.checked_div($right),
+            };
+            result
+                .map(HostValue::$variant)
+                .ok_or_else(|| "integer overflow or division by zero".to_owned())
+        }};
+    }
+    macro_rules! float {
+        ($left:expr, $right:expr, $variant:ident) => {
+            Ok(HostValue::$variant(match opcode {
+                op::Add => $left + $right,
+                op::Subtract => $left - $right,
+                op::Multiply => $left * $right,
+                _ => $left / $right,
+            }))
+        };
+    }
+    match (left, right) {
+        (HostValue::I8(left), HostValue::I8(right)) => integer!(left, right, I8),
+        (HostValue::I16(left), HostValue::I16(right)) => integer!(left, right, I16),
+        (HostValue::I32(left), HostValue::I32(right)) => integer!(left, right, I32),
+        (HostValue::I64(left), HostValue::I64(right)) => integer!(left, right, I64),
+        (HostValue::U8(left), HostValue::U8(right)) => integer!(left, right, U8),
+        (HostValue::U16(left), HostValue::U16(right)) => integer!(left, right, U16),
+        (HostValue::U32(left), HostValue::U32(right)) => integer!(left, right, U32),
+        (HostValue::U64(left), HostValue::U64(right)) => integer!(left, right, U64),
+        (HostValue::F32(left), HostValue::F32(right)) => float!(left, right, F32),
+        (HostValue::F64(left), HostValue::F64(right)) => float!(left, right, F64),
+        (left, right) => Err(format!("cannot combine {:?} and {:?}", left, right)),
+    }
+}
+
+fn compare(opcode: u8, left: HostValue, right: HostValue) -> Result<bool, String> {
+    macro_rules! compare {
+        ($left:expr, $right:expr) => {
+            match opcode {
+                op::CompareEqual => $left == $right,
+                op::CompareNotEqual => $left != $right,
+                op::CompareGreater => $left > $right,
+                op::CompareGreaterEqual => $left >= $right,
+                op::CompareLesser => $left < $right,
+                _ => $left <= $right,
+            }
+        };
+    }
+    Ok(match (left, right) {
+        (HostValue::I8(left), HostValue::I8(right)) => compare!(left, right),
+        (HostValue::I16(left), HostValue::I16(right)) => compare!(left, right),
+        (HostValue::I32(left), HostValue::I32(right)) => compare!(left, right),
+        (HostValue::I64(left), HostValue::I64(right)) => compare!(left, right),
+        (HostValue::U8(left), HostValue::U8(right)) => compare!(left, right),
+        (HostValue::U16(left), HostValue::U16(right)) => compare!(left, right),
+        (HostValue::U32(left), HostValue::U32(right)) => compare!(left, right),
+        (HostValue::U64(left), HostValue::U64(right)) => compare!(left, right),
+        (HostValue::F32(left), HostValue::F32(right)) => compare!(left, right),
+        (HostValue::F64(left), HostValue::F64(right)) => compare!(left, right),
+        (left, right) => return Err(format!("cannot compare {:?} and {:?}", left, right)),
+    })
+}
+
+fn cast(value: HostValue, to: u8) -> Result<HostValue, String> {
+    macro_rules! cast {
+        ($value:expr) => {
+            match to {
+                I8 => HostValue::I8($value as i8),
+                I16 => HostValue::I16($value as i16),
+                I32 => HostValue::I32($value as i32),
+                I64 => HostValue::I64($value as i64),
+                U8 => HostValue::U8($value as u8),
+                U16 => HostValue::U16($value as u16),
+                U32 => HostValue::U32($value as u32),
+                U64 => HostValue::U64($value as u64),
+                F32 => HostValue::F32($value as f32),
+                F64 => HostValue::F64($value as f64),
+                _ => return Err(format!("cannot cast to {}", bytecode::mnemonic(to))),
+            }
+        };
+    }
+    Ok(match value {
+        HostValue::Bool(_) => return Err("cannot cast bool".to_owned()),
+        HostValue::I8(value) => cast!(value),
+        HostValue::I16(value) => cast!(value),
+        HostValue::I32(value) => cast!(value),
+        HostValue::I64(value) => cast!(value),
+        HostValue::U8(value) => cast!(value),
+        HostValue::U16(value) => cast!(value),
+        HostValue::U32(value) => cast!(value),
+        HostValue::U64(value) => cast!(value),
+        HostValue::F32(value) => cast!(value),
+        HostValue::F64(value) => cast!(value),
+    })
+}
+
+/// What `pop` and `peek` print for `value`.
+fn display(value: HostValue) -> String {
+    match value {
+        HostValue::Bool(value) => format!("{:.3}", value),
+        HostValue::I8(value) => format!("{:.3}", value),
+        HostValue::I16(value) => format!("{:.3}", value),
+        HostValue::I32(value) => format!("{:.3}", value),
+        HostValue::I64(value) => format!("{:.3}", value),
+        HostValue::U8(value) => format!("{:.3}", value),
+        HostValue::U16(value) => format!("{:.3}", value),
+        HostValue::U32(value) => format!("{:.3}", value),
+        HostValue::U64(value) => format!("{:.3}", value),
+        HostValue::F32(value) => format!("{:.3}", value),
+        HostValue::F64(value) => format!("{:.3}", value),
+    }
+}
+
+impl Reference {
+    pub(crate) fn new(max_memory_size: usize) -> Reference {
+        Reference {
+            stack: Vec::new(),
+            memory: Vec::new(),
+            max_memory_size,
+            output: Vec::new(),
+        }
+    }
+
+    /// Runs `code` from the start until it ends, traps or has executed
+    /// `max_steps` instructions.
+    pub(crate) fn run(
+        &mut self,
+        code: &[u8],
+        host: &mut HostRegistry,
+        max_steps: u64,
+    ) -> Result<(), Trap> {
+        let mut offset = 0;
+        for _ in 0..max_steps {
+            if offset >= code.len() {
+                return Ok(());
+            }
+            let trap = |message| Trap { offset, message };
+            let instruction = bytecode::try_decode(code, offset).map_err(trap)?;
+            offset = self
+                .step(code, &instruction, host)
+                .map_err(trap)?
+                .unwrap_or(offset + instruction.size);
+        }
+        Err(Trap {
+            offset,
+            message: format!("still running after {} instructions", max_steps),
+        })
+    }
+
+    fn pop(&mut self, type_tag: u8) -> Result<HostValue, String> {
+        match self.stack.pop() {
+            Some(value) if value.type_tag() == type_tag => Ok(value),
+            Some(value) => Err(format!(
+                "expected {} on the stack, found {:?}",
+                bytecode::mnemonic(type_tag),
+                value
+            )),
+            None => Err("stack underflow".to_owned()),
+        }
+    }
+    fn peek(&mut self, type_tag: u8) -> Result<HostValue, String> {
+        let value = self.pop(type_tag)?;
+        self.stack.push(value);
+        Ok(value)
+    }
+    fn pop_bool(&mut self) -> Result<bool, String> {
+        match self.pop(BOOL)? {
+            HostValue::Bool(value) => Ok(value),
+            _ => unreachable!(),
+        }
+    }
+    fn print(&mut self, value: HostValue) {
+        self.output.extend(display(value).bytes());
+        self.output.push(b'\n');
+    }
+
+    fn memory_range(&self, address: usize, type_tag: u8) -> Result<(usize, usize), String> {
+        let size = bytecode::type_size(type_tag);
+        match address.checked_add(size) {
+            Some(end) if end <= self.max_memory_size => Ok((address, end)),
+            _ => Err(format!("memory access at {} out of bounds", address)),
+        }
+    }
+    fn load(&self, address: usize, type_tag: u8) -> Result<HostValue, String> {
+        let (start, end) = self.memory_range(address, type_tag)?;
+        let bytes: Vec<u8> = (start..end)
+            .map(|index| self.memory.get(index).copied().unwrap_or(0))
+            .collect();
+        Ok(HostValue::from_le_bytes(type_tag, &bytes))
+    }
+    fn store(&mut self, address: usize, value: HostValue) -> Result<(), String> {
+        let (start, end) = self.memory_range(address, value.type_tag())?;
+        if self.memory.len() < end {
+            self.memory.resize(end, 0);
+        }
+        self.memory[start..end].copy_from_slice(&value.to_le_bytes());
+        Ok(())
+    }
+
+    /// Executes one instruction. Returns the jump target if it jumps.
+    fn step(
+        &mut self,
+        code: &[u8],
+        instruction: &Decoded,
+        host: &mut HostRegistry,
+    ) -> Result<Option<usize>, String> {
+        let type_tag = instruction.type_tag.unwrap_or(BOOL);
+        let operand = instruction.operand.unwrap_or_default();
+        let end = instruction.offset + instruction.size;
+        let immediate =
+            || HostValue::from_le_bytes(type_tag, &code[end - bytecode::type_size(type_tag)..end]);
+        match instruction.opcode {
+            op::Push => self.stack.push(immediate()),
+            op::Pop => {
+                let value = self.pop(type_tag)?;
+                self.print(value);
+            }
+            op::Peek => {
+                let value = self.peek(type_tag)?;
+                self.print(value);
+            }
+            op::ClonePush => {
+                let value = self.peek(type_tag)?;
+                self.stack.push(value);
+            }
+            op::Add | op::Subtract | op::Multiply | op::Divide => {
+                let right = self.pop(type_tag)?;
+                let left = self.pop(type_tag)?;
+                self.stack
+                    .push(arithmetic(instruction.opcode, left, right)?);
+            }
+            op::AddImmediate => {
+                let left = self.pop(type_tag)?;
+                self.stack.push(arithmetic(op::Add, left, immediate())?);
+            }
+            op::CompareEqual
+            | op::CompareNotEqual
+            | op::CompareGreater
+            | op::CompareGreaterEqual
+            | op::CompareLesser
+            | op::CompareLesserEqual => {
+                let right = self.pop(type_tag)?;
+                let left = self.pop(type_tag)?;
+                let result = compare(instruction.opcode, left, right)?;
+                self.stack.push(HostValue::Bool(result));
+            }
+            op::LogicAnd | op::LogicOr => {
+                let right = self.pop_bool()?;
+                let left = self.pop_bool()?;
+                let result = if instruction.opcode == op::LogicAnd {
+                    left && right
+                } else {
+                    left || right
+                };
+                self.stack.push(HostValue::Bool(result));
+            }
+            op::LogicNot => {
+                let value = self.pop_bool()?;
+                self.stack.push(HostValue::Bool(!value));
+            }
+            op::TypeCast => {
+                let value = self.pop(type_tag)?;
+                let to = instruction.second_type_tag.unwrap_or(BOOL);
+                self.stack.push(cast(value, to)?);
+            }
+            op::Store => {
+                let value = self.pop(type_tag)?;
+                self.store(operand, value)?;
+            }
+            op::PeekStore => {
+                let value = self.peek(type_tag)?;
+                self.store(operand, value)?;
+            }
+            op::Load => {
+                let value = self.load(operand, type_tag)?;
+                self.stack.push(value);
+            }
+            op::Increment => {
+                let value = self.load(operand, type_tag)?;
+                self.store(operand, arithmetic(op::Add, value, immediate())?)?;
+            }
+            op::Goto => return Ok(Some(operand)),
+            op::PopGotoIfTrue => {
+                if self.pop_bool()? {
+                    return Ok(Some(operand));
+                }
+            }
+            op::PeekGotoIfTrue => {
+                let condition = self.pop_bool()?;
+                self.stack.push(HostValue::Bool(condition));
+                if condition {
+                    return Ok(Some(operand));
+                }
+            }
+            opcode if bytecode::is_conditional_goto(opcode) => {
+                let right = self.pop(type_tag)?;
+                let left = self.pop(type_tag)?;
+                if compare(bytecode::goto_condition(opcode), left, right)? {
+                    return Ok(Some(operand));
+                }
+            }
+            op::CallHost => {
+                let id = operand as u32;
+                let signature = host
+                    .signature(id)
+                    .ok_or_else(|| format!("unknown host function {}", id))?;
+                let params = signature.params.clone();
+                let mut args = Vec::new();
+                for param in params.iter().rev() {
+                    args.push(self.pop(*param)?);
+                }
+                args.reverse();
+                let mut context = HostContext {
+                    memory: &self.memory,
+                    output: &mut self.output,
+                };
+                self.stack.extend(host.call(id, &args, &mut context));
+            }
+            opcode => return Err(format!("unknown opcode {}", opcode)),
+        }
+        Ok(None)
+    }
+}
+
+/// Counter addresses of nested loops, one `u8` per nesting level.
+const LOOP_COUNTERS: usize = 0;
+const MAX_NESTING: usize = 2;
+/// Every type has `SLOTS` slots of its own in memory, so no value is ever
+/// loaded as another type: a `bool` from arbitrary bytes is undefined
+/// behaviour in `StackArray`, and Rust leaves the bits of a NaN unspecified.
+/// The slots start at an odd address, so most accesses are misaligned.
+const SLOTS: usize = 4;
+const NUMBERS: usize = 9;
+const BOOLS: usize = NUMBERS + NUMERIC.len() * SLOTS * 8;
+
+/// Where slot `index` of `type_tag` is.
+fn slot(type_tag: u8, index: usize) -> usize {
+    match NUMERIC.iter().position(|numeric| *numeric == type_tag) {
+        Some(position) => NUMBERS + position * SLOTS * 8 + index * bytecode::type_size(type_tag),
+        None => BOOLS + index,
+    }
+}
+
+/// Whether `left` and `right` are the same, counting any two NaNs as equal.
+fn same(left: HostValue, right: HostValue) -> bool {
+    match (left, right) {
+        (HostValue::F32(left), HostValue::F32(right)) => {
+            left.to_bits() == right.to_bits() || left.is_nan() && right.is_nan()
+        }
+        (HostValue::F64(left), HostValue::F64(right)) => {
+            left.to_bits() == right.to_bits() || left.is_nan() && right.is_nan()
+        }
+        (left, right) => left == right,
+    }
+}
+
+/// Whether `stack`, laid out as in `StackArray`, holds the values of
+/// `reference`'s stack.
+pub(crate) fn same_stack(reference: &Reference, stack: &[u8]) -> bool {
+    let mut offset = 0;
+    for value in reference.stack.iter() {
+        let end = offset + bytecode::type_size(value.type_tag());
+        if end > stack.len()
+            || !same(
+                *value,
+                HostValue::from_le_bytes(value.type_tag(), &stack[offset..end]),
+            )
+        {
+            return false;
+        }
+        offset = end;
+    }
+    offset == stack.len()
+}
+
+/// Whether `memory` holds what `reference`'s memory does, beyond its end too.
+/// Only slots are compared by value, so this holds for memory written by
+/// `Generator` programs only.
+pub(crate) fn same_memory(reference: &Reference, memory: &[u8]) -> bool {
+    let byte = |memory: &[u8], index: usize| memory.get(index).copied().unwrap_or(0);
+    let length = reference.memory.len().max(memory.len()).max(BOOLS);
+    let mut compared = vec![false; length];
+    for type_tag in NUMERIC {
+        let size = bytecode::type_size(type_tag);
+        for index in 0..SLOTS {
+            let start = slot(type_tag, index);
+            let value = |memory: &[u8]| {
+                let bytes: Vec<u8> = (start..start + size)
+                    .map(|index| byte(memory, index))
+                    .collect();
+                HostValue::from_le_bytes(type_tag, &bytes)
+            };
+            if !same(value(&reference.memory), value(memory)) {
+                return false;
+            }
+            compared[start..start + size].fill(true);
+        }
+    }
+    (0..length)
+        .all(|index| compared[index] || byte(&reference.memory, index) == byte(memory, index))
+}
+/// The host function random programs may call: `(i32, i32) -> (i64, bool)`.
+pub(crate) const HOST_FUNCTION: u32 = 7;
+
+pub(crate) fn register_host_function(host: &mut HostRegistry) {
+    host.register(
+        HOST_FUNCTION,
+        &[Token::I32, Token::I32],
+        &[Token::I64, Token::Bool],
+        |args, _| match args {
+            [HostValue::I32(left), HostValue::I32(right)] => vec![
+                HostValue::I64(*left as i64 * 3 - *right as i64),
+                HostValue::Bool(left < right),
+            ],
+            _ => unreachable!("checked against the signature"),
+        },
+    );
+}
+
+/// Random programs that pass the verifier: straight-line code chosen from
+/// what the types on the stack allow, plus `if`/`else` and bounded loops
+/// whose bodies leave the stack as they found it.
+pub(crate) struct Generator {
+    state: u64,
+    code: Vec<u8>,
+    types: Vec<u8>,
+    /// How many values at the bottom of `types` the current block must keep.
+    floor: usize,
+}
+
+enum Choice {
+    Push,
+    Pop,
+    Peek,
+    ClonePush,
+    Store,
+    PeekStore,
+    Load,
+    Arithmetic,
+    Compare,
+    AddImmediate,
+    TypeCast,
+    Logic,
+    LogicNot,
+    Increment,
+    CallHost,
+}
+
+impl Generator {
+    /// The program for `seed`, about `length` instructions at the top level.
+    pub(crate) fn program(seed: u64, length: u32) -> Vec<u8> {
+        let mut generator = Generator {
+            state: seed.wrapping_mul(0x9E37_79B9_7F4A_7C15) | 1,
+            code: Vec::new(),
+            types: Vec::new(),
+            floor: 0,
+        };
+        generator.block(0, length);
+        generator.code
+    }
+
+    fn next(&mut self) -> u64 {
+        // xorshift64*
+        self.state ^= self.state >> 12;
+        self.state ^= self.state << 25;
+        self.state ^= self.state >> 27;
+        self.state.wrapping_mul(0x2545_F491_4F6C_DD1D)
+    }
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+    fn numeric_type(&mut self) -> u8 {
+        NUMERIC[self.below(NUMERIC.len())]
+    }
+    /// Mostly small numbers, so that arithmetic does not overflow at once,
+    /// sometimes arbitrary bits and the awkward floats.
+    fn value(&mut self, type_tag: u8) -> HostValue {
+        if type_tag == BOOL {
+            return HostValue::Bool(self.below(2) == 1);
+        }
+        let small = HostValue::I8(self.below(13) as i8 - 6);
+        match type_tag {
+            F32 | F64 if self.below(4) == 0 => {
+                let awkward = [f64::NAN, -0.0, f64::INFINITY, 0.1, 1e30];
+                cast(HostValue::F64(awkward[self.below(awkward.len())]), type_tag).unwrap()
+            }
+            _ if self.below(5) == 0 => {
+                let bits = self.next().to_le_bytes();
+                HostValue::from_le_bytes(type_tag, &bits)
+            }
+            U8 | U16 | U32 | U64 => cast(HostValue::U8(self.below(13) as u8), type_tag).unwrap(),
+            _ => cast(small, type_tag).unwrap(),
+        }
+    }
+    fn emit(&mut self, opcode: Token, type_tags: &[u8]) {
+        self.code.push(opcode as u8);
+        self.code.extend_from_slice(type_tags);
+    }
+    fn push(&mut self, value: HostValue) {
+        self.emit(Token::Push, &[value.type_tag()]);
+        self.code.extend(value.to_le_bytes());
+        self.types.push(value.type_tag());
+    }
+    fn address(&mut self, type_tag: u8) {
+        let address = slot(type_tag, self.below(SLOTS));
+        self.code.extend_from_slice(&address.to_le_bytes());
+    }
+    /// Emits a jump and returns where its target goes, for `patch`.
+    fn jump(&mut self, opcode: u8, type_tags: &[u8]) -> usize {
+        self.code.push(opcode);
+        self.code.extend_from_slice(type_tags);
+        self.code.extend_from_slice(&0usize.to_le_bytes());
+        self.code.len() - 8
+    }
+    fn patch(&mut self, at: usize, target: usize) {
+        self.code[at..at + 8].copy_from_slice(&target.to_le_bytes());
+    }
+    fn pop_to(&mut self, depth: usize) {
+        while self.types.len() > depth {
+            let type_tag = self.types.pop().unwrap();
+            self.emit(Token::Pop, &[type_tag]);
+        }
+    }
+
+    fn block(&mut self, nesting: usize, length: u32) {
+        for _ in 0..length {
+            match self.below(24) {
+                0 | 1 if nesting < MAX_NESTING => self.if_else(nesting),
+                2 if nesting < MAX_NESTING => self.bounded_loop(nesting),
+                _ => self.instruction(),
+            }
+        }
+    }
+    /// A block that leaves the stack as deep as it was.
+    fn balanced_block(&mut self, nesting: usize) {
+        let (depth, floor) = (self.types.len(), self.floor);
+        self.floor = depth;
+        let length = 1 + self.below(8) as u32;
+        self.block(nesting + 1, length);
+        self.pop_to(depth);
+        self.floor = floor;
+    }
+
+    fn if_else(&mut self, nesting: usize) {
+        let peeked = self.below(3) == 0;
+        let to_else = if peeked {
+            let condition = self.value(BOOL);
+            self.push(condition);
+            self.jump(op::PeekGotoIfTrue, &[])
+        } else {
+            let type_tag = self.numeric_type();
+            for _ in 0..2 {
+                let value = self.value(type_tag);
+                self.push(value);
+            }
+            self.types.truncate(self.types.len() - 2);
+            let condition = COMPARES[self.below(COMPARES.len())];
+            match bytecode::fused_goto(condition) {
+                Some(goto_if) if self.below(2) != 0 => self.jump(goto_if, &[type_tag]),
+                _ => {
+                    self.code.extend([condition, type_tag]);
+                    self.jump(op::PopGotoIfTrue, &[])
+                }
+            }
+        };
+        self.balanced_block(nesting);
+        let to_end = self.jump(op::Goto, &[]);
+        self.patch(to_else, self.code.len());
+        self.balanced_block(nesting);
+        self.patch(to_end, self.code.len());
+        self.pop_to(self.types.len() - peeked as usize);
+    }
+
+    fn bounded_loop(&mut self, nesting: usize) {
+        let counter = (LOOP_COUNTERS + nesting).to_le_bytes();
+        self.push(HostValue::U8(0));
+        self.emit(Token::Store, &[U8]);
+        self.code.extend_from_slice(&counter);
+        self.types.pop();
+        let head = self.code.len();
+        self.emit(Token::Load, &[U8]);
+        self.code.extend_from_slice(&counter);
+        let iterations = 1 + self.below(3) as u8;
+        self.push(HostValue::U8(iterations));
+        self.emit(Token::CompareGreaterEqual, &[U8]);
+        self.types.pop();
+        let to_end = self.jump(op::PopGotoIfTrue, &[]);
+        self.balanced_block(nesting);
+        self.emit(Token::Increment, &[U8]);
+        self.code.extend_from_slice(&counter);
+        self.code.push(1);
+        let to_head = self.jump(op::Goto, &[]);
+        self.patch(to_head, head);
+        self.patch(to_end, self.code.len());
+    }
+
+    fn instruction(&mut self) {
+        let available = &self.types[self.floor..];
+        let top = available.last().copied();
+        let second = available.len().checked_sub(2).map(|index| available[index]);
+        let numeric = top.filter(|type_tag| *type_tag != BOOL);
+        let pair = top.filter(|_| top == second);
+
+        let mut choices = vec![Choice::Push, Choice::Load, Choice::Increment];
+        if top.is_some() {
+            choices.extend([Choice::Pop, Choice::Peek, Choice::Store, Choice::PeekStore]);
+            if self.types.len() < 12 {
+                choices.push(Choice::ClonePush);
+            }
+        }
+        if numeric.is_some() {
+            choices.extend([Choice::AddImmediate, Choice::TypeCast]);
+        }
+        if pair.is_some_and(|type_tag| type_tag != BOOL) {
+            choices.extend([Choice::Arithmetic, Choice::Arithmetic, Choice::Compare]);
+        }
+        if pair == Some(BOOL) {
+            choices.push(Choice::Logic);
+        }
+        if top == Some(BOOL) {
+            choices.push(Choice::LogicNot);
+        }
+        if pair == Some(I32) {
+            choices.push(Choice::CallHost);
+        }
+        if self.types.len() > 10 && top.is_some() {
+            choices = vec![Choice::Pop];
+        }
+
+        let type_tag = top.unwrap_or(BOOL);
+        match choices.swap_remove(self.below(choices.len())) {
+            Choice::Push => {
+                let type_tag = if self.below(4) == 0 {
+                    BOOL
+                } else {
+                    self.numeric_type()
+                };
+                let value = self.value(type_tag);
+                self.push(value);
+            }
+            Choice::Pop => self.pop_to(self.types.len() - 1),
+            Choice::Peek => self.emit(Token::Peek, &[type_tag]),
+            Choice::ClonePush => {
+                self.emit(Token::ClonePush, &[type_tag]);
+                self.types.push(type_tag);
+            }
+            Choice::Store => {
+                self.emit(Token::Store, &[type_tag]);
+                self.address(type_tag);
+                self.types.pop();
+            }
+            Choice::PeekStore => {
+                self.emit(Token::PeekStore, &[type_tag]);
+                self.address(type_tag);
+            }
+            Choice::Load => {
+                let type_tag = if self.below(4) == 0 {
+                    BOOL
+                } else {
+                    self.numeric_type()
+                };
+                self.emit(Token::Load, &[type_tag]);
+                self.address(type_tag);
+                self.types.push(type_tag);
+            }
+            Choice::Arithmetic => {
+                let opcode = ARITHMETIC[self.below(ARITHMETIC.len())];
+                self.code.extend([opcode, type_tag]);
+                self.types.pop();
+            }
+            Choice::Compare => {
+                let opcode = COMPARES[self.below(COMPARES.len())];
+                self.code.extend([opcode, type_tag]);
+                self.types.truncate(self.types.len() - 2);
+                self.types.push(BOOL);
+            }
+            Choice::AddImmediate => {
+                let value = self.value(type_tag);
+                self.emit(Token::AddImmediate, &[type_tag]);
+                self.code.extend(value.to_le_bytes());
+            }
+            Choice::TypeCast => {
+                let to = loop {
+                    let to = self.numeric_type();
+                    if to != type_tag {
+                        break to;
+                    }
+                };
+                self.emit(Token::TypeCast, &[type_tag, to]);
+                self.types.pop();
+                self.types.push(to);
+            }
+            Choice::Logic => {
+                let opcode = if self.below(2) == 0 {
+                    Token::LogicAnd
+                } else {
+                    Token::LogicOr
+                };
+                self.emit(opcode, &[]);
+                self.types.pop();
+            }
+            Choice::LogicNot => self.emit(Token::LogicNot, &[]),
+            Choice::Increment => {
+                let type_tag = self.numeric_type();
+                let amount = self.value(type_tag);
+                self.emit(Token::Increment, &[type_tag]);
+                self.address(type_tag);
+                self.code.extend(amount.to_le_bytes());
+            }
+            Choice::CallHost => {
+                self.emit(Token::CallHost, &[]);
+                self.code.extend(HOST_FUNCTION.to_le_bytes());
+                self.types.truncate(self.types.len() - 2);
+                self.types.extend([I64, BOOL]);
+            }
+        }
+    }
+}
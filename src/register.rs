@@ -0,0 +1,723 @@
+use crate::bytecode::{self, Decoded, BOOL};
+use crate::host::HostRegistry;
+use crate::instructions::{self, op};
+use crate::verifier::{self, VerifyError};
+use crate::{Buffer, BufferArray, StackArray, StackMachine, StackUpperVector};
+use num::cast::AsPrimitive;
+use std::collections::{HashMap, HashSet};
+
+type Binary = fn(u64, u64) -> u64;
+type Unary = fn(u64) -> u64;
+type Load = fn(&BufferArray, usize) -> u64;
+type Store = fn(&mut BufferArray, usize, u64);
+type Print = fn(&mut StackUpperVector, u64);
+type PushValue = fn(&mut StackArray, u64);
+type PopValue = fn(&mut StackArray) -> u64;
+
+/// One operation on the register file. Every value, whatever its type, sits in
+/// a `u64` register as the bytes `StackArray` would hold; the function
+/// pointers know the type. Jump targets are indices into
+/// `RegisterProgram::instructions`.
+enum Instruction {
+    Move {
+        dest: u32,
+        source: u32,
+    },
+    Binary {
+        operation: Binary,
+        dest: u32,
+        left: u32,
+        right: u32,
+    },
+    Unary {
+        operation: Unary,
+        dest: u32,
+        source: u32,
+    },
+    Load {
+        load: Load,
+        dest: u32,
+        address: usize,
+    },
+    Store {
+        store: Store,
+        source: u32,
+        address: usize,
+    },
+    Increment {
+        increment: Store,
+        amount: u32,
+        address: usize,
+    },
+    /// What `pop` and `peek` print.
+    Print {
+        print: Print,
+        source: u32,
+    },
+    Jump {
+        target: usize,
+    },
+    /// Jumps when `condition(left, right)` is a true `bool`.
+    Branch {
+        condition: Binary,
+        left: u32,
+        right: u32,
+        target: usize,
+    },
+    CallHost(Box<HostCall>),
+    /// Pushes registers `0..` back onto `StackArray` and stops.
+    Exit(Box<[PushValue]>),
+}
+
+struct HostCall {
+    id: u32,
+    arguments: Vec<(PushValue, u32)>,
+    results: Vec<(PopValue, u32)>,
+}
+
+impl Instruction {
+    fn registers_mut(&mut self) -> Vec<&mut u32> {
+        match self {
+            Instruction::Move { dest, source } | Instruction::Unary { dest, source, .. } => {
+                vec![dest, source]
+            }
+            Instruction::Binary {
+                dest, left, right, ..
+            } => vec![dest, left, right],
+            Instruction::Load { dest, .. } => vec![dest],
+            Instruction::Store { source, .. } | Instruction::Print { source, .. } => vec![source],
+            Instruction::Increment { amount, .. } => vec![amount],
+            Instruction::Branch { left, right, .. } => vec![left, right],
+            Instruction::CallHost(call) => {
+                let arguments = call.arguments.iter_mut().map(|(_, register)| register);
+                let results = call.results.iter_mut().map(|(_, register)| register);
+                arguments.chain(results).collect()
+            }
+            Instruction::Jump { .. } | Instruction::Exit(_) => Vec::new(),
+        }
+    }
+}
+
+fn pack<T>(value: T) -> u64 {
+    let mut bytes = [0u8; 8];
+    unsafe { (bytes.as_mut_ptr() as *mut T).write_unaligned(value) };
+    u64::from_ne_bytes(bytes)
+}
+
+fn unpack<T>(value: u64) -> T {
+    let bytes = value.to_ne_bytes();
+    unsafe { (bytes.as_ptr() as *const T).read_unaligned() }
+}
+
+fn add<T: std::ops::AddAssign>(left: u64, right: u64) -> u64 {
+    let mut value = unpack::<T>(left);
+    value += unpack::<T>(right);
+    pack(value)
+}
+fn subtract<T: std::ops::SubAssign>(left: u64, right: u64) -> u64 {
+    let mut value = unpack::<T>(left);
+    value -= unpack::<T>(right);
+    pack(value)
+}
+fn multiply<T: std::ops::MulAssign>(left: u64, right: u64) -> u64 {
+    let mut value = unpack::<T>(left);
+    value *= unpack::<T>(right);
+    pack(value)
+}
+fn divide<T: std::ops::DivAssign>(left: u64, right: u64) -> u64 {
+    let mut value = unpack::<T>(left);
+    value /= unpack::<T>(right);
+    pack(value)
+}
+fn compare_equal<T: PartialEq>(left: u64, right: u64) -> u64 {
+    pack(unpack::<T>(left) == unpack::<T>(right))
+}
+fn compare_not_equal<T: PartialEq>(left: u64, right: u64) -> u64 {
+    pack(unpack::<T>(left) != unpack::<T>(right))
+}
+fn compare_greater<T: PartialOrd>(left: u64, right: u64) -> u64 {
+    pack(unpack::<T>(left) > unpack::<T>(right))
+}
+fn compare_greater_equal<T: PartialOrd>(left: u64, right: u64) -> u64 {
+    pack(unpack::<T>(left) >= unpack::<T>(right))
+}
+fn compare_lesser<T: PartialOrd>(left: u64, right: u64) -> u64 {
+    pack(unpack::<T>(left) < unpack::<T>(right))
+}
+fn compare_lesser_equal<T: PartialOrd>(left: u64, right: u64) -> u64 {
+    pack(unpack::<T>(left) <= unpack::<T>(right))
+}
+fn logic_and(left: u64, right: u64) -> u64 {
+    pack(unpack::<bool>(left) && unpack::<bool>(right))
+}
+fn logic_or(left: u64, right: u64) -> u64 {
+    pack(unpack::<bool>(left) || unpack::<bool>(right))
+}
+fn logic_not(value: u64) -> u64 {
+    pack(!unpack::<bool>(value))
+}
+fn cast<From: AsPrimitive<To>, To: Copy + 'static>(value: u64) -> u64 {
+    pack::<To>(unpack::<From>(value).as_())
+}
+fn load<T>(memory: &BufferArray, address: usize) -> u64 {
+    pack(memory.load::<T>(address))
+}
+fn store<T>(memory: &mut BufferArray, address: usize, value: u64) {
+    memory.store::<T>(address, unpack(value));
+}
+fn increment<T: std::ops::AddAssign>(memory: &mut BufferArray, address: usize, amount: u64) {
+    memory.increment::<T>(address, unpack(amount));
+}
+fn print<T: std::fmt::Display>(vm: &mut StackUpperVector, value: u64) {
+    vm.print(format_args!("{:.3}", unpack::<T>(value)));
+}
+fn push_value<T>(stack: &mut StackArray, value: u64) {
+    stack.push::<T>(unpack(value));
+}
+fn pop_value<T>(stack: &mut StackArray) -> u64 {
+    pack(stack.pop::<T>())
+}
+
+/// `$function::<T>` for the numeric type `$type_tag`.
+macro_rules! numeric {
+    ($type_tag:expr, $function:ident) => {
+        instructions::with_numeric_type!($type_tag, T => $function::<T>)
+    };
+}
+
+/// `$function::<T>` for any type, `bool` included.
+macro_rules! any {
+    ($type_tag:expr, $function:ident) => {
+        instructions::with_type!($type_tag, T => $function::<T>)
+    };
+}
+
+fn cast_function(from: u8, to: u8) -> Unary {
+    instructions::with_numeric_type!(from, T => {
+        instructions::with_numeric_type!(to, U => cast::<T, U>)
+    })
+}
+
+/// The `Binary` behind `compare_*` or `goto_if_*` `opcode`.
+fn compare_function(opcode: u8, type_tag: u8) -> Binary {
+    let opcode = if bytecode::is_conditional_goto(opcode) {
+        bytecode::goto_condition(opcode)
+    } else {
+        opcode
+    };
+    match opcode {
+        op::CompareEqual => numeric!(type_tag, compare_equal),
+        op::CompareNotEqual => numeric!(type_tag, compare_not_equal),
+        op::CompareGreater => numeric!(type_tag, compare_greater),
+        op::CompareGreaterEqual => numeric!(type_tag, compare_greater_equal),
+        op::CompareLesser => numeric!(type_tag, compare_lesser),
+        _ => numeric!(type_tag, compare_lesser_equal),
+    }
+}
+
+/// Set on registers holding constants until `Translator::finish` moves the
+/// constants after the stack slots.
+const CONSTANT: u32 = 1 << 31;
+
+/// Stack slot `n` lives in register `n`. Within a basic block a slot may
+/// instead be known to equal a constant or a lower slot, in which case it is
+/// only written to its register when the block ends, so `push` and
+/// `clone_push` cost nothing and their consumers read the value directly.
+struct Translator<'a> {
+    code: &'a [u8],
+    host: &'a HostRegistry,
+    instructions: Vec<Instruction>,
+    /// The bytecode offset each instruction was translated from.
+    offsets: Vec<usize>,
+    /// The offset of the bytecode instruction being translated.
+    offset: usize,
+    constants: Vec<u64>,
+    constant_registers: HashMap<u64, u32>,
+    /// The register holding the value of each stack slot, bottom first.
+    slots: Vec<u32>,
+    types: Vec<u8>,
+    slot_count: usize,
+    /// Instructions whose target is still a bytecode offset.
+    jumps: Vec<(usize, usize)>,
+    /// Jumps to the end of the program, with the stack they leave behind.
+    exits: Vec<(usize, Vec<u8>)>,
+}
+
+impl Translator<'_> {
+    fn emit(&mut self, instruction: Instruction) {
+        self.instructions.push(instruction);
+        self.offsets.push(self.offset);
+    }
+    /// A register holding the bytes of `immediate`, as `push` would put them
+    /// on the stack.
+    fn constant(&mut self, immediate: &[u8]) -> u32 {
+        let mut bytes = [0u8; 8];
+        bytes[..immediate.len()].copy_from_slice(immediate);
+        let value = u64::from_ne_bytes(bytes);
+        let next = CONSTANT | self.constants.len() as u32;
+        let register = *self.constant_registers.entry(value).or_insert(next);
+        if register == next {
+            self.constants.push(value);
+        }
+        register
+    }
+    fn push(&mut self, source: u32, type_tag: u8) {
+        self.slots.push(source);
+        self.types.push(type_tag);
+        self.slot_count = self.slot_count.max(self.slots.len());
+    }
+    fn pop(&mut self) -> u32 {
+        self.types.pop();
+        self.slots.pop().expect("verified stack underflow")
+    }
+    fn top(&self) -> u32 {
+        *self.slots.last().expect("verified stack underflow")
+    }
+    /// The slot now on top, written back to its own register.
+    fn top_slot(&self) -> u32 {
+        self.slots.len() as u32 - 1
+    }
+    /// Writes every slot known to equal another register to its own.
+    fn write_back(&mut self) {
+        for slot in 0..self.slots.len() {
+            let dest = slot as u32;
+            if self.slots[slot] != dest {
+                let source = self.slots[slot];
+                self.emit(Instruction::Move { dest, source });
+                self.slots[slot] = dest;
+            }
+        }
+    }
+    /// Emits a jump or branch to bytecode offset `target`, ending the block.
+    fn jump(&mut self, instruction: Instruction, target: usize) {
+        if target == self.code.len() {
+            self.exits
+                .push((self.instructions.len(), self.types.clone()));
+        } else {
+            self.jumps.push((self.instructions.len(), target));
+        }
+        self.emit(instruction);
+    }
+    fn branch(&mut self, condition: Binary, left: u32, right: u32, target: usize) {
+        self.write_back();
+        let branch = Instruction::Branch {
+            condition,
+            left,
+            right,
+            target: 0,
+        };
+        self.jump(branch, target);
+    }
+
+    /// Translates `instruction`. `next` is the instruction after it if it is
+    /// in the same block; returns whether that one was translated as well.
+    fn translate(&mut self, instruction: &Decoded, next: Option<&Decoded>) -> bool {
+        let type_tag = instruction.type_tag.unwrap_or(BOOL);
+        let address = instruction.operand.unwrap_or_default();
+        let end = instruction.offset + instruction.size;
+        let code = self.code;
+        let immediate = || &code[end - bytecode::type_size(type_tag)..end];
+        match instruction.opcode {
+            op::Push => {
+                let source = self.constant(immediate());
+                self.push(source, type_tag);
+            }
+            op::Pop | op::Peek => {
+                let source = if instruction.opcode == op::Pop {
+                    self.pop()
+                } else {
+                    self.top()
+                };
+                let print = any!(type_tag, print);
+                self.emit(Instruction::Print { print, source });
+            }
+            op::ClonePush => self.push(self.top(), type_tag),
+            op::Add
+            | op::Subtract
+            | op::Multiply
+            | op::Divide
+            | op::LogicAnd
+            | op::LogicOr
+            | op::AddImmediate => {
+                let right = if instruction.opcode == op::AddImmediate {
+                    self.constant(immediate())
+                } else {
+                    self.pop()
+                };
+                let operation: Binary = match instruction.opcode {
+                    op::Add | op::AddImmediate => numeric!(type_tag, add),
+                    op::Subtract => numeric!(type_tag, subtract),
+                    op::Multiply => numeric!(type_tag, multiply),
+                    op::Divide => numeric!(type_tag, divide),
+                    op::LogicAnd => logic_and,
+                    _ => logic_or,
+                };
+                let left = self.pop();
+                self.push(self.slots.len() as u32, type_tag);
+                let dest = self.top_slot();
+                self.emit(Instruction::Binary {
+                    operation,
+                    dest,
+                    left,
+                    right,
+                });
+            }
+            op::CompareEqual
+            | op::CompareNotEqual
+            | op::CompareGreater
+            | op::CompareGreaterEqual
+            | op::CompareLesser
+            | op::CompareLesserEqual => {
+                let condition = compare_function(instruction.opcode, type_tag);
+                let right = self.pop();
+                let left = self.pop();
+                if let Some(next) = next.filter(|next| next.opcode == op::PopGotoIfTrue) {
+                    self.branch(condition, left, right, next.operand.unwrap_or_default());
+                    return true;
+                }
+                self.push(self.slots.len() as u32, BOOL);
+                let dest = self.top_slot();
+                self.emit(Instruction::Binary {
+                    operation: condition,
+                    dest,
+                    left,
+                    right,
+                });
+            }
+            opcode if bytecode::is_conditional_goto(opcode) => {
+                let condition = compare_function(opcode, type_tag);
+                let right = self.pop();
+                let left = self.pop();
+                self.branch(condition, left, right, address);
+            }
+            op::PopGotoIfTrue | op::PeekGotoIfTrue => {
+                let source = if instruction.opcode == op::PopGotoIfTrue {
+                    self.pop()
+                } else {
+                    self.top()
+                };
+                let true_value = self.constant(&[1]);
+                self.branch(compare_equal::<bool>, source, true_value, address);
+            }
+            op::Goto => {
+                self.write_back();
+                self.jump(Instruction::Jump { target: 0 }, address);
+            }
+            op::LogicNot | op::TypeCast => {
+                let (operation, result): (Unary, u8) = match instruction.second_type_tag {
+                    Some(to) => (cast_function(type_tag, to), to),
+                    None => (logic_not, BOOL),
+                };
+                let source = self.pop();
+                self.push(self.slots.len() as u32, result);
+                let dest = self.top_slot();
+                self.emit(Instruction::Unary {
+                    operation,
+                    dest,
+                    source,
+                });
+            }
+            op::Store | op::PeekStore => {
+                let source = if instruction.opcode == op::Store {
+                    self.pop()
+                } else {
+                    self.top()
+                };
+                let store = any!(type_tag, store);
+                self.emit(Instruction::Store {
+                    store,
+                    source,
+                    address,
+                });
+            }
+            op::Load => {
+                self.push(self.slots.len() as u32, type_tag);
+                let dest = self.top_slot();
+                let load = any!(type_tag, load);
+                self.emit(Instruction::Load {
+                    load,
+                    dest,
+                    address,
+                });
+            }
+            op::Increment => {
+                let amount = self.constant(immediate());
+                let increment = numeric!(type_tag, increment);
+                self.emit(Instruction::Increment {
+                    increment,
+                    amount,
+                    address,
+                });
+            }
+            op::CallHost => {
+                let id = address as u32;
+                let signature = self.host.signature(id).expect("verified host function");
+                let (params, results) = (signature.params.clone(), signature.results.clone());
+                let base = self.slots.len() - params.len();
+                let arguments = params
+                    .iter()
+                    .zip(&self.slots[base..])
+                    .map(|(type_tag, source)| (any!(*type_tag, push_value) as PushValue, *source))
+                    .collect();
+                self.slots.truncate(base);
+                self.types.truncate(base);
+                let mut call = HostCall {
+                    id,
+                    arguments,
+                    results: Vec::new(),
+                };
+                for type_tag in results {
+                    self.push(self.slots.len() as u32, type_tag);
+                    let pop: PopValue = any!(type_tag, pop_value);
+                    call.results.push((pop, self.top_slot()));
+                }
+                self.emit(Instruction::CallHost(Box::new(call)));
+            }
+            opcode => panic!("Unknown Token! {}", opcode),
+        }
+        false
+    }
+}
+
+/// Verified stack bytecode translated to instructions over virtual registers.
+pub(crate) struct RegisterProgram {
+    instructions: Vec<Instruction>,
+    /// The bytecode offset each instruction comes from, to report traps at.
+    offsets: Vec<usize>,
+    /// Register file to start with: the stack slots, then the constants.
+    registers: Vec<u64>,
+    entry: usize,
+    /// Moves the values already on `StackArray` into registers at entry.
+    entry_stack: Vec<PopValue>,
+    end: usize,
+}
+
+impl RegisterProgram {
+    /// Translates `code`, which must verify against `host`, to start running
+    /// at bytecode offset `start` with the stack the verifier expects there.
+    /// Fails if the values on the stack would not fit in `stack_size` bytes.
+    pub(crate) fn new(
+        code: &[u8],
+        host: &HostRegistry,
+        start: usize,
+        stack_size: usize,
+    ) -> Result<RegisterProgram, VerifyError> {
+        let stack_types = verifier::verify(code, host)?;
+        let entry_types = stack_types.get(&start).cloned().ok_or(VerifyError {
+            offset: start,
+            message: "execution cannot start here".to_owned(),
+        })?;
+        let decoded = bytecode::decode_all(code);
+        for instruction in &decoded {
+            let Some(types) = stack_types.get(&instruction.offset) else {
+                continue;
+            };
+            let depth: usize = types
+                .iter()
+                .map(|type_tag| bytecode::type_size(*type_tag))
+                .sum();
+            let (popped, pushed) = bytecode::stack_effect(instruction).unwrap_or_else(|| {
+                let signature = host.signature(instruction.operand.unwrap_or_default() as u32);
+                let bytes = |types: &[u8]| types.iter().map(|tag| bytecode::type_size(*tag)).sum();
+                signature.map_or((0, 0), |signature| {
+                    (bytes(&signature.params), bytes(&signature.results))
+                })
+            });
+            if depth - popped + pushed > stack_size {
+                return Err(VerifyError {
+                    offset: instruction.offset,
+                    message: format!("the stack of {} bytes is too small", stack_size),
+                });
+            }
+        }
+        let mut leaders: HashSet<usize> = bytecode::block_leaders(&decoded).into_iter().collect();
+        leaders.insert(start);
+
+        let mut translator = Translator {
+            code,
+            host,
+            instructions: Vec::new(),
+            offsets: Vec::new(),
+            offset: start,
+            constants: Vec::new(),
+            constant_registers: HashMap::new(),
+            slots: Vec::new(),
+            types: Vec::new(),
+            slot_count: entry_types.len(),
+            jumps: Vec::new(),
+            exits: Vec::new(),
+        };
+        let mut indices = HashMap::new();
+        let mut in_block = false;
+        let mut position = 0;
+        while position < decoded.len() {
+            let instruction = &decoded[position];
+            position += 1;
+            translator.offset = instruction.offset;
+            let Some(types) = stack_types.get(&instruction.offset) else {
+                in_block = false;
+                continue;
+            };
+            if !in_block || leaders.contains(&instruction.offset) {
+                if in_block {
+                    translator.write_back();
+                }
+                translator.types = types.clone();
+                translator.slots = (0..types.len() as u32).collect();
+                indices.insert(instruction.offset, translator.instructions.len());
+            }
+            let next = decoded
+                .get(position)
+                .filter(|next| !leaders.contains(&next.offset));
+            let fused = translator.translate(instruction, next);
+            if fused {
+                position += 1;
+            }
+            in_block = !fused && !instruction.is_jump();
+        }
+        if in_block {
+            translator.write_back();
+            let types = translator.types.clone();
+            translator
+                .exits
+                .push((translator.instructions.len(), types));
+            translator.emit(Instruction::Jump { target: 0 });
+        }
+
+        let Translator {
+            mut instructions,
+            mut offsets,
+            constants,
+            slot_count,
+            jumps,
+            exits,
+            ..
+        } = translator;
+        for (index, target) in jumps {
+            set_target(&mut instructions[index], indices[&target]);
+        }
+        for (index, types) in exits {
+            let target = instructions.len();
+            set_target(&mut instructions[index], target);
+            let stack = types
+                .iter()
+                .map(|type_tag| any!(*type_tag, push_value) as PushValue);
+            instructions.push(Instruction::Exit(stack.collect()));
+            offsets.push(code.len());
+        }
+        for instruction in instructions.iter_mut() {
+            for register in instruction.registers_mut() {
+                if *register & CONSTANT != 0 {
+                    *register = slot_count as u32 + (*register & !CONSTANT);
+                }
+            }
+        }
+        let mut registers = vec![0; slot_count];
+        registers.extend(constants);
+        Ok(RegisterProgram {
+            instructions,
+            offsets,
+            registers,
+            entry: indices[&start],
+            entry_stack: entry_types
+                .iter()
+                .map(|type_tag| any!(*type_tag, pop_value) as PopValue)
+                .collect(),
+            end: code.len(),
+        })
+    }
+}
+
+fn set_target(instruction: &mut Instruction, index: usize) {
+    match instruction {
+        Instruction::Jump { target } | Instruction::Branch { target, .. } => *target = index,
+        _ => unreachable!("only jumps have targets"),
+    }
+}
+
+impl StackUpperVector {
+    /// Same as `execute_all`, but running `program` on its register file.
+    pub(crate) fn execute_register(&mut self, program: &RegisterProgram) {
+        let mut registers = program.registers.clone();
+        for (register, pop) in program.entry_stack.iter().enumerate().rev() {
+            registers[register] = pop(&mut self.lower_stack);
+        }
+        let mut index = program.entry;
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| loop {
+            match &program.instructions[index] {
+                Instruction::Move { dest, source } => {
+                    registers[*dest as usize] = registers[*source as usize];
+                }
+                Instruction::Binary {
+                    operation,
+                    dest,
+                    left,
+                    right,
+                } => {
+                    registers[*dest as usize] =
+                        operation(registers[*left as usize], registers[*right as usize]);
+                }
+                Instruction::Unary {
+                    operation,
+                    dest,
+                    source,
+                } => registers[*dest as usize] = operation(registers[*source as usize]),
+                Instruction::Load {
+                    load,
+                    dest,
+                    address,
+                } => registers[*dest as usize] = load(&self.buffer, *address),
+                Instruction::Store {
+                    store,
+                    source,
+                    address,
+                } => store(&mut self.buffer, *address, registers[*source as usize]),
+                Instruction::Increment {
+                    increment,
+                    amount,
+                    address,
+                } => increment(&mut self.buffer, *address, registers[*amount as usize]),
+                Instruction::Print { print, source } => print(self, registers[*source as usize]),
+                Instruction::Jump { target } => {
+                    index = *target;
+                    continue;
+                }
+                Instruction::Branch {
+                    condition,
+                    left,
+                    right,
+                    target,
+                } => {
+                    if unpack::<bool>(condition(
+                        registers[*left as usize],
+                        registers[*right as usize],
+                    )) {
+                        index = *target;
+                        continue;
+                    }
+                }
+                Instruction::CallHost(call) => {
+                    for (push, source) in call.arguments.iter() {
+                        push(&mut self.lower_stack, registers[*source as usize]);
+                    }
+                    self.call_host(call.id);
+                    for (pop, dest) in call.results.iter().rev() {
+                        registers[*dest as usize] = pop(&mut self.lower_stack);
+                    }
+                }
+                Instruction::Exit(stack) => {
+                    for (register, push) in stack.iter().enumerate() {
+                        push(&mut self.lower_stack, registers[register]);
+                    }
+                    self.goto(program.end);
+                    return;
+                }
+            }
+            index += 1;
+        }));
+        if let Err(payload) = result {
+            self.trap_offset = Some(program.offsets[index]);
+            std::panic::resume_unwind(payload);
+        }
+    }
+}
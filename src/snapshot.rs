@@ -0,0 +1,227 @@
+use crate::{bytecode, verifier, BufferArray, StackArray, StackUpperVector};
+use std::fmt;
+
+const MAGIC: &[u8; 4] = b"FVMS";
+const VERSION: u8 = 2;
+/// Zero runs shorter than this are kept inside a memory segment instead of
+/// splitting it, since every segment costs 16 bytes of header.
+const MIN_ZERO_GAP: usize = 16;
+
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum SnapshotError {
+    BadMagic,
+    UnsupportedVersion(u8),
+    Truncated,
+    Invalid(String),
+}
+
+impl fmt::Display for SnapshotError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SnapshotError::BadMagic => write!(f, "not a VM snapshot"),
+            SnapshotError::UnsupportedVersion(version) => {
+                write!(f, "unsupported snapshot version {}", version)
+            }
+            SnapshotError::Truncated => write!(f, "snapshot is truncated"),
+            SnapshotError::Invalid(message) => write!(f, "invalid snapshot: {}", message),
+        }
+    }
+}
+
+impl StackArray {
+    pub(crate) fn depth(&self) -> usize {
+        unsafe { self.end.offset_from(self.stack.as_ptr()) as usize }
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+    position: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, count: usize) -> Result<&'a [u8], SnapshotError> {
+        let end = self
+            .position
+            .checked_add(count)
+            .ok_or(SnapshotError::Truncated)?;
+        let slice = self
+            .bytes
+            .get(self.position..end)
+            .ok_or(SnapshotError::Truncated)?;
+        self.position = end;
+        Ok(slice)
+    }
+    fn u64(&mut self) -> Result<usize, SnapshotError> {
+        let mut arr = [0u8; 8];
+        arr.copy_from_slice(self.take(8)?);
+        usize::try_from(u64::from_le_bytes(arr))
+            .map_err(|_| SnapshotError::Invalid("length does not fit in usize".to_owned()))
+    }
+}
+
+fn write_u64(output: &mut Vec<u8>, value: usize) {
+    output.extend_from_slice(&(value as u64).to_le_bytes());
+}
+
+/// Splits memory into `(start, end)` ranges that contain every non-zero byte.
+fn memory_segments(memory: &[u8]) -> Vec<(usize, usize)> {
+    let mut segments: Vec<(usize, usize)> = Vec::new();
+    let mut index = 0;
+    while index < memory.len() {
+        if memory[index] == 0 {
+            index += 1;
+            continue;
+        }
+        let start = index;
+        while index < memory.len() && memory[index] != 0 {
+            index += 1;
+        }
+        match segments.last_mut() {
+            Some(last) if start - last.1 < MIN_ZERO_GAP => last.1 = index,
+            _ => segments.push((start, index)),
+        }
+    }
+    segments
+}
+
+impl StackUpperVector {
+    /// Serializes everything needed to continue execution later: the program,
+    /// the program counter, the memory sizes, the live part of `StackArray` and the
+    /// non-zero parts of `BufferArray`. The VM has no call stack, so there is nothing
+    /// else to save.
+    ///
+    /// Layout, all integers little-endian `u64`:
+    /// `"FVMS" version | pc | stack_size memory_size max_memory_size | code_len code |
+    /// stack_depth stack | segment_count (start len bytes)*`
+    pub(crate) fn snapshot(&self) -> Vec<u8> {
+        let mut output = Vec::new();
+        output.extend_from_slice(MAGIC);
+        output.push(VERSION);
+        // Jumping past the end finishes the program just like reaching the end does.
+        write_u64(
+            &mut output,
+            self.cursor_offset().min(self.token_byte_sequence.len()),
+        );
+        write_u64(&mut output, self.lower_stack.stack.len());
+        write_u64(&mut output, self.buffer.buffer.len());
+        write_u64(&mut output, self.buffer.max_size);
+
+        write_u64(&mut output, self.token_byte_sequence.len());
+        output.extend_from_slice(&self.token_byte_sequence);
+
+        let depth = self.lower_stack.depth();
+        write_u64(&mut output, depth);
+        output.extend_from_slice(&self.lower_stack.stack[..depth]);
+
+        let segments = memory_segments(&self.buffer.buffer);
+        write_u64(&mut output, segments.len());
+        for (start, end) in segments {
+            write_u64(&mut output, start);
+            write_u64(&mut output, end - start);
+            output.extend_from_slice(&self.buffer.buffer[start..end]);
+        }
+        output
+    }
+
+    /// Replaces the whole state of this VM, including its memory sizes, with a
+    /// snapshot taken by `snapshot`. Afterwards `execute_all` continues where the
+    /// snapshotted VM stopped. The snapshot must not need a larger stack or
+    /// memory than this VM is configured for, and its program is checked
+    /// against this VM's host functions just like `load_program` does.
+    pub(crate) fn restore(&mut self, bytes: &[u8]) -> Result<(), SnapshotError> {
+        let mut reader = Reader { bytes, position: 0 };
+        if reader
+            .take(MAGIC.len())
+            .map_err(|_| SnapshotError::BadMagic)?
+            != MAGIC
+        {
+            return Err(SnapshotError::BadMagic);
+        }
+        let version = reader.take(1)?[0];
+        if version != VERSION {
+            return Err(SnapshotError::UnsupportedVersion(version));
+        }
+        let pc = reader.u64()?;
+        let stack_size = reader.u64()?;
+        let memory_size = reader.u64()?;
+        let max_memory_size = reader.u64()?;
+        // Checked before anything is allocated, so a corrupt size is an error
+        // rather than an allocation failure.
+        if stack_size > self.lower_stack.stack.len() {
+            return Err(SnapshotError::Invalid(format!(
+                "stack size {} exceeds this VM's {}",
+                stack_size,
+                self.lower_stack.stack.len()
+            )));
+        }
+        if max_memory_size > self.buffer.max_size {
+            return Err(SnapshotError::Invalid(format!(
+                "maximum memory size {} exceeds this VM's {}",
+                max_memory_size, self.buffer.max_size
+            )));
+        }
+        if memory_size > max_memory_size {
+            return Err(SnapshotError::Invalid(format!(
+                "memory size {} exceeds maximum {}",
+                memory_size, max_memory_size
+            )));
+        }
+
+        let code_len = reader.u64()?;
+        let code = reader.take(code_len)?.to_vec();
+        verifier::check(&code, &self.host)
+            .map_err(|error| SnapshotError::Invalid(error.to_string()))?;
+        let is_boundary = pc == code.len()
+            || bytecode::decode_all(&code)
+                .iter()
+                .any(|instruction| instruction.offset == pc);
+        if !is_boundary {
+            return Err(SnapshotError::Invalid(format!(
+                "program counter {} is not the start of an instruction",
+                pc
+            )));
+        }
+
+        let depth = reader.u64()?;
+        if depth > stack_size {
+            return Err(SnapshotError::Invalid(format!(
+                "stack depth {} exceeds stack size {}",
+                depth, stack_size
+            )));
+        }
+        let stack = reader.take(depth)?;
+
+        let mut buffer = BufferArray::with_size(memory_size, max_memory_size);
+        let segment_count = reader.u64()?;
+        for _ in 0..segment_count {
+            let start = reader.u64()?;
+            let len = reader.u64()?;
+            let contents = reader.take(len)?;
+            let target = start
+                .checked_add(len)
+                .and_then(|end| buffer.buffer.get_mut(start..end))
+                .ok_or_else(|| {
+                    SnapshotError::Invalid(format!(
+                        "memory segment at {} of {} bytes is out of bounds",
+                        start, len
+                    ))
+                })?;
+            target.copy_from_slice(contents);
+        }
+        if reader.position != bytes.len() {
+            return Err(SnapshotError::Invalid("trailing bytes".to_owned()));
+        }
+
+        self.token_byte_sequence = code;
+        self.lower_stack = StackArray::with_size(stack_size);
+        self.buffer = buffer;
+        self.init();
+        self.goto(pc);
+        self.lower_stack.stack[..depth].copy_from_slice(stack);
+        unsafe {
+            self.lower_stack.end = self.lower_stack.stack.as_mut_ptr().add(depth);
+        }
+        Ok(())
+    }
+}
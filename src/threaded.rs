@@ -0,0 +1,55 @@
+use crate::predecode::{Handler, PredecodedProgram};
+use crate::StackUpperVector;
+
+/// A handler together with its immediate: a pushed value, a buffer address,
+/// a jump index or a host function id.
+#[derive(Clone, Copy)]
+pub(crate) struct ThreadedInstruction {
+    handler: Handler,
+    operand: u64,
+}
+
+/// Pre-decoded instructions turned into a table of handler function pointers,
+/// so dispatch is an indirect call instead of a `match`.
+pub(crate) struct ThreadedProgram {
+    instructions: Vec<ThreadedInstruction>,
+    /// Also owns the operands the `increment` handlers point at.
+    predecoded: PredecodedProgram,
+}
+
+impl ThreadedProgram {
+    pub(crate) fn new(code: &[u8]) -> ThreadedProgram {
+        let predecoded = PredecodedProgram::new(code);
+        let instructions = predecoded
+            .instructions
+            .iter()
+            .map(|instruction| ThreadedInstruction {
+                handler: instruction.kind.handler(),
+                operand: instruction.operand,
+            })
+            .collect();
+        ThreadedProgram {
+            instructions,
+            predecoded,
+        }
+    }
+}
+
+impl StackUpperVector {
+    /// Same as `execute_all`, but dispatching through the handler table of `program`.
+    pub(crate) fn execute_threaded(&mut self, program: &ThreadedProgram) {
+        let instructions = &program.instructions[..];
+        let mut index = program.predecoded.index_of_offset(self.cursor_offset());
+        let result = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            while index < instructions.len() {
+                let instruction = instructions[index];
+                index = (instruction.handler)(self, instruction.operand, index + 1);
+            }
+        }));
+        if let Err(payload) = result {
+            self.trap_offset = Some(program.predecoded.offset_of_index(index));
+            std::panic::resume_unwind(payload);
+        }
+        self.goto(program.predecoded.offset_of_index(index));
+    }
+}
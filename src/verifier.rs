@@ -0,0 +1,311 @@
+use crate::bytecode::{self, Decoded, BOOL};
+use crate::host::HostRegistry;
+use crate::instructions::{self, Effect, Instruction, Slot, Types};
+use crate::{StackUpperVector, Token};
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct VerifyError {
+    pub offset: usize,
+    pub message: String,
+}
+
+impl fmt::Display for VerifyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "offset {}: {}", self.offset, self.message)
+    }
+}
+
+/// Type tags of the values on `StackArray` before each reachable instruction,
+/// bottom of the stack first.
+pub(crate) type StackTypes = HashMap<usize, Vec<u8>>;
+
+/// What `check` knows about the stack before an instruction: the types of the
+/// values on top of it, bottom first, `None` where paths disagree. Anything
+/// below them is unknown.
+type KnownTypes = Vec<Option<u8>>;
+
+fn error(instruction: &Decoded, message: String) -> VerifyError {
+    VerifyError {
+        offset: instruction.offset,
+        message,
+    }
+}
+
+/// The row of `instruction` in `instructions::INSTRUCTIONS`, once its type
+/// operands are ones it is defined for.
+fn definition(instruction: &Decoded) -> Result<&'static Instruction, VerifyError> {
+    let definition = instructions::instruction(instruction.opcode).ok_or_else(|| {
+        error(
+            instruction,
+            format!("Unknown Token! {}", instruction.opcode),
+        )
+    })?;
+    let types = [instruction.type_tag, instruction.second_type_tag];
+    if definition.types == Types::Numeric && types.contains(&Some(BOOL)) {
+        return Err(error(
+            instruction,
+            format!(
+                "{} is not defined for bool",
+                bytecode::describe(instruction)
+            ),
+        ));
+    }
+    if instruction.opcode == Token::TypeCast as u8 && types[0] == types[1] {
+        return Err(error(
+            instruction,
+            format!(
+                "type_cast from {} to itself",
+                bytecode::mnemonic(types[0].unwrap_or(BOOL))
+            ),
+        ));
+    }
+    Ok(definition)
+}
+
+/// The type a `Slot` of `instruction` stands for.
+fn slot_type(instruction: &Decoded, slot: &Slot) -> u8 {
+    match slot {
+        Slot::First => instruction.type_tag.unwrap_or(BOOL),
+        Slot::Second => instruction.second_type_tag.unwrap_or(BOOL),
+        Slot::Bool => BOOL,
+    }
+}
+
+/// Applies the stack effect of one instruction, as its row in
+/// `instructions::INSTRUCTIONS` describes it.
+fn step(
+    instruction: &Decoded,
+    mut stack: Vec<u8>,
+    host: &HostRegistry,
+) -> Result<Vec<u8>, VerifyError> {
+    match definition(instruction)?.effect {
+        Effect::Stack(pops, pushes) => {
+            for slot in pops.iter().rev() {
+                let expected = slot_type(instruction, slot);
+                let message = match stack.pop() {
+                    Some(found) if found == expected => continue,
+                    Some(found) => format!(
+                        "{} expects {} on top of the stack, found {}",
+                        bytecode::describe(instruction),
+                        bytecode::mnemonic(expected),
+                        bytecode::mnemonic(found)
+                    ),
+                    None => format!("{} underflows the stack", bytecode::describe(instruction)),
+                };
+                return Err(error(instruction, message));
+            }
+            stack.extend(pushes.iter().map(|slot| slot_type(instruction, slot)));
+        }
+        Effect::Host => {
+            let (id, params, results) = host_signature(instruction, host)?;
+            let depth = stack.len();
+            if depth < params.len() || stack[depth - params.len()..] != params[..] {
+                let found = &stack[depth.saturating_sub(params.len())..];
+                return Err(error(
+                    instruction,
+                    format!(
+                        "host function {} expects arguments {}, found {}",
+                        id,
+                        bytecode::type_names(params),
+                        bytecode::type_names(found)
+                    ),
+                ));
+            }
+            stack.truncate(depth - params.len());
+            stack.extend_from_slice(results);
+        }
+    }
+    Ok(stack)
+}
+
+/// The id, parameters and results of the host function `instruction` calls.
+fn host_signature<'a>(
+    instruction: &Decoded,
+    host: &'a HostRegistry,
+) -> Result<(u32, &'a [u8], &'a [u8]), VerifyError> {
+    let id = instruction.operand.unwrap_or_default() as u32;
+    let signature = host
+        .signature(id)
+        .ok_or_else(|| error(instruction, format!("Unknown host function {}", id)))?;
+    Ok((id, &signature.params, &signature.results))
+}
+
+/// Like `step`, but only rejects a `call_host` whose arguments are known to
+/// have other types than its signature declares. Popping a value as another
+/// type of the same size is allowed; popping it with another size makes the
+/// whole stack unknown.
+fn step_known(
+    instruction: &Decoded,
+    mut stack: KnownTypes,
+    host: &HostRegistry,
+) -> Result<KnownTypes, VerifyError> {
+    match definition(instruction)?.effect {
+        Effect::Stack(pops, pushes) => {
+            for slot in pops.iter().rev() {
+                let expected = slot_type(instruction, slot);
+                if let Some(Some(found)) = stack.pop() {
+                    if bytecode::type_size(found) != bytecode::type_size(expected) {
+                        stack.clear();
+                        break;
+                    }
+                }
+            }
+            stack.extend(pushes.iter().map(|slot| Some(slot_type(instruction, slot))));
+        }
+        Effect::Host => {
+            let (id, params, results) = host_signature(instruction, host)?;
+            let start = stack.len().saturating_sub(params.len());
+            let mut found = vec![None; params.len() - (stack.len() - start)];
+            found.extend_from_slice(&stack[start..]);
+            let contradicts = found
+                .iter()
+                .zip(params)
+                .any(|(found, param)| found.is_some_and(|found| found != *param));
+            if contradicts {
+                let names: Vec<String> = found
+                    .iter()
+                    .map(|found| found.map_or_else(|| "?".to_owned(), bytecode::mnemonic))
+                    .collect();
+                return Err(error(
+                    instruction,
+                    format!(
+                        "host function {} expects arguments {}, found [{}]",
+                        id,
+                        bytecode::type_names(params),
+                        names.join(", ")
+                    ),
+                ));
+            }
+            stack.truncate(start);
+            stack.extend(results.iter().map(|type_tag| Some(*type_tag)));
+        }
+    }
+    Ok(stack)
+}
+
+/// What two paths agree on about the values on top of the stack.
+fn merge(known: &KnownTypes, other: &KnownTypes) -> KnownTypes {
+    let len = known.len().min(other.len());
+    known[known.len() - len..]
+        .iter()
+        .zip(&other[other.len() - len..])
+        .map(|(known, other)| if known == other { *known } else { None })
+        .collect()
+}
+
+/// Decodes every instruction of `code` and checks that jumps land on
+/// instruction boundaries.
+fn decode(code: &[u8]) -> Result<HashMap<usize, Decoded>, VerifyError> {
+    let mut instructions = HashMap::new();
+    let mut offset = 0;
+    while offset < code.len() {
+        let instruction = bytecode::try_decode(code, offset)
+            .map_err(|message| VerifyError { offset, message })?;
+        offset += instruction.size;
+        instructions.insert(instruction.offset, instruction);
+    }
+    for instruction in instructions.values() {
+        if let Some(target) = instruction.jump_target() {
+            if target != code.len() && !instructions.contains_key(&target) {
+                return Err(error(
+                    instruction,
+                    format!("jump to {} is not the start of an instruction", target),
+                ));
+            }
+        }
+    }
+    Ok(instructions)
+}
+
+/// Offsets execution can go to after `instruction`.
+fn successors(instruction: &Decoded) -> impl Iterator<Item = usize> {
+    let next = instruction.offset + instruction.size;
+    let falls_through = instruction.opcode != Token::Goto as u8;
+    falls_through
+        .then_some(next)
+        .into_iter()
+        .chain(instruction.jump_target())
+}
+
+/// Checks that every instruction is well formed, that jumps land on
+/// instruction boundaries, that every reachable instruction sees the operand
+/// types it expects no matter which path leads to it, and that every
+/// `call_host` matches the signature registered in `host`. The register
+/// engine needs this much to translate a program; `check` is what a program
+/// has to pass to run at all.
+pub(crate) fn verify(code: &[u8], host: &HostRegistry) -> Result<StackTypes, VerifyError> {
+    let instructions = decode(code)?;
+    let mut stack_types = StackTypes::new();
+    let mut pending = vec![(0usize, Vec::<u8>::new())];
+    while let Some((offset, stack)) = pending.pop() {
+        if offset == code.len() {
+            continue;
+        }
+        if let Some(known) = stack_types.get(&offset) {
+            if *known != stack {
+                return Err(VerifyError {
+                    offset,
+                    message: format!(
+                        "stack is {} on one path and {} on another",
+                        bytecode::type_names(known),
+                        bytecode::type_names(&stack)
+                    ),
+                });
+            }
+            continue;
+        }
+        let instruction = &instructions[&offset];
+        stack_types.insert(offset, stack.clone());
+        let after = step(instruction, stack, host)?;
+        for successor in successors(instruction) {
+            pending.push((successor, after.clone()));
+        }
+    }
+    Ok(stack_types)
+}
+
+/// Checks that every instruction is well formed, that jumps land on
+/// instruction boundaries and that no reachable `call_host` is passed values
+/// of types its signature in `host` contradicts. Values popped as another type
+/// and paths that leave different stacks are fine; the types they leave are
+/// just not known any more.
+pub(crate) fn check(code: &[u8], host: &HostRegistry) -> Result<(), VerifyError> {
+    let instructions = decode(code)?;
+    let mut known_types: HashMap<usize, KnownTypes> = HashMap::new();
+    let mut pending = vec![(0usize, KnownTypes::new())];
+    while let Some((offset, stack)) = pending.pop() {
+        if offset == code.len() {
+            continue;
+        }
+        let stack = match known_types.get(&offset) {
+            Some(known) => {
+                let merged = merge(known, &stack);
+                if merged == *known {
+                    continue;
+                }
+                merged
+            }
+            None => stack,
+        };
+        let instruction = &instructions[&offset];
+        known_types.insert(offset, stack.clone());
+        let after = step_known(instruction, stack, host)?;
+        for successor in successors(instruction) {
+            pending.push((successor, after.clone()));
+        }
+    }
+    Ok(())
+}
+
+impl StackUpperVector {
+    /// Checks `code` against the registered host functions and makes it the
+    /// program to run.
+    pub(crate) fn load_program(&mut self, code: Vec<u8>) -> Result<(), VerifyError> {
+        check(&code, &self.host)?;
+        self.token_byte_sequence = code;
+        self.init();
+        Ok(())
+    }
+}
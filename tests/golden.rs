@@ -0,0 +1,108 @@
+//! Runs every program in `tests/programs` through the binary on every engine,
+//! with and without `--optimize`, and compares what it prints with the `.out`
+//! file of the same name. Run with `UPDATE_GOLDEN=1` to rewrite the `.out`
+//! files from the interpreter after an intended change of output. Programs in
+//! `tests/traps` must trap instead, and end their stderr with their `.err` file.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+
+#[cfg(not(all(target_arch = "x86_64", target_os = "linux")))]
+const ENGINES: [&str; 4] = ["interpreter", "predecoded", "threaded", "register"];
+#[cfg(all(target_arch = "x86_64", target_os = "linux"))]
+const ENGINES: [&str; 5] = ["interpreter", "predecoded", "threaded", "register", "jit"];
+
+fn programs(directory: &str) -> Vec<PathBuf> {
+    let directory = Path::new(env!("CARGO_MANIFEST_DIR")).join(directory);
+    let mut programs: Vec<PathBuf> = fs::read_dir(&directory)
+        .unwrap_or_else(|error| panic!("Cannot read {}: {}", directory.display(), error))
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|extension| extension == "txt"))
+        .collect();
+    programs.sort();
+    programs
+}
+
+fn run(program: &Path, args: &[&str]) -> Vec<u8> {
+    let output = Command::new(env!("CARGO_BIN_EXE_tests"))
+        .arg(program)
+        .args(args)
+        .output()
+        .expect("Cannot run the VM");
+    assert!(
+        output.status.success(),
+        "{} {:?} failed:\n{}",
+        program.display(),
+        args,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    output.stdout
+}
+
+#[test]
+fn programs_match_golden_output() {
+    let programs = programs("tests/programs");
+    assert!(!programs.is_empty());
+    let update = std::env::var_os("UPDATE_GOLDEN").is_some();
+    let mut failures = Vec::new();
+    for program in programs.iter() {
+        let golden = program.with_extension("out");
+        if update {
+            fs::write(&golden, run(program, &[])).unwrap();
+            continue;
+        }
+        let expected = fs::read(&golden)
+            .unwrap_or_else(|error| panic!("Cannot read {}: {}", golden.display(), error));
+        for engine in ENGINES {
+            for optimize in [false, true] {
+                let mut args = vec!["--engine", engine];
+                if optimize {
+                    args.push("--optimize");
+                }
+                if run(program, &args) != expected {
+                    failures.push(format!("{} {}", program.display(), args.join(" ")));
+                }
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "Output differs from the golden file:\n{}",
+        failures.join("\n")
+    );
+}
+
+#[test]
+fn traps_are_reported_where_they_happen() {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+    let programs = programs("tests/traps");
+    assert!(!programs.is_empty());
+    let mut failures = Vec::new();
+    for program in programs.iter() {
+        let relative = program.strip_prefix(root).unwrap();
+        let expected = fs::read(program.with_extension("err")).unwrap();
+        for engine in ENGINES {
+            for optimize in [false, true] {
+                let mut args = vec!["--engine", engine];
+                if optimize {
+                    args.push("--optimize");
+                }
+                let output = Command::new(env!("CARGO_BIN_EXE_tests"))
+                    .current_dir(root)
+                    .arg(relative)
+                    .args(&args)
+                    .output()
+                    .expect("Cannot run the VM");
+                if output.status.code() != Some(101) || !output.stderr.ends_with(&expected) {
+                    failures.push(format!("{} {}", relative.display(), args.join(" ")));
+                }
+            }
+        }
+    }
+    assert!(
+        failures.is_empty(),
+        "Trap differs from the .err file:\n{}",
+        failures.join("\n")
+    );
+}
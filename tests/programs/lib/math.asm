@@ -0,0 +1,12 @@
+.macro square type
+clone_push type
+multiply type
+.endm
+
+.macro countdown type address
+loop: increment type address -1
+load type address
+peek type
+push type 0
+goto_if_greater type loop
+.endm